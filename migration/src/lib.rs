@@ -19,6 +19,8 @@ mod m20230130_124740_read_only_login_logic;
 mod m20230130_165144_prepare_admin_imitation_pre_login;
 mod m20230215_152254_admin_trail;
 mod m20230307_002623_migrate_rpc_accounting_to_rpc_accounting_v2;
+mod m20230510_101500_user_tier_limits;
+mod m20230512_090000_user_tier_max_batch_size;
 
 pub struct Migrator;
 
@@ -45,6 +47,8 @@ impl MigratorTrait for Migrator {
             Box::new(m20230130_165144_prepare_admin_imitation_pre_login::Migration),
             Box::new(m20230215_152254_admin_trail::Migration),
             Box::new(m20230307_002623_migrate_rpc_accounting_to_rpc_accounting_v2::Migration),
+            Box::new(m20230510_101500_user_tier_limits::Migration),
+            Box::new(m20230512_090000_user_tier_max_batch_size::Migration),
         ]
     }
 }
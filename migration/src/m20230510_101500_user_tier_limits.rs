@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // null means the app's defaults are used
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(
+                        ColumnDef::new(UserTier::RequestTimeoutSeconds)
+                            .big_unsigned()
+                            .null(),
+                    )
+                    .add_column(
+                        ColumnDef::new(UserTier::MaxRequestBytes)
+                            .big_unsigned()
+                            .null(),
+                    )
+                    .add_column(
+                        ColumnDef::new(UserTier::MaxResponseBytes)
+                            .big_unsigned()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::RequestTimeoutSeconds)
+                    .drop_column(UserTier::MaxRequestBytes)
+                    .drop_column(UserTier::MaxResponseBytes)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    RequestTimeoutSeconds,
+    MaxRequestBytes,
+    MaxResponseBytes,
+}
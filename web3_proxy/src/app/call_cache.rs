@@ -0,0 +1,517 @@
+///! Reuse eth_call results from older blocks when nothing they depend on was touched since.
+///!
+///! The response cache is keyed by block hash, so every new block invalidates calls like `symbol()` and `decimals()`.
+///! This remembers which addresses each recent block touched (transaction senders and receivers, and log emitters).
+///! This is a heuristic! A contract's storage can also change inside a call from a different contract without a log.
+use crate::config::CallCacheConfig;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::{Web3ProxyErrorContext, Web3ProxyResult};
+use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcId, JsonRpcRequest};
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::many::Web3Rpcs;
+use ethers::prelude::{Address, Block, Bytes, Log, Transaction, H256, U64};
+use hashbrown::HashSet;
+use log::{trace, warn};
+use moka::future::Cache;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+
+/// Don't scan too many blocks at once after a gap in the consensus heads. Start over instead
+const MAX_SCAN_BLOCKS: u64 = 10;
+
+/// The addresses that an eth_call depends on
+#[derive(Debug)]
+pub struct CallKey {
+    /// the call object without the block
+    call: String,
+    addresses: Vec<Address>,
+}
+
+#[derive(Debug)]
+struct CachedCall {
+    block_num: U64,
+    block_hash: H256,
+    response: JsonRpcForwardedResponse,
+}
+
+#[derive(Debug)]
+struct BlockTouches {
+    num: U64,
+    hash: H256,
+    parent_hash: H256,
+    addresses: HashSet<Address>,
+}
+
+/// The call cache's counters in a format that serde_prometheus likes
+#[derive(Default, Serialize)]
+pub struct CallCacheMetrics {
+    hits: u64,
+    misses: u64,
+    /// times that the touched blocks had to start over because of a reorg, gap or error
+    resets: u64,
+}
+
+pub struct ContractCallCache {
+    config: CallCacheConfig,
+    selectors: HashSet<[u8; 4]>,
+    /// the last `max_age_blocks` contiguous consensus blocks. oldest first
+    touches: RwLock<VecDeque<BlockTouches>>,
+    results: Cache<String, Arc<CachedCall>, hashbrown::hash_map::DefaultHashBuilder>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    resets: AtomicU64,
+}
+
+impl Default for ContractCallCache {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl ContractCallCache {
+    pub fn new(config: CallCacheConfig) -> Self {
+        let selectors = config
+            .selectors
+            .iter()
+            .filter_map(|x| {
+                let x = Bytes::from_str(x).ok()?;
+
+                match x.len() {
+                    4 => Some([x[0], x[1], x[2], x[3]]),
+                    _ => {
+                        warn!("invalid selector in call_cache.selectors: {}", x);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let results = Cache::builder()
+            .max_capacity(config.max_bytes)
+            .weigher(|k: &String, v: &Arc<CachedCall>| {
+                (k.len() + v.response.num_bytes())
+                    .try_into()
+                    .unwrap_or(u32::MAX)
+            })
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Self {
+            config,
+            selectors,
+            touches: Default::default(),
+            results,
+            hits: 0.into(),
+            misses: 0.into(),
+            resets: 0.into(),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// The key for an eth_call at the head block. None if the call can't be reused
+    pub fn key(&self, params: Option<&serde_json::Value>) -> Option<CallKey> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let params = params?.as_array()?;
+
+        // state overrides are too much to think about
+        if params.len() > 2 {
+            return None;
+        }
+
+        // only calls for the head block
+        match params.get(1).map(|x| x.as_str()) {
+            None | Some(Some("latest")) => {}
+            _ => return None,
+        }
+
+        let call = params.get(0)?;
+
+        let to: Address = serde_json::from_value(call.get("to")?.clone()).ok()?;
+
+        let data = call
+            .get("data")
+            .or_else(|| call.get("input"))
+            .and_then(|x| x.as_str())
+            .and_then(|x| Bytes::from_str(x).ok())
+            .unwrap_or_default();
+
+        if !self.selectors.is_empty() {
+            let selector = data.get(..4)?;
+
+            if !self.selectors.contains(selector) {
+                return None;
+            }
+        }
+
+        let mut addresses = vec![to];
+
+        if let Some(from) = call
+            .get("from")
+            .and_then(|x| serde_json::from_value::<Address>(x.clone()).ok())
+        {
+            addresses.push(from);
+        }
+
+        if self.config.check_calldata_addresses {
+            addresses.extend(calldata_addresses(&data));
+        }
+
+        Some(CallKey {
+            call: call.to_string(),
+            addresses,
+        })
+    }
+
+    /// A response from an older block if nothing the call depends on was touched since
+    pub fn get(&self, key: &CallKey, head_block_num: U64) -> Option<JsonRpcForwardedResponse> {
+        let cached = self.results.get(&key.call);
+
+        let response = cached.and_then(|cached| {
+            let touches = self.touches.read();
+
+            let oldest = touches.front()?;
+            let newest = touches.back()?;
+
+            // the touches need to be known all the way to the head
+            if newest.num < head_block_num {
+                return None;
+            }
+
+            // and back to the cached block
+            if cached.block_num + 1 < oldest.num {
+                return None;
+            }
+
+            // the cached block needs to be on the same chain
+            let cached_hash = if cached.block_num + 1 == oldest.num {
+                oldest.parent_hash
+            } else {
+                touches
+                    .iter()
+                    .find(|x| x.num == cached.block_num)
+                    .map(|x| x.hash)?
+            };
+
+            if cached_hash != cached.block_hash {
+                return None;
+            }
+
+            let touched = touches
+                .iter()
+                .filter(|x| x.num > cached.block_num && x.num <= head_block_num)
+                .any(|x| key.addresses.iter().any(|a| x.addresses.contains(a)));
+
+            if touched {
+                None
+            } else {
+                Some(cached.response.clone())
+            }
+        });
+
+        if response.is_some() {
+            self.hits.fetch_add(1, atomic::Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        response
+    }
+
+    /// Save a successful response for the block that it was fetched at
+    pub async fn insert(
+        &self,
+        key: CallKey,
+        block: &Web3ProxyBlock,
+        response: &JsonRpcForwardedResponse,
+    ) {
+        if response.error.is_some() {
+            return;
+        }
+
+        let cached = CachedCall {
+            block_num: *block.number(),
+            block_hash: *block.hash(),
+            response: response.clone(),
+        };
+
+        self.results.insert(key.call, Arc::new(cached)).await;
+    }
+
+    fn reset(&self, touches: &mut VecDeque<BlockTouches>) {
+        touches.clear();
+
+        self.resets.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Find the addresses touched by a new consensus head and any blocks skipped since the last head
+    pub async fn on_head_block(
+        &self,
+        rpcs: &Web3Rpcs,
+        authorization: &Arc<Authorization>,
+        head_block: &Web3ProxyBlock,
+    ) {
+        let head_block_num = *head_block.number();
+
+        let newest = self.touches.read().back().map(|x| x.num);
+
+        let first_num = match newest {
+            Some(x) if x < head_block_num && head_block_num - x <= MAX_SCAN_BLOCKS.into() => x + 1,
+            _ => {
+                // a reorg, a big gap, or the first head
+                self.reset(&mut self.touches.write());
+
+                head_block_num
+            }
+        };
+
+        let mut num = first_num;
+
+        while num <= head_block_num {
+            let block = if num == head_block_num {
+                Some(head_block.clone())
+            } else {
+                rpcs.cached_block_by_number(&num)
+            };
+
+            let addresses = match block.as_ref() {
+                Some(block) => touched_addresses(rpcs, authorization, block).await,
+                None => Ok(None),
+            };
+
+            let mut touches = self.touches.write();
+
+            match (block, addresses) {
+                (Some(block), Ok(Some(addresses))) => {
+                    if touches
+                        .back()
+                        .map(|x| x.hash != *block.parent_hash())
+                        .unwrap_or(false)
+                    {
+                        trace!("reorg at block {}", num);
+                        self.reset(&mut touches);
+                    }
+
+                    touches.push_back(BlockTouches {
+                        num,
+                        hash: *block.hash(),
+                        parent_hash: *block.parent_hash(),
+                        addresses,
+                    });
+
+                    while touches.len() as u64 > self.config.max_age_blocks {
+                        touches.pop_front();
+                    }
+                }
+                (_, Err(err)) => {
+                    trace!(
+                        "unable to find the addresses touched by block {}. err={:?}",
+                        num,
+                        err
+                    );
+                    self.reset(&mut touches);
+                }
+                _ => {
+                    self.reset(&mut touches);
+                }
+            }
+
+            num += U64::one();
+        }
+    }
+
+    pub fn metrics(&self) -> CallCacheMetrics {
+        CallCacheMetrics {
+            hits: self.hits.load(atomic::Ordering::Relaxed),
+            misses: self.misses.load(atomic::Ordering::Relaxed),
+            resets: self.resets.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+/// Words in the calldata that look like addresses (like the owner in `balanceOf(owner)`)
+fn calldata_addresses(data: &[u8]) -> impl Iterator<Item = Address> + '_ {
+    data.get(4..)
+        .unwrap_or_default()
+        .chunks_exact(32)
+        .filter(|x| x[..12].iter().all(|x| *x == 0) && x[12..].iter().any(|x| *x != 0))
+        .map(|x| Address::from_slice(&x[12..]))
+}
+
+/// The senders and receivers of a block's transactions and the contracts that emitted its logs.
+/// None if the block isn't available yet
+async fn touched_addresses(
+    rpcs: &Web3Rpcs,
+    authorization: &Arc<Authorization>,
+    block: &Web3ProxyBlock,
+) -> Web3ProxyResult<Option<HashSet<Address>>> {
+    let block_num = *block.number();
+
+    let block_request = JsonRpcRequest::new(
+        JsonRpcId::Number(1),
+        "eth_getBlockByHash".to_string(),
+        Some(json!([block.hash(), true])),
+    )?;
+
+    let logs_request = JsonRpcRequest::new(
+        JsonRpcId::Number(1),
+        "eth_getLogs".to_string(),
+        Some(json!([{ "blockHash": block.hash() }])),
+    )?;
+
+    let (block_response, logs_response) = tokio::try_join!(
+        rpcs.try_proxy_connection(authorization, block_request, None, Some(&block_num), None),
+        rpcs.try_proxy_connection(authorization, logs_request, None, Some(&block_num), None),
+    )?;
+
+    if block_response.error.is_some() {
+        return Err(block_response.into());
+    }
+
+    if logs_response.error.is_some() {
+        return Err(logs_response.into());
+    }
+
+    let full_block: Option<Block<Transaction>> = serde_json::from_str(
+        block_response
+            .result
+            .as_ref()
+            .web3_context("no block result")?
+            .get(),
+    )?;
+
+    let full_block = match full_block {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let logs: Vec<Log> = serde_json::from_str(
+        logs_response
+            .result
+            .as_ref()
+            .web3_context("no logs result")?
+            .get(),
+    )?;
+
+    let mut addresses = HashSet::new();
+
+    for tx in full_block.transactions {
+        addresses.insert(tx.from);
+
+        if let Some(to) = tx.to {
+            addresses.insert(to);
+        }
+    }
+
+    for log in logs {
+        addresses.insert(log.address);
+    }
+
+    // the miner gets paid every block
+    if let Some(author) = full_block.author {
+        addresses.insert(author);
+    }
+
+    Ok(Some(addresses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_block(num: u64, hash: u8, parent_hash: u8) -> Web3ProxyBlock {
+        let block = Block {
+            number: Some(num.into()),
+            hash: Some(H256::repeat_byte(hash)),
+            parent_hash: H256::repeat_byte(parent_hash),
+            ..Default::default()
+        };
+
+        Web3ProxyBlock::try_from(Arc::new(block)).unwrap()
+    }
+
+    fn push(cache: &ContractCallCache, block: &Web3ProxyBlock, addresses: &[Address]) {
+        cache.touches.write().push_back(BlockTouches {
+            num: *block.number(),
+            hash: *block.hash(),
+            parent_hash: *block.parent_hash(),
+            addresses: addresses.iter().copied().collect(),
+        });
+    }
+
+    #[tokio::test]
+    async fn test_call_cache() {
+        let cache = ContractCallCache::new(CallCacheConfig {
+            enabled: true,
+            ..Default::default()
+        });
+
+        let token = Address::repeat_byte(1);
+        let other = Address::repeat_byte(2);
+
+        // symbol()
+        let params = json!([{"to": token, "data": "0x95d89b41"}, "latest"]);
+
+        let key = cache.key(Some(&params)).unwrap();
+        assert_eq!(key.addresses, vec![token]);
+
+        // transfer(address,uint256) isn't in the default selectors
+        assert!(cache
+            .key(Some(&json!([{"to": token, "data": "0xa9059cbb"}])))
+            .is_none());
+
+        // old blocks are cached by the response cache already
+        assert!(cache
+            .key(Some(&json!([{"to": token, "data": "0x95d89b41"}, "0x1"])))
+            .is_none());
+
+        let block_100 = test_block(100, 100, 99);
+        let block_101 = test_block(101, 101, 100);
+        let block_102 = test_block(102, 102, 101);
+
+        push(&cache, &block_100, &[]);
+
+        let response = JsonRpcForwardedResponse::from_value(json!("0x1234"), Default::default());
+
+        cache.insert(key, &block_100, &response).await;
+
+        let key = cache.key(Some(&params)).unwrap();
+
+        // the touches aren't known for the head yet
+        assert!(cache.get(&key, 101.into()).is_none());
+
+        push(&cache, &block_101, &[other]);
+        assert!(cache.get(&key, 101.into()).is_some());
+
+        push(&cache, &block_102, &[token]);
+        assert!(cache.get(&key, 101.into()).is_some());
+        assert!(cache.get(&key, 102.into()).is_none());
+
+        assert_eq!(cache.metrics().hits, 2);
+    }
+
+    #[test]
+    fn test_calldata_addresses() {
+        let owner = Address::repeat_byte(0xaa);
+
+        // balanceOf(owner)
+        let data = [&[0x70, 0xa0, 0x82, 0x31][..], &[0; 12], owner.as_bytes()].concat();
+
+        assert_eq!(calldata_addresses(&data).collect::<Vec<_>>(), vec![owner]);
+
+        // numbers aren't addresses
+        let data = [&[0x70, 0xa0, 0x82, 0x31][..], &[0; 32]].concat();
+
+        assert_eq!(calldata_addresses(&data).count(), 0);
+    }
+}
@@ -0,0 +1,485 @@
+///! An optional on-disk store behind the response cache.
+///!
+///! Only responses that will never change are saved here, so they survive restarts and aren't limited by RAM.
+use crate::config::DiskCacheConfig;
+use crate::jsonrpc::JsonRpcForwardedResponse;
+use anyhow::{anyhow, Context};
+use ethers::types::U64;
+use log::{info, trace, warn};
+use serde::Serialize;
+use sled::transaction::{TransactionError, TransactionalTree, UnabortableTransactionError};
+use sled::Transactional;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+
+/// How often to check if the cache needs to be compacted
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The key in the meta tree for the total length of the stored responses
+const STORED_BYTES: &[u8] = b"stored_bytes";
+
+/// The disk cache's counters in a format that serde_prometheus likes
+#[derive(Debug, Default, Serialize)]
+pub struct DiskCacheMetrics {
+    hits: u64,
+    misses: u64,
+    writes: u64,
+    evictions: u64,
+}
+
+/// If a response will never change. `block_num` is the block that anchors the request's cache key.
+/// null and empty results are never immutable. an unknown hash might be known after the next block
+pub fn is_immutable(
+    block_num: Option<U64>,
+    head_block_num: U64,
+    min_block_depth: u64,
+    response: &JsonRpcForwardedResponse,
+) -> bool {
+    if response.error.is_some() {
+        // errors might be temporary
+        return false;
+    }
+
+    let result = match response
+        .result
+        .as_ref()
+        .and_then(|x| serde_json::from_str::<serde_json::Value>(x.get()).ok())
+    {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let is_empty = match &result {
+        serde_json::Value::Null => true,
+        serde_json::Value::String(x) => x.is_empty() || x == "0x",
+        serde_json::Value::Array(x) => x.is_empty(),
+        serde_json::Value::Object(x) => x.is_empty(),
+        _ => false,
+    };
+
+    if is_empty {
+        return false;
+    }
+
+    let block_num = match block_num {
+        Some(x) => x,
+        None => match result_block_num(&result) {
+            Some(x) => x,
+            // without a block, we can't know if a reorg would change this
+            None => return false,
+        },
+    };
+
+    head_block_num.saturating_sub(block_num) >= min_block_depth.into()
+}
+
+/// The block that a result came from. Transactions and receipts have a blockNumber and blocks have a number
+fn result_block_num(result: &serde_json::Value) -> Option<U64> {
+    let block_num = result.get("blockNumber").or_else(|| result.get("number"))?;
+
+    serde_json::from_value(block_num.clone()).ok()
+}
+
+pub struct DiskCache {
+    config: DiskCacheConfig,
+    db: sled::Db,
+    /// serialized responses by `ResponseCacheKey::disk_key`
+    responses: sled::Tree,
+    /// disk key -> last access
+    accessed: sled::Tree,
+    /// last access ++ disk key -> (). iterating this gives the least recently used keys first
+    lru: sled::Tree,
+    /// `STORED_BYTES` -> the total length of the stored responses.
+    /// sled's size on disk doesn't shrink promptly after removes, so compaction can't use it
+    meta: sled::Tree,
+    /// always increasing so that the lru order is exact
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writes: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn open(config: DiskCacheConfig) -> anyhow::Result<Self> {
+        let path = config.path.as_ref().context("disk cache needs a path")?;
+
+        let db = sled::open(path).context("opening disk cache")?;
+
+        info!(
+            "disk cache at {} is {} bytes",
+            path,
+            db.size_on_disk().unwrap_or_default()
+        );
+
+        Self::from_db(config, db)
+    }
+
+    fn from_db(config: DiskCacheConfig, db: sled::Db) -> anyhow::Result<Self> {
+        let responses = db.open_tree("responses")?;
+        let accessed = db.open_tree("accessed")?;
+        let lru = db.open_tree("lru")?;
+        let meta = db.open_tree("meta")?;
+
+        // caches from before the counter existed need it counted once
+        if meta.get(STORED_BYTES)?.is_none() {
+            let mut stored_bytes = 0;
+
+            for x in responses.iter() {
+                let (_, response) = x?;
+
+                stored_bytes += response.len() as u64;
+            }
+
+            meta.insert(STORED_BYTES, &stored_bytes.to_be_bytes()[..])?;
+        }
+
+        Ok(Self {
+            config,
+            db,
+            responses,
+            accessed,
+            lru,
+            meta,
+            clock: 0.into(),
+            hits: 0.into(),
+            misses: 0.into(),
+            writes: 0.into(),
+            evictions: 0.into(),
+        })
+    }
+
+    /// Responses for blocks at least this far behind the head are safe to save forever
+    pub fn min_block_depth(&self) -> u64 {
+        self.config.min_block_depth
+    }
+
+    fn now(&self) -> u64 {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+
+        let last = self
+            .clock
+            .fetch_update(
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+                |last| Some(now.max(last + 1)),
+            )
+            .expect("the closure always returns Some");
+
+        now.max(last + 1)
+    }
+
+    /// The total length of the stored responses
+    pub fn stored_bytes(&self) -> anyhow::Result<u64> {
+        Ok(self
+            .meta
+            .get(STORED_BYTES)?
+            .map(decode_u64)
+            .unwrap_or_default())
+    }
+
+    fn get_blocking(&self, key: &[u8; 32]) -> anyhow::Result<Option<JsonRpcForwardedResponse>> {
+        let response = match self.responses.get(key)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let now = self.now().to_be_bytes();
+
+        // concurrent touches of the same key would leave extra lru entries. those would evict a live response
+        (&self.responses, &self.accessed, &self.lru)
+            .transaction(|(responses, accessed, lru)| {
+                // it might have been evicted since we read it
+                if responses.get(&key[..])?.is_some() {
+                    touch(accessed, lru, key, &now)?;
+                }
+
+                Ok(())
+            })
+            .map_err(|err: TransactionError| anyhow!("disk cache touch failed. {:?}", err))?;
+
+        Ok(Some(serde_json::from_slice(&response)?))
+    }
+
+    fn insert_blocking(&self, key: &[u8; 32], response: &[u8]) -> anyhow::Result<()> {
+        let now = self.now().to_be_bytes();
+
+        (&self.responses, &self.accessed, &self.lru, &self.meta)
+            .transaction(|(responses, accessed, lru, meta)| {
+                let old_len = responses
+                    .insert(&key[..], response)?
+                    .map(|x| x.len() as u64)
+                    .unwrap_or_default();
+
+                add_stored_bytes(meta, response.len() as u64, old_len)?;
+
+                touch(accessed, lru, key, &now)?;
+
+                Ok(())
+            })
+            .map_err(|err: TransactionError| anyhow!("disk cache insert failed. {:?}", err))?;
+
+        Ok(())
+    }
+
+    /// Remove the least recently used responses until at least `bytes` have been freed.
+    /// Returns the number of bytes freed
+    fn evict_blocking(&self, bytes: u64) -> anyhow::Result<u64> {
+        let mut freed = 0;
+
+        for x in self.lru.iter() {
+            if freed >= bytes {
+                break;
+            }
+
+            let (lru_key, _) = x?;
+
+            let key = &lru_key[8..];
+
+            freed += (&self.responses, &self.accessed, &self.lru, &self.meta)
+                .transaction(|(responses, accessed, lru, meta)| {
+                    lru.remove(lru_key.clone())?;
+
+                    // a stale entry. the response was used more recently than this
+                    if let Some(accessed_at) = accessed.get(key)? {
+                        if accessed_at.as_ref() != &lru_key[..8] {
+                            return Ok(0);
+                        }
+                    }
+
+                    accessed.remove(key)?;
+
+                    let len = responses
+                        .remove(key)?
+                        .map(|x| x.len() as u64)
+                        .unwrap_or_default();
+
+                    add_stored_bytes(meta, 0, len)?;
+
+                    Ok(len)
+                })
+                .map_err(|err: TransactionError| {
+                    anyhow!("disk cache eviction failed. {:?}", err)
+                })?;
+
+            self.evictions.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        Ok(freed)
+    }
+
+    fn compact_blocking(&self) -> anyhow::Result<()> {
+        let size = self.stored_bytes()?;
+
+        if size <= self.config.max_bytes {
+            return Ok(());
+        }
+
+        // free an extra 10% so that this doesn't run every interval
+        let bytes = size - self.config.max_bytes + self.config.max_bytes / 10;
+
+        let freed = self.evict_blocking(bytes)?;
+
+        info!(
+            "disk cache held {} bytes of responses. freed {} bytes. {} bytes on disk",
+            size,
+            freed,
+            self.db.size_on_disk().unwrap_or_default()
+        );
+
+        Ok(())
+    }
+
+    pub async fn get(self: &Arc<Self>, key: [u8; 32]) -> Option<JsonRpcForwardedResponse> {
+        let x = self.clone();
+
+        match spawn_blocking(move || x.get_blocking(&key)).await {
+            Ok(Ok(Some(response))) => {
+                self.hits.fetch_add(1, atomic::Ordering::Relaxed);
+                Some(response)
+            }
+            Ok(Ok(None)) => {
+                self.misses.fetch_add(1, atomic::Ordering::Relaxed);
+                None
+            }
+            Ok(Err(err)) => {
+                warn!("disk cache read failed. err={:?}", err);
+                None
+            }
+            Err(err) => {
+                warn!("disk cache read panicked. err={:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Save a response in the background. Responses larger than `max_item_bytes` are skipped
+    pub fn insert(self: &Arc<Self>, key: [u8; 32], response: &JsonRpcForwardedResponse) {
+        let response = match serde_json::to_vec(response) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(
+                    "unable to serialize response for the disk cache. err={:?}",
+                    err
+                );
+                return;
+            }
+        };
+
+        if response.len() as u64 > self.config.max_item_bytes {
+            trace!("{} bytes is too large for the disk cache", response.len());
+            return;
+        }
+
+        let x = self.clone();
+
+        spawn_blocking(move || {
+            if let Err(err) = x.insert_blocking(&key, &response) {
+                warn!("disk cache write failed. err={:?}", err);
+            } else {
+                trace!("saved {} bytes to the disk cache", response.len());
+                x.writes.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Keep the cache under its max size
+    pub async fn compact_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(COMPACT_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let x = self.clone();
+
+            if let Err(err) = spawn_blocking(move || x.compact_blocking()).await? {
+                warn!("disk cache compaction failed. err={:?}", err);
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> DiskCacheMetrics {
+        DiskCacheMetrics {
+            hits: self.hits.load(atomic::Ordering::Relaxed),
+            misses: self.misses.load(atomic::Ordering::Relaxed),
+            writes: self.writes.load(atomic::Ordering::Relaxed),
+            evictions: self.evictions.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+fn decode_u64(x: impl AsRef<[u8]>) -> u64 {
+    u64::from_be_bytes(x.as_ref().try_into().unwrap_or_default())
+}
+
+/// Move a key to the back of the lru
+fn touch(
+    accessed: &TransactionalTree,
+    lru: &TransactionalTree,
+    key: &[u8; 32],
+    now: &[u8; 8],
+) -> Result<(), UnabortableTransactionError> {
+    if let Some(old) = accessed.insert(&key[..], &now[..])? {
+        lru.remove([old.as_ref(), &key[..]].concat())?;
+    }
+
+    lru.insert([&now[..], &key[..]].concat(), vec![])?;
+
+    Ok(())
+}
+
+fn add_stored_bytes(
+    meta: &TransactionalTree,
+    add: u64,
+    sub: u64,
+) -> Result<(), UnabortableTransactionError> {
+    let old = meta.get(STORED_BYTES)?.map(decode_u64).unwrap_or_default();
+
+    let new = (old + add).saturating_sub(sub);
+
+    meta.insert(STORED_BYTES, &new.to_be_bytes()[..])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_disk_cache_lru() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let cache = DiskCache::from_db(Default::default(), db).unwrap();
+
+        let response =
+            JsonRpcForwardedResponse::from_value(json!({"number": "0x1"}), Default::default());
+        let serialized = serde_json::to_vec(&response).unwrap();
+
+        let (a, b, c) = ([1; 32], [2; 32], [3; 32]);
+
+        cache.insert_blocking(&a, &serialized).unwrap();
+        cache.insert_blocking(&b, &serialized).unwrap();
+        cache.insert_blocking(&c, &serialized).unwrap();
+
+        assert_eq!(cache.stored_bytes().unwrap(), 3 * serialized.len() as u64);
+
+        // replacing a response doesn't count it twice
+        cache.insert_blocking(&c, &serialized).unwrap();
+        assert_eq!(cache.stored_bytes().unwrap(), 3 * serialized.len() as u64);
+        assert_eq!(cache.lru.len(), 3);
+
+        // reading a makes b the least recently used
+        let cached = cache.get_blocking(&a).unwrap().unwrap();
+        assert_eq!(cached.result.unwrap().get(), response.result.unwrap().get());
+
+        // an extra entry for a that is older than its last access. it must not evict a
+        cache
+            .lru
+            .insert([&[0; 8][..], &a[..]].concat(), vec![])
+            .unwrap();
+
+        assert_eq!(cache.evict_blocking(1).unwrap(), serialized.len() as u64);
+
+        assert!(cache.get_blocking(&a).unwrap().is_some());
+        assert!(cache.get_blocking(&b).unwrap().is_none());
+        assert!(cache.get_blocking(&c).unwrap().is_some());
+
+        assert_eq!(cache.lru.len(), 2);
+        assert_eq!(cache.accessed.len(), 2);
+        assert_eq!(cache.stored_bytes().unwrap(), 2 * serialized.len() as u64);
+    }
+
+    #[test]
+    fn test_is_immutable() {
+        let head = U64::from(100);
+
+        let receipt = JsonRpcForwardedResponse::from_value(
+            json!({"blockNumber": "0x14", "status": "0x1"}),
+            Default::default(),
+        );
+
+        // lookups by hash use the block in the response
+        assert!(is_immutable(None, head, 64, &receipt));
+        assert!(!is_immutable(None, head, 90, &receipt));
+
+        // the request's block wins
+        assert!(!is_immutable(Some(90.into()), head, 64, &receipt));
+
+        // unknown hashes might be known after the next block
+        let null = JsonRpcForwardedResponse::from_value(json!(null), Default::default());
+        assert!(!is_immutable(None, head, 64, &null));
+        assert!(!is_immutable(Some(1.into()), head, 64, &null));
+
+        let empty = JsonRpcForwardedResponse::from_value(json!([]), Default::default());
+        assert!(!is_immutable(Some(1.into()), head, 64, &empty));
+
+        // without a block, a reorg could change this
+        let balance = JsonRpcForwardedResponse::from_value(json!("0x1"), Default::default());
+        assert!(!is_immutable(None, head, 64, &balance));
+        assert!(is_immutable(Some(1.into()), head, 64, &balance));
+    }
+}
@@ -1,34 +1,47 @@
 // TODO: this file is way too big now. move things into other modules
+mod call_cache;
+mod disk_cache;
+mod multicall;
+mod prefetch;
+mod response_cache;
+mod single_flight;
 mod ws;
 
 use crate::block_number::{block_needed, BlockNeeded};
-use crate::config::{AppConfig, TopConfig};
+use crate::config::{AppConfig, ChainSplitTxPolicy, TopConfig};
 use crate::frontend::authorization::{Authorization, RequestMetadata, RpcSecretKey};
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::jsonrpc::{
-    JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest, JsonRpcRequestEnum,
+    CacheControl, CacheStatus, JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcId,
+    JsonRpcRequest, JsonRpcRequestEnum, JsonRpcResponseMetadata,
 };
+use crate::rpcs::affinity::{AffinityKey, BackendAffinity};
 use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::chain_split::{ChainSplitDetector, ChainSplitMetrics};
+use crate::rpcs::circuit_breaker::CircuitBreakerMetrics;
 use crate::rpcs::consensus::ConsensusWeb3Rpcs;
-use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::many::{ResponseAggregate, Web3Rpcs};
 use crate::rpcs::one::Web3Rpc;
-use crate::rpcs::transactions::TxStatus;
+use crate::rpcs::private_txs::{PrivateTxStatus, PrivateTxTracker};
+use crate::rpcs::recent_txs::{RecentTxs, MAX_RECEIPT_WAIT};
+use crate::rpcs::transactions::{decode_raw_transaction, TxStatus};
 use crate::stats::{AppStat, RpcQueryStats, StatBuffer};
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
 use axum::headers::{Origin, Referer, UserAgent};
 use axum::http::StatusCode;
+use call_cache::{CallCacheMetrics, ContractCallCache};
 use chrono::Utc;
 use deferred_rate_limiter::DeferredRateLimiter;
 use derive_more::From;
+use disk_cache::{is_immutable, DiskCache, DiskCacheMetrics};
 use entities::sea_orm_active_enums::TrackingLevel;
 use entities::user;
 use ethers::core::utils::keccak256;
 use ethers::prelude::{Address, Bytes, Transaction, TxHash, H256, U64};
 use ethers::types::U256;
 use ethers::utils::rlp::{Decodable, Rlp};
-use futures::future::join_all;
 use futures::stream::{FuturesUnordered, StreamExt};
 use hashbrown::{HashMap, HashSet};
 use ipnet::IpNet;
@@ -39,15 +52,22 @@ use migration::sea_orm::{
 use migration::sea_query::table::ColumnDef;
 use migration::{Alias, DbErr, Migrator, MigratorTrait, Table};
 use moka::future::Cache;
+use multicall::{MulticallBatcher, MulticallMetrics};
+use pagerduty_rs::eventsv2async::EventsV2 as PagerdutyAsyncEventsV2;
+use prefetch::HotRequests;
 use rdkafka::message::{Header, OwnedHeaders};
 use rdkafka::producer::FutureRecord;
 use redis_rate_limiter::redis::AsyncCommands;
 use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter};
+use response_cache::{ResponseCacheMethodMetrics, ResponseCacheStats};
 use serde::Serialize;
 use serde_json::json;
 use serde_json::value::to_raw_value;
+use single_flight::{is_cacheable_read, is_deduplicable, SingleFlight, SingleFlightMetrics};
 use std::fmt;
+use std::future::Future;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::net::IpAddr;
 use std::num::NonZeroU64;
 use std::str::FromStr;
@@ -55,7 +75,7 @@ use std::sync::{atomic, Arc};
 use std::time::Duration;
 use tokio::sync::{broadcast, watch, Semaphore};
 use tokio::task::JoinHandle;
-use tokio::time::{sleep, timeout};
+use tokio::time::{sleep, timeout, timeout_at, Instant};
 use ulid::Ulid;
 
 // TODO: make this customizable?
@@ -70,6 +90,13 @@ pub static APP_USER_AGENT: &str = concat!(
 // aggregate across 1 week
 pub const BILLING_PERIOD_SECONDS: i64 = 60 * 60 * 24 * 7;
 
+/// How long web3proxy_waitForTransactionReceipt waits if no timeout is given
+pub const DEFAULT_RECEIPT_LONG_POLL: Duration = Duration::from_secs(30);
+/// Long polls hold a connection open. Don't let them hold it for too long
+pub const MAX_RECEIPT_LONG_POLL: Duration = Duration::from_secs(60);
+/// Don't scan too many cached blocks at once after a gap in the consensus heads
+const MAX_RECEIPT_SCAN_BLOCKS: u64 = 100;
+
 #[derive(Debug, From)]
 struct ResponseCacheKey {
     // if none, this is cached until evicted
@@ -84,7 +111,8 @@ struct ResponseCacheKey {
 
 impl ResponseCacheKey {
     fn weight(&self) -> usize {
-        let mut w = self.method.len();
+        // the blocks are shared with the block caches, so only count the pointers to them
+        let mut w = std::mem::size_of::<Self>() + self.method.len();
 
         if let Some(p) = self.params.as_ref() {
             w += p.to_string().len();
@@ -92,6 +120,44 @@ impl ResponseCacheKey {
 
         w
     }
+
+    /// A key that is the same after a restart. The Hash impl uses a random seed
+    fn disk_key(&self, chain_id: u64) -> [u8; 32] {
+        let x = json!([
+            chain_id,
+            self.from_block.as_ref().map(|x| x.hash()),
+            self.to_block.as_ref().map(|x| x.hash()),
+            self.method,
+            self.params,
+            self.cache_errors,
+        ]);
+
+        keccak256(x.to_string())
+    }
+
+    /// If this key is only good until the next head block
+    fn is_head_scoped(&self, head_block_num: U64) -> bool {
+        self.to_block.is_none()
+            && self.from_block.as_ref().map(|x| *x.number()) == Some(head_block_num)
+    }
+
+    /// If the response for this key might never change. Checked before the response exists
+    fn may_be_immutable(&self, head_block_num: U64, min_block_depth: u64) -> bool {
+        // ranges are anchored by their last block
+        match self.to_block.as_ref().or(self.from_block.as_ref()) {
+            // lookups by hash are anchored by the block in their response
+            None => true,
+            Some(block) => head_block_num.saturating_sub(*block.number()) >= min_block_depth.into(),
+        }
+    }
+}
+
+/// The approximate number of bytes that a response uses in the response cache
+fn response_cache_weight(key_weight: usize, v: &JsonRpcForwardedResponse) -> u32 {
+    let weight = key_weight + std::mem::size_of::<JsonRpcForwardedResponse>() + v.num_bytes();
+
+    // the or in unwrap_or is probably never called
+    weight.try_into().unwrap_or(u32::MAX)
 }
 
 impl PartialEq for ResponseCacheKey {
@@ -153,6 +219,17 @@ impl Hash for ResponseCacheKey {
 type ResponseCache =
     Cache<ResponseCacheKey, JsonRpcForwardedResponse, hashbrown::hash_map::DefaultHashBuilder>;
 
+/// Requests that can't be cached are only shared while they are in flight
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct SingleFlightKey {
+    head_block_num: Option<U64>,
+    method: String,
+    params: Option<String>,
+}
+
+type InFlightRequests =
+    SingleFlight<SingleFlightKey, Result<JsonRpcForwardedResponse, Arc<Web3ProxyError>>>;
+
 pub type AnyhowJoinHandle<T> = JoinHandle<anyhow::Result<T>>;
 
 /// TODO: move this
@@ -189,6 +266,20 @@ pub struct AuthorizationChecks {
     /// IMPORTANT! Once confirmed by a miner, they will be public on the blockchain!
     pub private_txs: bool,
     pub proxy_mode: ProxyMode,
+    /// if true, pending nonces are the highest from all the synced rpcs
+    pub aggregate_pending_nonce: bool,
+    /// if true, transactions are simulated and rejected if they would revert
+    pub revert_protection: bool,
+    /// if true, eth_calls are combined with other eth_calls for the same block into one Multicall3 call
+    pub multicall: bool,
+    /// the most requests allowed in one batch. None uses the app's batch.max_size. inherited from the user_tier
+    pub max_batch_size: Option<usize>,
+    /// None uses the app's request_timeout_seconds. inherited from the user_tier
+    pub request_timeout: Option<Duration>,
+    /// None uses the app's max_request_bytes. inherited from the user_tier
+    pub max_request_bytes: Option<u64>,
+    /// None uses the app's max_response_bytes. inherited from the user_tier
+    pub max_response_bytes: Option<u64>,
 }
 
 /// Simple wrapper so that we can keep track of read only connections.
@@ -209,6 +300,15 @@ impl DatabaseReplica {
 pub struct Web3ProxyApp {
     /// Send requests to the best server available
     pub balanced_rpcs: Arc<Web3Rpcs>,
+    /// nonce-sensitive requests from the same key or sender stick to one of the balanced rpcs
+    pub backend_affinity: BackendAffinity,
+    /// transactions recently sent by each rpc key. used for read-your-writes
+    pub recent_txs: RecentTxs,
+    /// what the private rpcs said about the transactions that users sent them
+    pub private_tx_tracker: PrivateTxTracker,
+    /// the highest pending nonce from all the synced rpcs
+    pending_nonce_cache:
+        Cache<Address, JsonRpcForwardedResponse, hashbrown::hash_map::DefaultHashBuilder>,
     /// Send 4337 Abstraction Bundler requests to one of these servers
     pub bundler_4337_rpcs: Option<Arc<Web3Rpcs>>,
     pub http_client: Option<reqwest::Client>,
@@ -220,6 +320,17 @@ pub struct Web3ProxyApp {
     pub private_rpcs: Option<Arc<Web3Rpcs>>,
     /// track JSONRPC responses
     response_cache: ResponseCache,
+    response_cache_stats: Arc<ResponseCacheStats>,
+    /// responses that will never change can also be saved to disk
+    disk_cache: Option<Arc<DiskCache>>,
+    /// identical requests that can't be cached share a backend request while it is in flight
+    in_flight_requests: InFlightRequests,
+    /// the hottest head-block requests are refreshed on every new head
+    hot_requests: HotRequests,
+    /// eth_call results that are still valid because nothing they depend on was touched
+    call_cache: ContractCallCache,
+    /// concurrent eth_calls for the same block can share one Multicall3 call
+    multicall: Arc<MulticallBatcher>,
     /// rpc clients that subscribe to newHeads use this channel
     /// don't drop this or the sender will stop working
     /// TODO: broadcast channel instead?
@@ -290,6 +401,19 @@ pub async fn flatten_handles<T>(
     Ok(())
 }
 
+/// Like `tokio::time::timeout`, but None waits forever and running out of time is a RequestTimeout
+async fn with_timeout<T>(
+    max_time: Option<Duration>,
+    f: impl Future<Output = Web3ProxyResult<T>>,
+) -> Web3ProxyResult<T> {
+    match max_time {
+        Some(max_time) => timeout(max_time, f)
+            .await
+            .map_err(|_| Web3ProxyError::RequestTimeout(max_time))?,
+        None => f.await,
+    }
+}
+
 pub async fn get_db(
     db_url: String,
     min_connections: u32,
@@ -409,6 +533,7 @@ impl Web3ProxyApp {
         top_config: TopConfig,
         num_workers: usize,
         shutdown_sender: broadcast::Sender<()>,
+        pagerduty_async: Option<Arc<PagerdutyAsyncEventsV2>>,
     ) -> anyhow::Result<Web3ProxyAppSpawn> {
         let stat_buffer_shutdown_receiver = shutdown_sender.subscribe();
         let mut background_shutdown_receiver = shutdown_sender.subscribe();
@@ -680,24 +805,38 @@ impl Web3ProxyApp {
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
         // responses can be very different in sizes, so this is a cache with a max capacity and a weigher
-        // TODO: don't allow any response to be bigger than X% of the cache
-        let response_cache = Cache::builder()
-            .max_capacity(top_config.app.response_cache_max_bytes)
-            .weigher(|k: &ResponseCacheKey, v| {
-                // TODO: is this good enough?
-                if let Ok(v) = serde_json::to_string(v) {
-                    let weight = k.weight() + v.len();
-
-                    // the or in unwrap_or is probably never called
-                    weight.try_into().unwrap_or(u32::MAX)
-                } else {
-                    // this seems impossible
-                    u32::MAX
-                }
-            })
-            // TODO: what should we set? 10 minutes is arbitrary. the nodes themselves hold onto transactions for much longer
-            .time_to_live(Duration::from_secs(600))
-            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        // responses bigger than response_cache_max_item_bytes are never inserted
+        let response_cache_stats = Arc::new(ResponseCacheStats::default());
+
+        let response_cache = {
+            let response_cache_stats = response_cache_stats.clone();
+
+            Cache::builder()
+                .max_capacity(top_config.app.response_cache_max_bytes)
+                .weigher(|k: &ResponseCacheKey, v| response_cache_weight(k.weight(), v))
+                .eviction_listener_with_queued_delivery_mode(
+                    move |k: Arc<ResponseCacheKey>, v, cause| {
+                        response_cache_stats.removed(
+                            &k.method,
+                            response_cache_weight(k.weight(), &v),
+                            cause,
+                        )
+                    },
+                )
+                // TODO: what should we set? 10 minutes is arbitrary. the nodes themselves hold onto transactions for much longer
+                .time_to_live(Duration::from_secs(600))
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default())
+        };
+
+        let disk_cache = if top_config.app.disk_cache.path.is_some() {
+            let disk_cache = Arc::new(DiskCache::open(top_config.app.disk_cache.clone())?);
+
+            app_handles.push(tokio::spawn(disk_cache.clone().compact_loop()));
+
+            Some(disk_cache)
+        } else {
+            None
+        };
 
         // all the users are the same size, so no need for a weigher
         // if there is no database of users, there will be no keys and so this will be empty
@@ -720,6 +859,13 @@ impl Web3ProxyApp {
             .time_to_idle(Duration::from_secs(120))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+        // pagerduty is optional. it is used for alerts like chain splits
+        let chain_split = ChainSplitDetector::new(
+            top_config.app.chain_split.clone(),
+            top_config.app.chain_id,
+            pagerduty_async,
+        );
+
         let (balanced_rpcs, balanced_handle, consensus_connections_watcher) = Web3Rpcs::spawn(
             top_config.app.chain_id,
             db_conn.clone(),
@@ -731,6 +877,7 @@ impl Web3ProxyApp {
             pending_transactions.clone(),
             Some(pending_tx_sender.clone()),
             Some(watch_consensus_head_sender),
+            chain_split,
         )
         .await
         .context("spawning balanced rpcs")?;
@@ -763,6 +910,8 @@ impl Web3ProxyApp {
                 // however, they are well connected to miners/validators. so maybe using them as a safety check would be good
                 // TODO: but maybe we could include privates in the "backup" tier
                 None,
+                // without heads, there are no chain splits to detect
+                Default::default(),
             )
             .await
             .context("spawning private_rpcs")?;
@@ -791,6 +940,7 @@ impl Web3ProxyApp {
                 pending_transactions.clone(),
                 None,
                 None,
+                Default::default(),
             )
             .await
             .context("spawning bundler_4337_rpcs")?;
@@ -804,14 +954,38 @@ impl Web3ProxyApp {
             .ok()
             .and_then(|x| x.to_str().map(|x| x.to_string()));
 
+        let backend_affinity = BackendAffinity::new(&top_config.app.affinity);
+
+        let recent_txs =
+            RecentTxs::new(Duration::from_secs(top_config.app.read_your_writes_seconds));
+
+        // all the responses are small, so no need for a weigher
+        // TODO: max_capacity from config
+        let pending_nonce_cache = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_millis(
+                top_config.app.aggregate_pending_nonce.cache_millis,
+            ))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
         let app = Self {
             config: top_config.app.clone(),
             balanced_rpcs,
+            backend_affinity,
+            recent_txs,
+            pending_nonce_cache,
+            private_tx_tracker: Default::default(),
             bundler_4337_rpcs,
             http_client,
             kafka_producer,
             private_rpcs,
             response_cache,
+            response_cache_stats,
+            disk_cache,
+            in_flight_requests: Default::default(),
+            hot_requests: HotRequests::new(top_config.app.prefetch.clone()),
+            call_cache: ContractCallCache::new(top_config.app.call_cache.clone()),
+            multicall: Arc::new(MulticallBatcher::new(top_config.app.multicall.clone())),
             watch_consensus_head_receiver,
             pending_tx_sender,
             pending_transactions,
@@ -832,6 +1006,83 @@ impl Web3ProxyApp {
 
         let app = Arc::new(app);
 
+        // private transactions are found by scanning the consensus head blocks
+        if app.private_rpcs.is_some() {
+            let app = app.clone();
+            let mut head_receiver = app.watch_consensus_head_receiver.clone();
+
+            let private_tx_handle = tokio::spawn(async move {
+                loop {
+                    let head_block = head_receiver.borrow_and_update().clone();
+
+                    if let Some(head_block) = head_block {
+                        app.private_tx_tracker
+                            .on_head_block(&app.balanced_rpcs, &head_block);
+                    }
+
+                    head_receiver
+                        .changed()
+                        .await
+                        .context("failed awaiting consensus head change")?;
+                }
+            });
+
+            app_handles.push(private_tx_handle);
+        }
+
+        // refresh the hottest head-block requests as soon as there is a new head
+        if app.hot_requests.enabled() {
+            let app = app.clone();
+            let mut head_receiver = app.watch_consensus_head_receiver.clone();
+
+            let prefetch_handle = tokio::spawn(async move {
+                loop {
+                    head_receiver
+                        .changed()
+                        .await
+                        .context("failed awaiting consensus head change")?;
+
+                    let head_block_num = head_receiver
+                        .borrow_and_update()
+                        .as_ref()
+                        .map(|x| *x.number());
+
+                    if let Some(head_block_num) = head_block_num {
+                        app.prefetch_hot_requests(head_block_num).await;
+                    }
+                }
+            });
+
+            app_handles.push(prefetch_handle);
+        }
+
+        // find the addresses touched by every consensus head so that eth_call results can be reused
+        if app.call_cache.enabled() {
+            let app = app.clone();
+            let mut head_receiver = app.watch_consensus_head_receiver.clone();
+
+            let authorization = Arc::new(Authorization::internal(app.db_conn())?);
+
+            let call_cache_handle = tokio::spawn(async move {
+                loop {
+                    let head_block = head_receiver.borrow_and_update().clone();
+
+                    if let Some(head_block) = head_block {
+                        app.call_cache
+                            .on_head_block(&app.balanced_rpcs, &authorization, &head_block)
+                            .await;
+                    }
+
+                    head_receiver
+                        .changed()
+                        .await
+                        .context("failed awaiting consensus head change")?;
+                }
+            });
+
+            app_handles.push(call_cache_handle);
+        }
+
         // watch for config changes
         // TODO: initial config reload should be from this channel. not from the call to spawn
 
@@ -917,6 +1168,14 @@ impl Web3ProxyApp {
         Ok(())
     }
 
+    /// Requests for state older than this need an archive server.
+    /// Uses the deepest block data limit of the pruned servers once it is known
+    pub fn archive_depth(&self) -> u64 {
+        self.balanced_rpcs
+            .archive_depth()
+            .unwrap_or(self.config.archive_depth)
+    }
+
     pub fn head_block_receiver(&self) -> watch::Receiver<Option<Web3ProxyBlock>> {
         self.watch_consensus_head_receiver.clone()
     }
@@ -1084,13 +1343,75 @@ impl Web3ProxyApp {
             recent_user_id_counts: RecentCounts,
             recent_tx_counts: RecentCounts,
             user_count: UserCount,
+            circuit_breakers: HashMap<String, CircuitBreakerMetrics>,
+            rpc_requests: HashMap<String, RpcRequestCounts>,
+            chain_split: ChainSplitMetrics,
+            response_cache: ResponseCacheMetrics,
+            response_cache_methods: HashMap<String, ResponseCacheMethodMetrics>,
+            disk_cache: DiskCacheMetrics,
+            single_flight: SingleFlightMetrics,
+            call_cache: CallCacheMetrics,
+            multicall: MulticallMetrics,
+        }
+
+        #[derive(Serialize)]
+        struct ResponseCacheMetrics {
+            entries: u64,
+            bytes: u64,
+            max_bytes: u64,
         }
 
+        #[derive(Serialize)]
+        struct RpcRequestCounts {
+            active: usize,
+            queued: usize,
+        }
+
+        let circuit_breakers = self
+            .balanced_rpcs
+            .by_name
+            .read()
+            .iter()
+            .map(|(name, rpc)| (name.clone(), rpc.circuit_breaker.metrics()))
+            .collect();
+
+        let rpc_requests = self
+            .balanced_rpcs
+            .by_name
+            .read()
+            .iter()
+            .map(|(name, rpc)| {
+                let counts = RpcRequestCounts {
+                    active: rpc.active_requests.load(atomic::Ordering::Relaxed),
+                    queued: rpc.queued_requests.load(atomic::Ordering::Relaxed),
+                };
+
+                (name.clone(), counts)
+            })
+            .collect();
+
         let metrics = CombinedMetrics {
             recent_ip_counts,
             recent_user_id_counts,
             recent_tx_counts,
             user_count,
+            circuit_breakers,
+            rpc_requests,
+            chain_split: self.balanced_rpcs.chain_split.metrics(),
+            response_cache: ResponseCacheMetrics {
+                entries: self.response_cache.entry_count(),
+                bytes: self.response_cache.weighted_size(),
+                max_bytes: self.config.response_cache_max_bytes,
+            },
+            response_cache_methods: self.response_cache_stats.metrics(),
+            disk_cache: self
+                .disk_cache
+                .as_ref()
+                .map(|x| x.metrics())
+                .unwrap_or_default(),
+            single_flight: self.in_flight_requests.metrics(),
+            call_cache: self.call_cache.metrics(),
+            multicall: self.multicall.metrics(),
         };
 
         // TODO: i don't like this library. it doesn't include HELP or TYPE lines and so our prometheus server fails to parse it
@@ -1108,33 +1429,105 @@ impl Web3ProxyApp {
 
         // even though we have timeouts on the requests to our backend providers,
         // we need a timeout for the incoming request so that retries don't run forever
-        // TODO: take this as an optional argument. per user max? expiration time instead of duration?
-        let max_time = Duration::from_secs(120);
+        // TODO: expiration time instead of duration?
+        let max_time = self.request_timeout(&authorization);
 
         let response = match request {
             JsonRpcRequestEnum::Single(request) => {
-                let (response, rpcs) = timeout(
+                let (response, rpcs) = with_timeout(
                     max_time,
                     self.proxy_cached_request(&authorization, request, None),
                 )
-                .await??;
+                .await?;
 
                 (JsonRpcForwardedResponseEnum::Single(response), rpcs)
             }
             JsonRpcRequestEnum::Batch(requests) => {
-                let (responses, rpcs) = timeout(
+                // the frontend already checked this before rate limiting. internal requests are checked here
+                self.check_batch_size(&authorization.checks, requests.len())?;
+
+                let (responses, rpcs) = with_timeout(
                     max_time,
                     self.proxy_web3_rpc_requests(&authorization, requests),
                 )
-                .await??;
+                .await?;
 
                 (JsonRpcForwardedResponseEnum::Batch(responses), rpcs)
             }
         };
 
+        if let Some(max) = self.max_response_bytes(&authorization) {
+            // TODO: stop reading from the backend once this is reached. ethers only gives us the whole response
+            let size = response.0.num_bytes() as u64;
+
+            if size > max {
+                return Err(Web3ProxyError::ResponseTooLarge { size, max });
+            }
+        }
+
         Ok(response)
     }
 
+    /// Batches bigger than the key's (or the app's) max_batch_size are rejected.
+    /// This is checked before rate limiting so that a rejected batch doesn't use up the rate limit
+    pub fn check_batch_size(
+        &self,
+        checks: &AuthorizationChecks,
+        num_requests: usize,
+    ) -> Web3ProxyResult<()> {
+        let max_batch_size = checks.max_batch_size.unwrap_or(self.config.batch.max_size);
+
+        if max_batch_size > 0 && num_requests > max_batch_size {
+            return Err(Web3ProxyError::BatchTooLarge {
+                size: num_requests,
+                max: max_batch_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The user tier's request timeout or the app's default. None means no timeout
+    pub fn request_timeout(&self, authorization: &Authorization) -> Option<Duration> {
+        authorization.checks.request_timeout.or_else(|| {
+            if self.config.request_timeout_seconds == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(self.config.request_timeout_seconds))
+            }
+        })
+    }
+
+    /// Requests larger than the user tier's max_request_bytes are rejected.
+    /// The app's max_request_bytes applies to everyone. Like the batch size, this is checked before rate limiting
+    pub fn check_request_bytes(
+        &self,
+        checks: &AuthorizationChecks,
+        size: usize,
+    ) -> Web3ProxyResult<()> {
+        let max = match (checks.max_request_bytes, self.config.max_request_bytes) {
+            (Some(x), 0) => x,
+            (Some(x), y) => x.min(y),
+            (None, y) => y,
+        };
+
+        let size = size as u64;
+
+        if max > 0 && size > max {
+            return Err(Web3ProxyError::RequestTooLarge { size, max });
+        }
+
+        Ok(())
+    }
+
+    /// The user tier's max_response_bytes or the app's default. None means no limit
+    pub fn max_response_bytes(&self, authorization: &Authorization) -> Option<u64> {
+        authorization
+            .checks
+            .max_response_bytes
+            .or(self.config.max_response_bytes)
+    }
+
     /// cut up the request and send to potentually different servers
     /// TODO: make sure this isn't a problem
     async fn proxy_web3_rpc_requests(
@@ -1145,7 +1538,6 @@ impl Web3ProxyApp {
         // TODO: we should probably change ethers-rs to support this directly. they pushed this off to v2 though
         let num_requests = requests.len();
 
-        // TODO: spawn so the requests go in parallel? need to think about rate limiting more if we do that
         // TODO: improve flattening
 
         // get the head block now so that any requests that need it all use the same block
@@ -1155,26 +1547,59 @@ impl Web3ProxyApp {
             .head_block_num()
             .ok_or(Web3ProxyError::NoServersSynced)?;
 
-        let responses = join_all(
-            requests
-                .into_iter()
-                .map(|request| {
-                    self.proxy_cached_request(authorization, request, Some(head_block_num))
+        // identical reads in a batch are only proxied once. they only differ by id
+        let mut ids = Vec::with_capacity(num_requests);
+        let mut unique_indexes = Vec::with_capacity(num_requests);
+        let mut unique_requests = vec![];
+        let mut seen = HashMap::new();
+
+        for request in requests {
+            let key = (
+                request.method.clone(),
+                request.params.as_ref().map(|x| x.to_string()),
+                request.web3proxy.clone(),
+            );
+
+            ids.push(request.id.clone());
+
+            // things like eth_newFilter return something different every time
+            let index = if is_cacheable_read(&request.method) {
+                *seen.entry(key).or_insert_with(|| {
+                    unique_requests.push(request);
+                    unique_requests.len() - 1
                 })
-                .collect::<Vec<_>>(),
-        )
-        .await;
+            } else {
+                unique_requests.push(request);
+                unique_requests.len() - 1
+            };
+
+            unique_indexes.push(index);
+        }
+
+        if unique_requests.len() < num_requests {
+            trace!(
+                "{} of {} batched requests are duplicates",
+                num_requests - unique_requests.len(),
+                num_requests
+            );
+        }
+
+        // buffered keeps the responses in the same order as the requests
+        let responses: Vec<_> = futures::stream::iter(unique_requests)
+            .map(|request| self.proxy_cached_request(authorization, request, Some(head_block_num)))
+            .buffered(self.config.batch.max_concurrent.max(1))
+            .collect()
+            .await;
 
-        // TODO: i'm sure this could be done better with iterators
         // TODO: stream the response?
-        let mut collected: Vec<JsonRpcForwardedResponse> = Vec::with_capacity(num_requests);
+        let mut unique_responses = Vec::with_capacity(responses.len());
         let mut collected_rpc_names: HashSet<String> = HashSet::new();
         let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
         for response in responses {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
             let (response, rpcs) = response?;
 
-            collected.push(response);
+            unique_responses.push(response);
             collected_rpcs.extend(rpcs.into_iter().filter(|x| {
                 if collected_rpc_names.contains(&x.name) {
                     false
@@ -1185,6 +1610,16 @@ impl Web3ProxyApp {
             }));
         }
 
+        let collected = ids
+            .into_iter()
+            .zip(unique_indexes)
+            .map(|(id, index)| {
+                let mut response = unique_responses[index].clone();
+                response.id = id;
+                response
+            })
+            .collect();
+
         Ok((collected, collected_rpcs))
     }
 
@@ -1209,132 +1644,732 @@ impl Web3ProxyApp {
         }
     }
 
-    /// try to send transactions to the best available rpcs with private mempools
-    /// if no private rpcs are configured, then some public rpcs are used instead
-    async fn try_send_protected(
-        self: &Arc<Self>,
-        authorization: &Arc<Authorization>,
-        request: &JsonRpcRequest,
-        request_metadata: Arc<RequestMetadata>,
-        num_public_rpcs: Option<usize>,
-    ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
-        if let Some(protected_rpcs) = self.private_rpcs.as_ref() {
-            if !protected_rpcs.is_empty() {
-                let protected_response = protected_rpcs
-                    .try_send_all_synced_connections(
-                        authorization,
-                        request,
-                        Some(request_metadata),
-                        None,
-                        None,
-                        Level::Trace,
-                        None,
-                        true,
-                    )
-                    .await?;
+    /// remember the balanced rpc that served a nonce-sensitive request
+    /// sends go to multiple rpcs. keep the preferred rpc if it was one of them
+    async fn record_backend_affinity(
+        &self,
+        affinity_key: AffinityKey,
+        request_metadata: &RequestMetadata,
+        rpcs: &[Arc<Web3Rpc>],
+    ) {
+        let preferred_rpc = request_metadata.preferred_rpc.lock().clone();
+
+        let rpc = rpcs
+            .iter()
+            .find(|x| Some(&x.name) == preferred_rpc.as_ref())
+            .or_else(|| rpcs.last());
+
+        if let Some(rpc) = rpc {
+            // private rpcs can't serve reads
+            if self.balanced_rpcs.by_name.read().contains_key(&rpc.name) {
+                if preferred_rpc.as_ref() != Some(&rpc.name) {
+                    trace!("{:?} now prefers {}", affinity_key, rpc);
+                }
 
-                return Ok(protected_response);
+                self.backend_affinity
+                    .record(affinity_key, rpc.name.clone())
+                    .await;
             }
         }
+    }
 
-        // no private rpcs to send to. send to a few public rpcs
-        // try_send_all_upstream_servers puts the request id into the response. no need to do that ourselves here.
-        self.balanced_rpcs
-            .try_send_all_synced_connections(
-                authorization,
-                request,
-                Some(request_metadata),
-                None,
-                None,
-                Level::Trace,
-                num_public_rpcs,
-                true,
-            )
-            .await
+    /// The status of a transaction that this user sent to the private rpcs
+    pub fn private_tx_status(&self, user_id: u64, tx_hash: &TxHash) -> Option<PrivateTxStatus> {
+        self.private_tx_tracker
+            .status(user_id, tx_hash, self.balanced_rpcs.head_block_num())
     }
 
-    // TODO: more robust stats and kafka logic! if we use the try operator, they aren't saved!
-    // TODO: move this to another module
-    async fn proxy_cached_request(
+    /// Wait for a transaction's receipt. The result is null if it isn't mined before the timeout
+    /// Each new consensus head is checked for the transaction before asking the rpcs for the receipt
+    pub async fn wait_for_transaction_receipt(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
-        mut request: JsonRpcRequest,
-        head_block_num: Option<U64>,
-    ) -> Web3ProxyResult<(JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>)> {
-        // TODO: move this code to another module so that its easy to turn this trace logging on in dev
-        trace!("Received request: {:?}", request);
-
-        let request_metadata = Arc::new(RequestMetadata::new(request.num_bytes()));
-
-        let mut kafka_stuff = None;
-
-        if matches!(authorization.checks.proxy_mode, ProxyMode::Debug) {
-            if let Some(kafka_producer) = self.kafka_producer.clone() {
-                let kafka_topic = "proxy_cached_request".to_string();
+        request_id: Box<serde_json::value::RawValue>,
+        tx_hash: TxHash,
+        max_wait: Duration,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+    ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
+        let deadline = Instant::now() + max_wait.min(MAX_RECEIPT_LONG_POLL);
+
+        let receipt_request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: request_id,
+            method: "eth_getTransactionReceipt".to_string(),
+            params: Some(json!([tx_hash])),
+            web3proxy: Default::default(),
+        };
 
-                let rpc_secret_key_id = authorization
-                    .checks
-                    .rpc_secret_key_id
-                    .map(|x| x.get())
-                    .unwrap_or_default();
+        let mut head_receiver = self.watch_consensus_head_receiver.clone();
 
-                let kafka_key = rmp_serde::to_vec(&rpc_secret_key_id)?;
+        let mut last_checked = head_receiver
+            .borrow_and_update()
+            .as_ref()
+            .map(|x| *x.number());
 
-                let request_bytes = rmp_serde::to_vec(&request)?;
+        let mut response = self
+            .balanced_rpcs
+            .try_proxy_connection(
+                authorization,
+                receipt_request.clone(),
+                request_metadata,
+                None,
+                None,
+            )
+            .await?;
 
-                let request_hash = Some(keccak256(&request_bytes));
+        while response
+            .result
+            .as_ref()
+            .map(|x| x.get() == "null")
+            .unwrap_or(false)
+        {
+            if !matches!(
+                timeout_at(deadline, head_receiver.changed()).await,
+                Ok(Ok(_))
+            ) {
+                // timed out. the null response says that the transaction isn't mined yet
+                break;
+            }
 
-                let chain_id = self.config.chain_id;
+            let head_block_num = match head_receiver.borrow_and_update().as_ref() {
+                Some(x) => *x.number(),
+                None => continue,
+            };
 
-                // another item is added with the response, so initial_capacity is +1 what is needed here
-                let kafka_headers = OwnedHeaders::new_with_capacity(4)
-                    .insert(Header {
-                        key: "request_hash",
-                        value: request_hash.as_ref(),
-                    })
-                    .insert(Header {
-                        key: "head_block_num",
-                        value: head_block_num.map(|x| x.to_string()).as_ref(),
-                    })
-                    .insert(Header {
-                        key: "chain_id",
-                        value: Some(&chain_id.to_le_bytes()),
-                    });
+            let first_num = match last_checked {
+                Some(x) if x < head_block_num => {
+                    (x + 1).max(head_block_num.saturating_sub(MAX_RECEIPT_SCAN_BLOCKS.into()))
+                }
+                // a reorg or the same head again. only check the head
+                _ => head_block_num,
+            };
 
-                // save the key and headers for when we log the response
-                kafka_stuff = Some((
-                    kafka_topic.clone(),
-                    kafka_key.clone(),
-                    kafka_headers.clone(),
-                ));
+            last_checked = Some(head_block_num);
 
-                let f = async move {
-                    let produce_future = kafka_producer.send(
-                        FutureRecord::to(&kafka_topic)
-                            .key(&kafka_key)
-                            .payload(&request_bytes)
-                            .headers(kafka_headers),
-                        Duration::from_secs(0),
-                    );
+            // blocks that aren't cached (or came from a subscription without their transactions) might include it
+            let mut maybe_included = false;
+            let mut num = first_num;
 
-                    if let Err((err, _)) = produce_future.await {
-                        error!("produce kafka request log: {}", err);
-                        // TODO: re-queue the msg?
+            while num <= head_block_num {
+                match self.balanced_rpcs.cached_block_by_number(&num) {
+                    Some(block) if !block.block.transactions.is_empty() => {
+                        if block.block.transactions.contains(&tx_hash) {
+                            maybe_included = true;
+                            break;
+                        }
                     }
-                };
+                    _ => {
+                        maybe_included = true;
+                        break;
+                    }
+                }
 
-                tokio::spawn(f);
+                num += U64::one();
             }
-        }
+
+            if !maybe_included {
+                trace!(
+                    "{:?} not in blocks {}..={}",
+                    tx_hash,
+                    first_num,
+                    head_block_num
+                );
+                continue;
+            }
+
+            response = self
+                .balanced_rpcs
+                .try_proxy_connection(
+                    authorization,
+                    receipt_request.clone(),
+                    request_metadata,
+                    None,
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(response)
+    }
+
+    /// The response cache key for a request. None if the response should never be cached
+    /// This might modify the request params
+    async fn response_cache_key(
+        &self,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        params: &mut Option<serde_json::Value>,
+        head_block_num: U64,
+        request_metadata: Option<&RequestMetadata>,
+    ) -> Web3ProxyResult<Option<ResponseCacheKey>> {
+        // we do this check before checking caches because it might modify the request params
+        // TODO: add a stat for archive vs full since they should probably cost different
+        // TODO: this cache key can be rather large. is that okay?
+        let cache_key: Option<ResponseCacheKey> = match block_needed(
+            authorization,
+            method,
+            params.as_mut(),
+            head_block_num,
+            &self.balanced_rpcs,
+        )
+        .await?
+        {
+            BlockNeeded::CacheSuccessForever => Some(ResponseCacheKey {
+                from_block: None,
+                to_block: None,
+                method: method.to_string(),
+                params: params.clone(),
+                cache_errors: false,
+            }),
+            BlockNeeded::CacheNever => None,
+            BlockNeeded::Cache {
+                block_num,
+                data_kind,
+                cache_errors,
+            } => {
+                let (request_block_hash, block_depth) = self
+                    .balanced_rpcs
+                    .block_hash(authorization, &block_num)
+                    .await?;
+
+                // old receipts and blocks are kept by full nodes too
+                if data_kind.needs_archive() && block_depth < self.archive_depth() {
+                    if let Some(request_metadata) = request_metadata {
+                        request_metadata
+                            .archive_request
+                            .store(true, atomic::Ordering::Relaxed);
+                    }
+                }
+
+                let request_block = self
+                    .balanced_rpcs
+                    .block(authorization, &request_block_hash, None)
+                    .await?;
+
+                Some(ResponseCacheKey {
+                    from_block: Some(request_block),
+                    to_block: None,
+                    method: method.to_string(),
+                    // TODO: hash here?
+                    params: params.clone(),
+                    cache_errors,
+                })
+            }
+            BlockNeeded::CacheRange {
+                from_block_num,
+                to_block_num,
+                data_kind,
+                cache_errors,
+            } => {
+                let (from_block_hash, block_depth) = self
+                    .balanced_rpcs
+                    .block_hash(authorization, &from_block_num)
+                    .await?;
+
+                // old receipts and blocks are kept by full nodes too
+                if data_kind.needs_archive() && block_depth < self.archive_depth() {
+                    if let Some(request_metadata) = request_metadata {
+                        request_metadata
+                            .archive_request
+                            .store(true, atomic::Ordering::Relaxed);
+                    }
+                }
+
+                let from_block = self
+                    .balanced_rpcs
+                    .block(authorization, &from_block_hash, None)
+                    .await?;
+
+                let (to_block_hash, _) = self
+                    .balanced_rpcs
+                    .block_hash(authorization, &to_block_num)
+                    .await?;
+
+                let to_block = self
+                    .balanced_rpcs
+                    .block(authorization, &to_block_hash, None)
+                    .await?;
+
+                Some(ResponseCacheKey {
+                    from_block: Some(from_block),
+                    to_block: Some(to_block),
+                    method: method.to_string(),
+                    // TODO: hash here?
+                    params: params.clone(),
+                    cache_errors,
+                })
+            }
+        };
+
+        Ok(cache_key)
+    }
+
+    /// Refresh the hottest head-block requests for a new head. Stops when the budget runs out
+    async fn prefetch_hot_requests(self: &Arc<Self>, head_block_num: U64) {
+        let hot_requests = self.hot_requests.on_head_block();
+
+        if hot_requests.is_empty() {
+            return;
+        }
+
+        let authorization = match Authorization::internal(self.db_conn()) {
+            Ok(x) => Arc::new(x),
+            Err(err) => {
+                warn!("unable to prefetch. err={:?}", err);
+                return;
+            }
+        };
+
+        let prefetches = futures::stream::iter(hot_requests)
+            .map(|(method, params)| {
+                let authorization = &authorization;
+
+                async move {
+                    if let Err(err) = self
+                        .prefetch(authorization, &method, params, head_block_num)
+                        .await
+                    {
+                        trace!("prefetching {} failed. err={:?}", method, err);
+                    }
+                }
+            })
+            .buffer_unordered(self.hot_requests.max_concurrent())
+            .collect::<Vec<_>>();
+
+        if timeout(self.hot_requests.budget(), prefetches)
+            .await
+            .is_err()
+        {
+            trace!("prefetch budget for block {} ran out", head_block_num);
+        }
+    }
+
+    /// Put a response for a new head block in the response cache before anyone asks for it
+    async fn prefetch(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        mut params: Option<serde_json::Value>,
+        head_block_num: U64,
+    ) -> Web3ProxyResult<()> {
+        let cache_key = match self
+            .response_cache_key(authorization, method, &mut params, head_block_num, None)
+            .await?
+        {
+            Some(x) if x.is_head_scoped(head_block_num) => x,
+            _ => return Ok(()),
+        };
+
+        let key_hash = HotRequests::key_hash(&cache_key);
+        let key_weight = cache_key.weight();
+        let max_item_bytes = self.config.response_cache_max_item_bytes(method);
+
+        let request = JsonRpcRequest::new(JsonRpcId::Number(1), method.to_string(), params)?;
+
+        // if a user already asked for this, don't count it as a prefetch
+        let fetched = atomic::AtomicBool::new(false);
+        let fetched_ref = &fetched;
+
+        let response = self
+            .response_cache
+            .try_get_with(cache_key, async move {
+                fetched_ref.store(true, atomic::Ordering::Relaxed);
+
+                let mut response = self
+                    .balanced_rpcs
+                    .try_proxy_connection(authorization, request, None, Some(&head_block_num), None)
+                    .await?;
+
+                response.id = Default::default();
+
+                if response.num_bytes() as u64 > max_item_bytes {
+                    return Err(Web3ProxyError::JsonRpcForwardedError(response));
+                }
+
+                Ok::<_, Web3ProxyError>(response)
+            })
+            .await?;
+
+        if fetched.load(atomic::Ordering::Relaxed) {
+            self.hot_requests.prefetched(key_hash);
+            self.response_cache_stats.prefetched(method);
+            self.response_cache_stats
+                .inserted(method, response_cache_weight(key_weight, &response));
+        }
+
+        Ok(())
+    }
+
+    /// Send a request that can't be cached.
+    /// Concurrent identical requests share one backend request. The others are counted like cache hits
+    async fn try_proxy_deduplicated(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+        head_block_num: Option<U64>,
+    ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
+        if !is_deduplicable(&request.method) {
+            return self
+                .try_proxy_connection_multicall(
+                    authorization,
+                    request,
+                    request_metadata,
+                    None,
+                    None,
+                )
+                .await;
+        }
+
+        let key = SingleFlightKey {
+            head_block_num,
+            method: request.method.clone(),
+            params: request.params.as_ref().map(|x| x.to_string()),
+        };
+
+        let app = self.clone();
+        let authorization = authorization.clone();
+        let request_metadata = request_metadata.clone();
+
+        let (response, leader) = self
+            .in_flight_requests
+            .run(key, async move {
+                let mut response = app
+                    .try_proxy_connection_multicall(
+                        &authorization,
+                        request,
+                        &request_metadata,
+                        None,
+                        None,
+                    )
+                    .await?;
+
+                // the waiters have different ids. the callers set their own
+                response.id = Default::default();
+
+                Ok(response)
+            })
+            .await;
+
+        if !leader {
+            trace!("shared an in-flight request");
+        }
+
+        Ok(response?)
+    }
+
+    /// Send an eth_call inside of a Multicall3 batch if the key opted in and the call can be batched.
+    /// Everything else is sent on its own
+    async fn try_proxy_connection_multicall(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
+        let call = if authorization.checks.multicall {
+            self.multicall.call_for(&request)
+        } else {
+            None
+        };
+
+        if let Some(call) = call {
+            if let Some((response, rpcs)) = self
+                .multicall
+                .call(&self.balanced_rpcs, authorization, call, min_block_needed)
+                .await
+            {
+                request_metadata.backend_requests.lock().extend(rpcs);
+
+                return Ok(response);
+            }
+        }
+
+        self.balanced_rpcs
+            .try_proxy_connection(
+                authorization,
+                request,
+                Some(request_metadata),
+                min_block_needed,
+                max_block_needed,
+            )
+            .await
+    }
+
+    /// simulate a signed transaction with eth_call. reverts are always saved to the revert_log by `RequestErrorHandler::Save`
+    async fn check_revert(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+    ) -> Web3ProxyResult<()> {
+        let tx = decode_raw_transaction(request.params.as_ref()).ok_or_else(|| {
+            Web3ProxyError::BadRequest("unable to decode the signed transaction".to_string())
+        })?;
+
+        let from = tx
+            .recover_from()
+            .map_err(|_| Web3ProxyError::BadRequest("invalid transaction signature".to_string()))?;
+
+        // the fees are left out so that a base fee change can't make the simulation fail
+        let call = json!({
+            "from": from,
+            "to": tx.to,
+            "gas": tx.gas,
+            "value": tx.value,
+            "data": tx.input,
+        });
+
+        let call_request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: request.id.clone(),
+            method: "eth_call".to_string(),
+            params: Some(json!([call, self.config.revert_protection.block_tag])),
+            web3proxy: Default::default(),
+        };
+
+        // the request handle saves reverts. a transaction that we refused to send is always worth saving
+        let mut simulation_authorization = authorization.as_ref().clone();
+        simulation_authorization.checks.log_revert_chance = 1.0;
+        let simulation_authorization = Arc::new(simulation_authorization);
+
+        // no request_metadata. the simulation isn't part of the user's stats
+        let response = self
+            .balanced_rpcs
+            .try_proxy_connection(&simulation_authorization, call_request, None, None, None)
+            .await?;
+
+        if let Some(error) = response.error {
+            if error.is_revert() {
+                return Err(Web3ProxyError::TransactionWouldRevert(error));
+            }
+
+            // other errors (like insufficient funds) are left for the private rpcs to report
+            debug!("unable to simulate transaction: {:?}", error);
+        }
+
+        Ok(())
+    }
+
+    /// try to send transactions to the best available rpcs with private mempools
+    /// if no private rpcs are configured, then some public rpcs are used instead
+    async fn try_send_protected(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: Arc<RequestMetadata>,
+        num_public_rpcs: Option<usize>,
+    ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
+        if self.balanced_rpcs.chain_split.current().is_some() {
+            match self.balanced_rpcs.chain_split.tx_policy() {
+                ChainSplitTxPolicy::Allow => {}
+                ChainSplitTxPolicy::Pause => return Err(Web3ProxyError::ChainSplit),
+                ChainSplitTxPolicy::Consensus => {
+                    // the consensus rpcs are all on the same side of the split
+                    return self
+                        .balanced_rpcs
+                        .try_send_all_synced_connections(
+                            authorization,
+                            request,
+                            Some(request_metadata),
+                            None,
+                            None,
+                            Level::Trace,
+                            num_public_rpcs,
+                            true,
+                            ResponseAggregate::MostCommon,
+                        )
+                        .await;
+                }
+            }
+        }
+
+        if let Some(protected_rpcs) = self.private_rpcs.as_ref() {
+            if !protected_rpcs.is_empty() {
+                let protected_response = protected_rpcs
+                    .try_send_all_synced_connections(
+                        authorization,
+                        request,
+                        Some(request_metadata),
+                        None,
+                        None,
+                        Level::Trace,
+                        None,
+                        true,
+                        ResponseAggregate::MostCommon,
+                    )
+                    .await?;
+
+                return Ok(protected_response);
+            }
+        }
+
+        // no private rpcs to send to. send to a few public rpcs
+        // try_send_all_upstream_servers puts the request id into the response. no need to do that ourselves here.
+        self.balanced_rpcs
+            .try_send_all_synced_connections(
+                authorization,
+                request,
+                Some(request_metadata),
+                None,
+                None,
+                Level::Trace,
+                num_public_rpcs,
+                true,
+                ResponseAggregate::MostCommon,
+            )
+            .await
+    }
+
+    // TODO: more robust stats and kafka logic! if we use the try operator, they aren't saved!
+    // TODO: move this to another module
+    async fn proxy_cached_request(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        mut request: JsonRpcRequest,
+        head_block_num: Option<U64>,
+    ) -> Web3ProxyResult<(JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>)> {
+        // TODO: move this code to another module so that its easy to turn this trace logging on in dev
+        trace!("Received request: {:?}", request);
+
+        let request_metadata = Arc::new(RequestMetadata::new(request.num_bytes()));
+
+        let mut kafka_stuff = None;
+
+        if matches!(authorization.checks.proxy_mode, ProxyMode::Debug) {
+            if let Some(kafka_producer) = self.kafka_producer.clone() {
+                let kafka_topic = "proxy_cached_request".to_string();
+
+                let rpc_secret_key_id = authorization
+                    .checks
+                    .rpc_secret_key_id
+                    .map(|x| x.get())
+                    .unwrap_or_default();
+
+                let kafka_key = rmp_serde::to_vec(&rpc_secret_key_id)?;
+
+                let request_bytes = rmp_serde::to_vec(&request)?;
+
+                let request_hash = Some(keccak256(&request_bytes));
+
+                let chain_id = self.config.chain_id;
+
+                // another item is added with the response, so initial_capacity is +1 what is needed here
+                let kafka_headers = OwnedHeaders::new_with_capacity(4)
+                    .insert(Header {
+                        key: "request_hash",
+                        value: request_hash.as_ref(),
+                    })
+                    .insert(Header {
+                        key: "head_block_num",
+                        value: head_block_num.map(|x| x.to_string()).as_ref(),
+                    })
+                    .insert(Header {
+                        key: "chain_id",
+                        value: Some(&chain_id.to_le_bytes()),
+                    });
+
+                // save the key and headers for when we log the response
+                kafka_stuff = Some((
+                    kafka_topic.clone(),
+                    kafka_key.clone(),
+                    kafka_headers.clone(),
+                ));
+
+                let f = async move {
+                    let produce_future = kafka_producer.send(
+                        FutureRecord::to(&kafka_topic)
+                            .key(&kafka_key)
+                            .payload(&request_bytes)
+                            .headers(kafka_headers),
+                        Duration::from_secs(0),
+                    );
+
+                    if let Err((err, _)) = produce_future.await {
+                        error!("produce kafka request log: {}", err);
+                        // TODO: re-queue the msg?
+                    }
+                };
+
+                tokio::spawn(f);
+            }
+        }
 
         // save the id so we can attach it to the response
         // TODO: instead of cloning, take the id out?
         let request_id = request.id.clone();
         let request_method = request.method.clone();
 
+        let request_options = mem::take(&mut request.web3proxy);
+        let bypass_cache = request_options.cache == CacheControl::Bypass;
+
+        // the block that the response cache used. included in the response metadata
+        let mut response_block_num = None;
+
+        // only responses from the response cache or the call cache are hits
+        let mut cache_status = if bypass_cache {
+            CacheStatus::Bypass
+        } else {
+            CacheStatus::Miss
+        };
+
+        let affinity_key = if self.backend_affinity.enabled() {
+            self.backend_affinity
+                .key(authorization, &request.method, request.params.as_ref())
+        } else {
+            None
+        };
+
+        if let Some(affinity_key) = affinity_key.as_ref() {
+            *request_metadata.preferred_rpc.lock() = self.backend_affinity.preferred(affinity_key);
+        }
+
+        // read-your-writes for transactions that this rpc key recently sent
+        let rpc_key_id = authorization
+            .checks
+            .rpc_secret_key_id
+            .filter(|_| self.recent_txs.enabled());
+
+        // some users get the highest pending nonce of all the synced rpcs
+        let aggregate_pending_nonce_address = if authorization.checks.aggregate_pending_nonce
+            && request.method == "eth_getTransactionCount"
+        {
+            request.params.as_ref().and_then(|x| {
+                if x.get(1)?.as_str()? == "pending" {
+                    x.get(0)?.as_str()?.parse::<Address>().ok()
+                } else {
+                    None
+                }
+            })
+        } else {
+            None
+        };
+
+        let min_pending_nonce = if request.method == "eth_getTransactionCount" {
+            rpc_key_id.and_then(|x| {
+                self.recent_txs
+                    .min_pending_nonce(x, request.params.as_ref())
+            })
+        } else {
+            None
+        };
+
+        // eth_call results from older blocks can be reused if nothing they depend on was touched since
+        let call_cache_key = if request.method == "eth_call" && !bypass_cache {
+            self.call_cache.key(request.params.as_ref())
+        } else {
+            None
+        };
+
+        let cached_call = call_cache_key.as_ref().and_then(|x| {
+            let head_block_num = head_block_num.or(self.balanced_rpcs.head_block_num())?;
+
+            self.call_cache.get(x, head_block_num)
+        });
+
         // TODO: if eth_chainId or net_version, serve those without querying the backend
         // TODO: don't clone?
-        let response: JsonRpcForwardedResponse = match request_method.as_ref() {
+        let mut response: JsonRpcForwardedResponse = match request_method.as_ref() {
             // lots of commands are blocked
             method @ ("db_getHex"
             | "db_getString"
@@ -1486,16 +2521,16 @@ impl Web3ProxyApp {
             }
             "eth_estimateGas" => {
                 let mut response = self
-                    .balanced_rpcs
-                    .try_proxy_connection(
+                    .try_proxy_deduplicated(
                         authorization,
                         request,
-                        Some(&request_metadata),
-                        None,
-                        None,
+                        &request_metadata,
+                        self.balanced_rpcs.head_block_num(),
                     )
                     .await?;
 
+                response.id = request_id.clone();
+
                 let mut gas_estimate: U256 = if let Some(gas_estimate) = response.result.take() {
                     serde_json::from_str(gas_estimate.get())
                         .or(Err(Web3ProxyError::GasEstimateNotU256))?
@@ -1523,7 +2558,64 @@ impl Web3ProxyApp {
 
                 JsonRpcForwardedResponse::from_value(json!(gas_estimate), request_id)
             }
+            "eth_call" if cached_call.is_some() => {
+                let mut response = cached_call.expect("checked by the match guard");
+
+                response.id = request_id;
+
+                cache_status = CacheStatus::Hit;
+
+                response
+            }
+            "eth_getTransactionCount" if aggregate_pending_nonce_address.is_some() => {
+                let address = aggregate_pending_nonce_address.expect("checked by the match guard");
+
+                let request_metadata = request_metadata.clone();
+
+                let mut response = self
+                    .pending_nonce_cache
+                    .try_get_with(address, async move {
+                        // TODO: cache errors?
+                        let mut response = self
+                            .balanced_rpcs
+                            .try_send_all_synced_connections(
+                                authorization,
+                                &request,
+                                Some(request_metadata),
+                                None,
+                                None,
+                                Level::Trace,
+                                None,
+                                false,
+                                ResponseAggregate::Max,
+                            )
+                            .await?;
+
+                        // discard their id by replacing it with an empty
+                        response.id = Default::default();
+
+                        Ok::<_, Web3ProxyError>(response)
+                    })
+                    .await?;
+
+                response.id = request_id;
+
+                response
+            }
             "eth_getTransactionReceipt" | "eth_getTransactionByHash" => {
+                // transactions that this rpc key just sent might not be on every backend yet
+                let sent_tx =
+                    rpc_key_id.and_then(|x| self.recent_txs.sent_tx(x, request.params.as_ref()));
+
+                if let Some(sent_tx) = sent_tx.as_ref() {
+                    let mut preferred_rpc = request_metadata.preferred_rpc.lock();
+
+                    if preferred_rpc.is_none() {
+                        // TODO: prefer all of the rpcs that accepted the transaction, not just the first
+                        *preferred_rpc = sent_tx.rpcs.first().cloned();
+                    }
+                }
+
                 // try to get the transaction without specifying a min_block_height
                 let mut response = self
                     .balanced_rpcs
@@ -1536,8 +2628,44 @@ impl Web3ProxyApp {
                     )
                     .await?;
 
-                // if we got "null", it is probably because the tx is old. retry on nodes with old block data
-                if let Some(ref result) = response.result {
+                if let Some(sent_tx) = sent_tx {
+                    // we just sent this transaction. retry until the block that should include it
+                    let deadline = Instant::now() + MAX_RECEIPT_WAIT;
+
+                    let mut head_receiver = self.watch_consensus_head_receiver.clone();
+                    head_receiver.borrow_and_update();
+
+                    while response
+                        .result
+                        .as_ref()
+                        .map(|x| x.get() == "null")
+                        .unwrap_or(false)
+                    {
+                        if self.balanced_rpcs.head_block_num() > sent_tx.expected_block() {
+                            // the transaction is probably still pending
+                            break;
+                        }
+
+                        if !matches!(
+                            timeout_at(deadline, head_receiver.changed()).await,
+                            Ok(Ok(_))
+                        ) {
+                            break;
+                        }
+
+                        response = self
+                            .balanced_rpcs
+                            .try_proxy_connection(
+                                authorization,
+                                request.clone(),
+                                Some(&request_metadata),
+                                None,
+                                None,
+                            )
+                            .await?;
+                    }
+                } else if let Some(ref result) = response.result {
+                    // if we got "null", it is probably because the tx is old. retry on nodes with old block data
                     if result.get() == "null" {
                         request_metadata
                             .archive_request
@@ -1577,6 +2705,10 @@ impl Web3ProxyApp {
                     ProxyMode::Versus => None,
                 };
 
+                if authorization.checks.revert_protection {
+                    self.check_revert(authorization, &request).await?;
+                }
+
                 let mut response = self
                     .try_send_protected(
                         authorization,
@@ -1664,6 +2796,68 @@ impl Web3ProxyApp {
                     }
                 }
 
+                if authorization.checks.user_id != 0 {
+                    // let the user see what the private rpcs said
+                    let relays: Vec<_> = {
+                        let private_rpcs = self.private_rpcs.as_ref().map(|x| x.by_name.read());
+
+                        request_metadata
+                            .relay_responses
+                            .lock()
+                            .iter()
+                            .filter(|x| {
+                                private_rpcs
+                                    .as_ref()
+                                    .map(|private_rpcs| private_rpcs.contains_key(&x.rpc))
+                                    .unwrap_or(false)
+                            })
+                            .cloned()
+                            .collect()
+                    };
+
+                    if !relays.is_empty() {
+                        if let Some(tx) = decode_raw_transaction(request.params.as_ref()) {
+                            if let Ok(sender) = tx.recover_from() {
+                                self.private_tx_tracker
+                                    .record_send(
+                                        authorization.checks.user_id,
+                                        &tx,
+                                        sender,
+                                        relays,
+                                        self.balanced_rpcs.head_block_num(),
+                                    )
+                                    .await;
+                            }
+                        }
+                    }
+                }
+
+                if response.error.is_none() {
+                    if let Some(rpc_key_id) = rpc_key_id {
+                        // only the balanced rpcs serve receipts
+                        let rpcs = {
+                            let by_name = self.balanced_rpcs.by_name.read();
+
+                            request_metadata
+                                .backend_requests
+                                .lock()
+                                .iter()
+                                .filter(|x| by_name.contains_key(&x.name))
+                                .map(|x| x.name.clone())
+                                .collect()
+                        };
+
+                        self.recent_txs
+                            .record_send(
+                                rpc_key_id,
+                                request.params.as_ref(),
+                                rpcs,
+                                self.balanced_rpcs.head_block_num(),
+                            )
+                            .await;
+                    }
+                }
+
                 response
             }
             "eth_syncing" => {
@@ -1689,6 +2883,54 @@ impl Web3ProxyApp {
                 json!(U64::from(self.balanced_rpcs.num_synced_rpcs())),
                 request_id,
             ),
+            "web3proxy_waitForTransactionReceipt" => {
+                let params = request.params.as_ref();
+
+                let tx_hash = params
+                    .and_then(|x| x.get(0))
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| x.parse::<TxHash>().ok())
+                    .ok_or_else(|| {
+                        Web3ProxyError::BadRequest(
+                            "web3proxy_waitForTransactionReceipt needs a transaction hash"
+                                .to_string(),
+                        )
+                    })?;
+
+                // the timeout is in seconds
+                let max_wait = params
+                    .and_then(|x| x.get(1))
+                    .and_then(|x| x.as_u64())
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_RECEIPT_LONG_POLL);
+
+                self.wait_for_transaction_receipt(
+                    authorization,
+                    request.id.clone(),
+                    tx_hash,
+                    max_wait,
+                    Some(&request_metadata),
+                )
+                .await?
+            }
+            "web3proxy_getPrivateTxStatus" => {
+                let tx_hash = request
+                    .params
+                    .as_ref()
+                    .and_then(|x| x.get(0))
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| x.parse::<TxHash>().ok())
+                    .ok_or_else(|| {
+                        Web3ProxyError::BadRequest(
+                            "web3proxy_getPrivateTxStatus needs a transaction hash".to_string(),
+                        )
+                    })?;
+
+                // anonymous users have a user_id of 0 and never have any statuses
+                let status = self.private_tx_status(authorization.checks.user_id, &tx_hash);
+
+                JsonRpcForwardedResponse::from_value(json!(status), request_id)
+            }
             "web3_clientVersion" => JsonRpcForwardedResponse::from_value(
                 serde_json::Value::String(APP_USER_AGENT.to_string()),
                 request_id,
@@ -1756,144 +2998,206 @@ impl Web3ProxyApp {
                     .or(self.balanced_rpcs.head_block_num())
                     .ok_or(Web3ProxyError::NoServersSynced)?;
 
-                // we do this check before checking caches because it might modify the request params
-                // TODO: add a stat for archive vs full since they should probably cost different
-                // TODO: this cache key can be rather large. is that okay?
-                let cache_key: Option<ResponseCacheKey> = match block_needed(
-                    authorization,
-                    method,
-                    request.params.as_mut(),
-                    head_block_num,
-                    &self.balanced_rpcs,
-                )
-                .await?
-                {
-                    BlockNeeded::CacheSuccessForever => Some(ResponseCacheKey {
-                        from_block: None,
-                        to_block: None,
-                        method: method.to_string(),
-                        params: request.params.clone(),
-                        cache_errors: false,
-                    }),
-                    BlockNeeded::CacheNever => None,
-                    BlockNeeded::Cache {
-                        block_num,
-                        cache_errors,
-                    } => {
-                        let (request_block_hash, block_depth) = self
-                            .balanced_rpcs
-                            .block_hash(authorization, &block_num)
-                            .await?;
-
-                        if block_depth < self.config.archive_depth {
-                            request_metadata
-                                .archive_request
-                                .store(true, atomic::Ordering::Relaxed);
-                        }
+                // hot requests are saved before block_needed replaces tags like "latest"
+                let hot_params = self.hot_requests.enabled().then(|| request.params.clone());
 
-                        let request_block = self
-                            .balanced_rpcs
-                            .block(authorization, &request_block_hash, None)
-                            .await?;
+                // we do this check before checking caches because it might modify the request params
+                let cache_key = self
+                    .response_cache_key(
+                        authorization,
+                        method,
+                        &mut request.params,
+                        head_block_num,
+                        Some(&request_metadata),
+                    )
+                    .await?;
+                trace!("cache_key: {:#?}", cache_key);
 
-                        Some(ResponseCacheKey {
-                            from_block: Some(request_block),
-                            to_block: None,
-                            method: method.to_string(),
-                            // TODO: hash here?
-                            params: request.params.clone(),
-                            cache_errors,
-                        })
-                    }
-                    BlockNeeded::CacheRange {
-                        from_block_num,
-                        to_block_num,
-                        cache_errors,
-                    } => {
-                        let (from_block_hash, block_depth) = self
-                            .balanced_rpcs
-                            .block_hash(authorization, &from_block_num)
-                            .await?;
+                // the block that the call cache saves this response for
+                let call_block = cache_key
+                    .as_ref()
+                    .filter(|x| x.is_head_scoped(head_block_num))
+                    .and_then(|x| x.from_block.clone());
 
-                        if block_depth < self.config.archive_depth {
-                            request_metadata
-                                .archive_request
-                                .store(true, atomic::Ordering::Relaxed);
-                        }
+                let mut key_hash = None;
 
-                        let from_block = self
-                            .balanced_rpcs
-                            .block(authorization, &from_block_hash, None)
-                            .await?;
+                if let (Some(hot_params), Some(cache_key)) = (hot_params, cache_key.as_ref()) {
+                    if cache_key.is_head_scoped(head_block_num) {
+                        self.hot_requests.record(method, hot_params);
 
-                        let (to_block_hash, _) = self
-                            .balanced_rpcs
-                            .block_hash(authorization, &to_block_num)
-                            .await?;
+                        key_hash = Some(HotRequests::key_hash(cache_key));
+                    }
+                }
 
-                        let to_block = self
-                            .balanced_rpcs
-                            .block(authorization, &to_block_hash, None)
-                            .await?;
+                let from_block_num = cache_key
+                    .as_ref()
+                    .and_then(|x| x.from_block.as_ref())
+                    .map(|x| *x.number());
+                let to_block_num = cache_key
+                    .as_ref()
+                    .and_then(|x| x.to_block.as_ref())
+                    .map(|x| *x.number());
 
-                        Some(ResponseCacheKey {
-                            from_block: Some(from_block),
-                            to_block: Some(to_block),
-                            method: method.to_string(),
-                            // TODO: hash here?
-                            params: request.params.clone(),
-                            cache_errors,
-                        })
-                    }
-                };
-                trace!("cache_key: {:#?}", cache_key);
+                response_block_num = to_block_num.or(from_block_num);
 
                 let mut response = {
                     let request_metadata = request_metadata.clone();
 
                     let authorization = authorization.clone();
 
-                    if let Some(cache_key) = cache_key {
-                        let from_block_num = cache_key.from_block.as_ref().map(|x| *x.number());
-                        let to_block_num = cache_key.to_block.as_ref().map(|x| *x.number());
+                    if let Some(cache_key) = cache_key.filter(|_| !bypass_cache) {
+                        let key_weight = cache_key.weight();
+                        let max_item_bytes = self.config.response_cache_max_item_bytes(method);
+
+                        // only responses that will never change go to disk
+                        let disk_cache = self
+                            .disk_cache
+                            .as_ref()
+                            .filter(|x| {
+                                cache_key.may_be_immutable(head_block_num, x.min_block_depth())
+                            })
+                            .map(|x| (x, cache_key.disk_key(self.config.chain_id)));
+
+                        // only the request that fetches the response counts as a miss
+                        let miss = atomic::AtomicBool::new(false);
+                        let miss_ref = &miss;
 
-                        self.response_cache
+                        // a response from disk counts as a hit
+                        let disk_hit = atomic::AtomicBool::new(false);
+                        let disk_hit_ref = &disk_hit;
+
+                        let response = self
+                            .response_cache
                             .try_get_with(cache_key, async move {
-                                // TODO: put the hash here instead of the block number? its in the request already.
-                                let mut response = self
-                                    .balanced_rpcs
-                                    .try_proxy_connection(
-                                        &authorization,
-                                        request,
-                                        Some(&request_metadata),
-                                        from_block_num.as_ref(),
-                                        to_block_num.as_ref(),
-                                    )
-                                    .await?;
+                                miss_ref.store(true, atomic::Ordering::Relaxed);
+
+                                let disk_response = match disk_cache {
+                                    Some((disk_cache, disk_key)) => disk_cache.get(disk_key).await,
+                                    None => None,
+                                };
+
+                                let response = if let Some(response) = disk_response {
+                                    disk_hit_ref.store(true, atomic::Ordering::Relaxed);
+
+                                    response
+                                } else {
+                                    // TODO: put the hash here instead of the block number? its in the request already.
+                                    let mut response = self
+                                        .try_proxy_connection_multicall(
+                                            &authorization,
+                                            request,
+                                            &request_metadata,
+                                            from_block_num.as_ref(),
+                                            to_block_num.as_ref(),
+                                        )
+                                        .await?;
 
-                                // discard their id by replacing it with an empty
-                                response.id = Default::default();
+                                    // discard their id by replacing it with an empty
+                                    response.id = Default::default();
+
+                                    // the disk cache has its own max_item_bytes. large eth_getLogs are why it exists
+                                    if let Some((disk_cache, disk_key)) = disk_cache {
+                                        if is_immutable(
+                                            response_block_num,
+                                            head_block_num,
+                                            disk_cache.min_block_depth(),
+                                            &response,
+                                        ) {
+                                            disk_cache.insert(disk_key, &response);
+                                        }
+                                    }
+
+                                    response
+                                };
 
                                 // TODO: only cache the inner response
                                 // TODO: how are we going to stream this?
-                                // TODO: check response size. if its very large, return it in a custom Error type that bypasses caching? or will moka do that for us?
+                                if response.num_bytes() as u64 > max_item_bytes {
+                                    // too large to cache. the error type skips the cache but still returns the response
+                                    return Err(Web3ProxyError::JsonRpcForwardedError(response));
+                                }
+
                                 Ok::<_, Web3ProxyError>(response)
                             })
-                            // TODO: add context (error while caching and forwarding response {})
-                            .await?
+                            .await;
+
+                        let miss = miss.load(atomic::Ordering::Relaxed);
+                        let disk_hit = disk_hit.load(atomic::Ordering::Relaxed);
+
+                        match response {
+                            Ok(response) => {
+                                if miss {
+                                    self.response_cache_stats.inserted(
+                                        method,
+                                        response_cache_weight(key_weight, &response),
+                                    );
+                                }
+
+                                if disk_hit {
+                                    self.response_cache_stats.hit(method);
+
+                                    cache_status = CacheStatus::Hit;
+                                } else if miss {
+                                    self.response_cache_stats.miss(method);
+                                } else {
+                                    self.response_cache_stats.hit(method);
+
+                                    cache_status = CacheStatus::Hit;
+
+                                    if key_hash
+                                        .map(|x| self.hot_requests.was_prefetched(x))
+                                        .unwrap_or(false)
+                                    {
+                                        self.response_cache_stats.prefetch_hit(method);
+                                    }
+                                }
+
+                                response
+                            }
+                            Err(err) => match err.as_ref() {
+                                Web3ProxyError::JsonRpcForwardedError(response) => {
+                                    if disk_hit {
+                                        self.response_cache_stats.hit(method);
+
+                                        cache_status = CacheStatus::Hit;
+                                    } else if miss {
+                                        self.response_cache_stats.miss(method);
+                                    }
+                                    self.response_cache_stats.bypass(method);
+
+                                    response.clone()
+                                }
+                                // TODO: add context (error while caching and forwarding response {})
+                                _ => return Err(err.into()),
+                            },
+                        }
+                    } else if bypass_cache {
+                        // fresh responses are not saved. the next request might not want to bypass the cache
+                        self.try_proxy_connection_multicall(
+                            &authorization,
+                            request,
+                            &request_metadata,
+                            from_block_num.as_ref(),
+                            to_block_num.as_ref(),
+                        )
+                        .await?
                     } else {
-                        self.balanced_rpcs
-                            .try_proxy_connection(
-                                &authorization,
-                                request,
-                                Some(&request_metadata),
-                                None,
-                                None,
-                            )
-                            .await?
+                        self.try_proxy_deduplicated(
+                            &authorization,
+                            request,
+                            &request_metadata,
+                            Some(head_block_num),
+                        )
+                        .await?
                     }
                 };
 
+                if let (Some(call_cache_key), Some(call_block)) = (call_cache_key, call_block) {
+                    self.call_cache
+                        .insert(call_cache_key, &call_block, &response)
+                        .await;
+                }
+
                 // since this data came likely out of a cache, the id is not going to match
                 // replace the id with our request's id.
                 response.id = request_id;
@@ -1902,9 +3206,43 @@ impl Web3ProxyApp {
             }
         };
 
+        if let Some(min_pending_nonce) = min_pending_nonce {
+            // other backends might not have seen this key's latest transaction yet
+            let pending_nonce = response
+                .result
+                .as_ref()
+                .and_then(|x| serde_json::from_str::<U256>(x.get()).ok());
+
+            if let Some(pending_nonce) = pending_nonce {
+                if pending_nonce < min_pending_nonce {
+                    trace!("pending nonce {} < {}", pending_nonce, min_pending_nonce);
+
+                    response.result = Some(
+                        to_raw_value(&json!(min_pending_nonce))
+                            .expect("a nonce is always valid json"),
+                    );
+                }
+            }
+        }
+
         // save the rpcs so they can be included in a response header
         let rpcs = request_metadata.backend_requests.lock().clone();
 
+        if request_options.metadata {
+            response.web3proxy = Some(JsonRpcResponseMetadata {
+                cache: cache_status,
+                block: response_block_num,
+                backends: rpcs.iter().map(|x| x.name.clone()).collect(),
+            });
+        }
+
+        if let Some(affinity_key) = affinity_key {
+            if response.error.is_none() {
+                self.record_backend_affinity(affinity_key, &request_metadata, &rpcs)
+                    .await;
+            }
+        }
+
         // send stats used for accounting and graphs
         if let Some(stat_sender) = self.stat_sender.as_ref() {
             let response_stat = RpcQueryStats::new(
@@ -1914,10 +3252,15 @@ impl Web3ProxyApp {
                 response.num_bytes(),
             );
 
-            stat_sender
-                .send_async(response_stat.into())
-                .await
-                .map_err(Web3ProxyError::SendAppStatError)?;
+            if let Some(wire_bytes) = authorization.wire_bytes.as_ref() {
+                // sent once the http response is sent and its compressed size is known
+                wire_bytes.defer(response_stat);
+            } else {
+                stat_sender
+                    .send_async(response_stat.into())
+                    .await
+                    .map_err(Web3ProxyError::SendAppStatError)?;
+            }
         }
 
         // send debug info as a kafka log
@@ -0,0 +1,530 @@
+///! Combine concurrent eth_calls for the same block into one Multicall3 `aggregate3` call.
+///!
+///! Each caller gets its own result back, including revert data. If the batch fails, every caller sends its own request.
+///! A call that fails without any revert data might have run out of the batch's gas, so it is sent on its own too.
+use crate::config::MulticallConfig;
+use crate::frontend::authorization::{Authorization, AuthorizationType, RequestMetadata};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcId, JsonRpcRequest};
+use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::one::Web3Rpc;
+use ethers::abi::{self, ParamType, Token};
+use ethers::prelude::{Address, Bytes, U64};
+use hashbrown::HashMap;
+use log::trace;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::json;
+use std::mem;
+use std::num::NonZeroU64;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// `aggregate3((address,bool,bytes)[])`
+const AGGREGATE3_SELECTOR: [u8; 4] = [0x82, 0xad, 0x56, 0xcb];
+
+/// A response without an id and the rpcs that served it
+type BatchedResponse = (JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>);
+
+/// An eth_call that can go in a batch
+#[derive(Debug)]
+pub struct MulticallCall {
+    /// the block param after `block_needed`. calls are only batched with calls for the same block
+    block: String,
+    target: Address,
+    data: Bytes,
+}
+
+/// Calls are only batched with calls that would be sent the same way
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct BatchKey {
+    block: String,
+    min_block_needed: Option<U64>,
+    /// calls from the same key have the same checks (like the request timeout)
+    rpc_secret_key_id: Option<NonZeroU64>,
+    internal: bool,
+}
+
+impl BatchKey {
+    fn new(authorization: &Authorization, block: String, min_block_needed: Option<&U64>) -> Self {
+        Self {
+            block,
+            min_block_needed: min_block_needed.copied(),
+            rpc_secret_key_id: authorization.checks.rpc_secret_key_id,
+            internal: matches!(
+                authorization.authorization_type,
+                AuthorizationType::Internal
+            ),
+        }
+    }
+}
+
+struct PendingCall {
+    target: Address,
+    data: Bytes,
+    /// None if the batch failed
+    sender: oneshot::Sender<Option<BatchedResponse>>,
+}
+
+struct PendingBatch {
+    id: u64,
+    /// every call in the batch has a compatible authorization. the first one is used
+    authorization: Arc<Authorization>,
+    calls: Vec<PendingCall>,
+}
+
+/// The multicall counters in a format that serde_prometheus likes
+#[derive(Default, Serialize)]
+pub struct MulticallMetrics {
+    batches: u64,
+    /// calls sent inside of batches
+    batched_calls: u64,
+    /// batches that failed and were sent as individual calls
+    failed_batches: u64,
+    /// calls that failed inside of a batch without revert data and were sent on their own
+    resent_calls: u64,
+}
+
+pub struct MulticallBatcher {
+    config: MulticallConfig,
+    pending: Mutex<HashMap<BatchKey, PendingBatch>>,
+    next_batch_id: AtomicU64,
+    batches: AtomicU64,
+    batched_calls: AtomicU64,
+    failed_batches: AtomicU64,
+    resent_calls: AtomicU64,
+}
+
+impl Default for MulticallBatcher {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl MulticallBatcher {
+    pub fn new(config: MulticallConfig) -> Self {
+        Self {
+            config,
+            pending: Default::default(),
+            next_batch_id: 0.into(),
+            batches: 0.into(),
+            batched_calls: 0.into(),
+            failed_batches: 0.into(),
+            resent_calls: 0.into(),
+        }
+    }
+
+    /// The call if this request can be sent inside of a Multicall3 call.
+    /// Multicall3 is the sender of every call, so calls that set a `from` can't be batched.
+    /// A zero `from` is the same as no `from`
+    pub fn call_for(&self, request: &JsonRpcRequest) -> Option<MulticallCall> {
+        self.config.address?;
+
+        if request.method != "eth_call" {
+            return None;
+        }
+
+        let params = request.params.as_ref()?.as_array()?;
+
+        // state overrides apply to the whole batch
+        if params.len() != 2 {
+            return None;
+        }
+
+        let call = params[0].as_object()?;
+
+        if call
+            .keys()
+            .any(|x| !matches!(x.as_str(), "to" | "data" | "input" | "from"))
+        {
+            return None;
+        }
+
+        if let Some(from) = call.get("from") {
+            let from: Address = serde_json::from_value(from.clone()).ok()?;
+
+            if !from.is_zero() {
+                return None;
+            }
+        }
+
+        let target = serde_json::from_value(call.get("to")?.clone()).ok()?;
+
+        let data = match call.get("data").or_else(|| call.get("input")) {
+            Some(x) => x.as_str()?.parse().ok()?,
+            None => Bytes::default(),
+        };
+
+        Some(MulticallCall {
+            block: params[1].to_string(),
+            target,
+            data,
+        })
+    }
+
+    /// Wait for the batch that this call is in. None if the call should be sent on its own
+    pub async fn call(
+        self: &Arc<Self>,
+        rpcs: &Arc<Web3Rpcs>,
+        authorization: &Arc<Authorization>,
+        call: MulticallCall,
+        min_block_needed: Option<&U64>,
+    ) -> Option<BatchedResponse> {
+        let (sender, receiver) = oneshot::channel();
+
+        let key = BatchKey::new(authorization, call.block, min_block_needed);
+
+        let (new_batch_id, full_batch) = {
+            let mut pending = self.pending.lock();
+
+            let batch = pending.entry(key.clone()).or_insert_with(|| PendingBatch {
+                id: self.next_batch_id.fetch_add(1, atomic::Ordering::Relaxed),
+                authorization: authorization.clone(),
+                calls: vec![],
+            });
+
+            let new_batch_id = batch.calls.is_empty().then_some(batch.id);
+
+            batch.calls.push(PendingCall {
+                target: call.target,
+                data: call.data,
+                sender,
+            });
+
+            let full_batch = if batch.calls.len() >= self.config.max_calls {
+                pending.remove(&key)
+            } else {
+                None
+            };
+
+            (new_batch_id, full_batch)
+        };
+
+        // the batch runs in its own task so that callers going away don't leave the others waiting
+        if let Some(batch) = full_batch {
+            let x = self.clone();
+            let rpcs = rpcs.clone();
+
+            tokio::spawn(async move { x.send_batch(&rpcs, key, batch).await });
+        } else if let Some(id) = new_batch_id {
+            let x = self.clone();
+            let rpcs = rpcs.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(x.config.window_millis)).await;
+
+                let batch = {
+                    let mut pending = x.pending.lock();
+
+                    // the batch might have filled up and been sent already
+                    if pending.get(&key).map(|b| b.id == id).unwrap_or(false) {
+                        pending.remove(&key)
+                    } else {
+                        None
+                    }
+                };
+
+                if let Some(batch) = batch {
+                    x.send_batch(&rpcs, key, batch).await;
+                }
+            });
+        }
+
+        receiver.await.ok().flatten()
+    }
+
+    async fn send_batch(&self, rpcs: &Web3Rpcs, key: BatchKey, batch: PendingBatch) {
+        // a batch of one is no better than the call itself
+        if batch.calls.len() == 1 {
+            for call in batch.calls {
+                let _ = call.sender.send(None);
+            }
+            return;
+        }
+
+        let responses = self.try_send_batch(rpcs, &key, &batch).await;
+
+        self.batches.fetch_add(1, atomic::Ordering::Relaxed);
+
+        match responses {
+            Some((responses, rpcs)) => {
+                self.batched_calls
+                    .fetch_add(batch.calls.len() as u64, atomic::Ordering::Relaxed);
+
+                // every call in the batch was served by these rpcs
+                for (call, response) in batch.calls.into_iter().zip(responses) {
+                    if response.is_none() {
+                        self.resent_calls.fetch_add(1, atomic::Ordering::Relaxed);
+                    }
+
+                    let _ = call.sender.send(response.map(|x| (x, rpcs.clone())));
+                }
+            }
+            None => {
+                self.failed_batches.fetch_add(1, atomic::Ordering::Relaxed);
+
+                for call in batch.calls {
+                    let _ = call.sender.send(None);
+                }
+            }
+        }
+    }
+
+    async fn try_send_batch(
+        &self,
+        rpcs: &Web3Rpcs,
+        key: &BatchKey,
+        batch: &PendingBatch,
+    ) -> Option<(Vec<Option<JsonRpcForwardedResponse>>, Vec<Arc<Web3Rpc>>)> {
+        let data = encode_aggregate3(batch.calls.iter().map(|x| (x.target, x.data.clone())));
+
+        let block: serde_json::Value = serde_json::from_str(&key.block).ok()?;
+
+        let request = JsonRpcRequest::new(
+            JsonRpcId::Number(1),
+            "eth_call".to_string(),
+            Some(json!([{"to": self.config.address?, "data": data}, block])),
+        )
+        .ok()?;
+
+        let request_metadata = Arc::new(RequestMetadata::new(request.num_bytes()));
+
+        let response = rpcs
+            .try_proxy_connection(
+                &batch.authorization,
+                request,
+                Some(&request_metadata),
+                key.min_block_needed.as_ref(),
+                None,
+            )
+            .await;
+
+        let response = match response {
+            Ok(x) if x.error.is_none() => x,
+            Ok(x) => {
+                trace!("multicall batch failed. err={:?}", x.error);
+                return None;
+            }
+            Err(err) => {
+                trace!("multicall batch failed. err={:?}", err);
+                return None;
+            }
+        };
+
+        let output: Bytes = serde_json::from_str(response.result?.get()).ok()?;
+
+        let responses = decode_aggregate3(&output)?;
+
+        if responses.len() != batch.calls.len() {
+            return None;
+        }
+
+        let rpcs = mem::take(&mut *request_metadata.backend_requests.lock());
+
+        Some((responses, rpcs))
+    }
+
+    pub fn metrics(&self) -> MulticallMetrics {
+        MulticallMetrics {
+            batches: self.batches.load(atomic::Ordering::Relaxed),
+            batched_calls: self.batched_calls.load(atomic::Ordering::Relaxed),
+            failed_batches: self.failed_batches.load(atomic::Ordering::Relaxed),
+            resent_calls: self.resent_calls.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+/// Calldata for `aggregate3` with every call allowed to fail
+fn encode_aggregate3(calls: impl Iterator<Item = (Address, Bytes)>) -> Bytes {
+    let calls = calls
+        .map(|(target, data)| {
+            Token::Tuple(vec![
+                Token::Address(target),
+                Token::Bool(true),
+                Token::Bytes(data.to_vec()),
+            ])
+        })
+        .collect();
+
+    let encoded = abi::encode(&[Token::Array(calls)]);
+
+    [&AGGREGATE3_SELECTOR[..], &encoded].concat().into()
+}
+
+/// Split `aggregate3`'s output into a response for each call. Reverts look like they do from geth.
+/// A failure without revert data is None. It might only have failed because of the batch (like running out of gas)
+fn decode_aggregate3(output: &[u8]) -> Option<Vec<Option<JsonRpcForwardedResponse>>> {
+    let results = abi::decode(
+        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
+            ParamType::Bool,
+            ParamType::Bytes,
+        ])))],
+        output,
+    )
+    .ok()?
+    .pop()?
+    .into_array()?;
+
+    results
+        .into_iter()
+        .map(|x| {
+            let mut x = x.into_tuple()?.into_iter();
+
+            let success = x.next()?.into_bool()?;
+            let data = Bytes::from(x.next()?.into_bytes()?);
+
+            let response = if success {
+                JsonRpcForwardedResponse::from_value(json!(data), Default::default())
+            } else if data.is_empty() {
+                return Some(None);
+            } else {
+                let mut error = JsonRpcErrorData {
+                    code: 3,
+                    message: "execution reverted".to_string(),
+                    data: Some(json!(data)),
+                };
+
+                if let Some(reason) = error.revert_reason() {
+                    error.message = format!("execution reverted: {}", reason);
+                }
+
+                JsonRpcForwardedResponse {
+                    jsonrpc: "2.0".to_string(),
+                    id: Default::default(),
+                    result: None,
+                    error: Some(error),
+                    web3proxy: None,
+                }
+            };
+
+            Some(Some(response))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(params: serde_json::Value) -> JsonRpcRequest {
+        JsonRpcRequest::new(JsonRpcId::Number(1), "eth_call".to_string(), Some(params)).unwrap()
+    }
+
+    #[test]
+    fn test_call_for() {
+        let batcher = MulticallBatcher::new(MulticallConfig {
+            address: Some(Address::repeat_byte(0xca)),
+            ..Default::default()
+        });
+
+        let token = Address::repeat_byte(1);
+
+        let call = batcher
+            .call_for(&request(
+                json!([{"to": token, "data": "0x95d89b41"}, "0x10"]),
+            ))
+            .unwrap();
+
+        assert_eq!(call.target, token);
+        assert_eq!(call.block, "\"0x10\"");
+        assert_eq!(call.data.as_ref(), &[0x95, 0xd8, 0x9b, 0x41]);
+
+        // calls that depend on their sender can't be batched
+        assert!(batcher
+            .call_for(&request(
+                json!([{"from": token, "to": token, "data": "0x95d89b41"}, "0x10"])
+            ))
+            .is_none());
+
+        // a zero sender is the same as no sender
+        assert!(batcher
+            .call_for(&request(
+                json!([{"from": Address::zero(), "to": token, "data": "0x95d89b41"}, "0x10"])
+            ))
+            .is_some());
+
+        // state overrides
+        assert!(batcher
+            .call_for(&request(
+                json!([{"to": token, "data": "0x95d89b41"}, "0x10", {}])
+            ))
+            .is_none());
+
+        // no address means no batching
+        assert!(MulticallBatcher::default()
+            .call_for(&request(
+                json!([{"to": token, "data": "0x95d89b41"}, "0x10"])
+            ))
+            .is_none());
+    }
+
+    #[test]
+    fn test_batch_key() {
+        let internal = Authorization::internal(None).unwrap();
+
+        let mut keyed = internal.clone();
+        keyed.authorization_type = AuthorizationType::Frontend;
+        keyed.checks.rpc_secret_key_id = NonZeroU64::new(1);
+
+        let block = "\"0x10\"".to_string();
+
+        let a = BatchKey::new(&internal, block.clone(), Some(&16.into()));
+
+        assert_eq!(a, BatchKey::new(&internal, block.clone(), Some(&16.into())));
+
+        // different keys might have different timeouts
+        assert_ne!(a, BatchKey::new(&keyed, block.clone(), Some(&16.into())));
+
+        // different block requirements might need different servers
+        assert_ne!(a, BatchKey::new(&internal, block, None));
+    }
+
+    #[test]
+    fn test_aggregate3() {
+        let calldata = encode_aggregate3(
+            vec![(
+                Address::repeat_byte(1),
+                Bytes::from(vec![0x95, 0xd8, 0x9b, 0x41]),
+            )]
+            .into_iter(),
+        );
+
+        assert_eq!(calldata[..4], AGGREGATE3_SELECTOR);
+
+        // Error("nope")
+        let revert = [
+            &[0x08, 0xc3, 0x79, 0xa0][..],
+            &abi::encode(&[Token::String("nope".to_string())]),
+        ]
+        .concat();
+
+        let output = abi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![0x12, 0x34])]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(revert.clone())]),
+            // out of gas looks like this
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+
+        let responses = decode_aggregate3(&output).unwrap();
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[0]
+                .as_ref()
+                .unwrap()
+                .result
+                .as_ref()
+                .unwrap()
+                .get(),
+            "\"0x1234\""
+        );
+
+        // sent again on its own
+        assert!(responses[2].is_none());
+
+        let error = responses[1].as_ref().unwrap().error.as_ref().unwrap();
+        assert_eq!(error.message, "execution reverted: nope");
+        assert_eq!(error.data, Some(json!(Bytes::from(revert))));
+    }
+}
@@ -0,0 +1,177 @@
+///! Track the hottest head-block requests so they can be refreshed as soon as a new consensus head arrives.
+///!
+///! Without this, the first callers after every block pay the full latency and all hit the backends at once.
+use crate::config::PrefetchConfig;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+/// Scores are multiplied by this on every new head so that old requests cool down
+const DECAY: f64 = 0.5;
+/// Requests that cool down below this are forgotten
+const MIN_SCORE: f64 = 0.1;
+
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct HotKey {
+    method: String,
+    params: Option<String>,
+}
+
+#[derive(Debug)]
+struct HotRequest {
+    params: Option<serde_json::Value>,
+    score: f64,
+}
+
+pub struct HotRequests {
+    config: PrefetchConfig,
+    /// params are saved before block_needed replaces tags like "latest" with the head block number
+    requests: Mutex<HashMap<HotKey, HotRequest>>,
+    /// hashes of the response cache keys that were prefetched for the current head
+    prefetched: Mutex<HashSet<u64>>,
+}
+
+impl Default for HotRequests {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl HotRequests {
+    pub fn new(config: PrefetchConfig) -> Self {
+        Self {
+            config,
+            requests: Default::default(),
+            prefetched: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.config.top_n > 0
+    }
+
+    pub fn budget(&self) -> Duration {
+        Duration::from_millis(self.config.budget_millis)
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.config.max_concurrent.max(1)
+    }
+
+    /// Don't let one-off requests grow the map forever
+    fn max_tracked(&self) -> usize {
+        (self.config.top_n * 10).max(100)
+    }
+
+    /// A hash of a response cache key. Cheaper to keep than the key itself
+    pub fn key_hash<K: Hash>(key: &K) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Count a request for the head block
+    pub fn record(&self, method: &str, params: Option<serde_json::Value>) {
+        let key = HotKey {
+            method: method.to_string(),
+            params: params.as_ref().map(|x| x.to_string()),
+        };
+
+        let mut requests = self.requests.lock();
+
+        if let Some(x) = requests.get_mut(&key) {
+            x.score += 1.0;
+        } else if requests.len() < self.max_tracked() {
+            requests.insert(key, HotRequest { params, score: 1.0 });
+        }
+    }
+
+    /// Cool down the scores and return the hottest requests (method, params). Hottest first
+    pub fn on_head_block(&self) -> Vec<(String, Option<serde_json::Value>)> {
+        self.prefetched.lock().clear();
+
+        let mut requests = self.requests.lock();
+
+        let mut hottest: Vec<_> = requests
+            .iter()
+            .map(|(k, v)| (v.score, &k.method, &v.params))
+            .collect();
+
+        hottest.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let hottest = hottest
+            .into_iter()
+            .take(self.config.top_n)
+            .map(|(_, method, params)| (method.clone(), params.clone()))
+            .collect();
+
+        requests.retain(|_, v| {
+            v.score *= DECAY;
+
+            v.score >= MIN_SCORE
+        });
+
+        hottest
+    }
+
+    pub fn prefetched(&self, key_hash: u64) {
+        self.prefetched.lock().insert(key_hash);
+    }
+
+    pub fn was_prefetched(&self, key_hash: u64) -> bool {
+        self.prefetched.lock().contains(&key_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_hot_requests() {
+        let hot_requests = HotRequests::new(PrefetchConfig {
+            top_n: 2,
+            ..Default::default()
+        });
+
+        assert!(hot_requests.enabled());
+        assert!(!HotRequests::default().enabled());
+
+        let balance = json!(["0x00000000219ab540356cbb839cbe05303d7705fa", "latest"]);
+
+        for _ in 0..10 {
+            hot_requests.record("eth_blockNumber", None);
+        }
+        for _ in 0..5 {
+            hot_requests.record("eth_getBalance", Some(balance.clone()));
+        }
+        hot_requests.record("eth_gasPrice", None);
+
+        assert_eq!(
+            hot_requests.on_head_block(),
+            vec![
+                ("eth_blockNumber".to_string(), None),
+                ("eth_getBalance".to_string(), Some(balance.clone()))
+            ]
+        );
+
+        // requests that stop coming cool down and are forgotten
+        for _ in 0..4 {
+            hot_requests.record("eth_gasPrice", None);
+            hot_requests.on_head_block();
+        }
+
+        assert_eq!(hot_requests.on_head_block()[0].0, "eth_gasPrice");
+
+        hot_requests.prefetched(1);
+        assert!(hot_requests.was_prefetched(1));
+
+        // prefetches are only for the current head
+        hot_requests.on_head_block();
+        assert!(!hot_requests.was_prefetched(1));
+    }
+}
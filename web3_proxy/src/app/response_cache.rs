@@ -0,0 +1,136 @@
+///! Per-method counters for the response cache.
+use hashbrown::HashMap;
+use moka::notification::RemovalCause;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Method names come from users. Don't let them grow the counters forever
+const MAX_METHODS: usize = 1_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResponseCacheMethodMetrics {
+    pub entries: u64,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// responses that were too large to cache
+    pub bypassed: u64,
+    /// removed because of the size limit or ttl
+    pub evictions: u64,
+    /// responses refreshed for a new head block before anyone asked for them
+    pub prefetched: u64,
+    /// hits that were only hits because of a prefetch
+    pub prefetch_hits: u64,
+    pub hit_ratio: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct ResponseCacheStats {
+    methods: Mutex<HashMap<String, ResponseCacheMethodMetrics>>,
+}
+
+impl ResponseCacheStats {
+    fn update(&self, method: &str, f: impl FnOnce(&mut ResponseCacheMethodMetrics)) {
+        let mut methods = self.methods.lock();
+
+        if let Some(x) = methods.get_mut(method) {
+            f(x);
+        } else if methods.len() < MAX_METHODS {
+            f(methods.entry(method.to_string()).or_default());
+        } else {
+            f(methods.entry("other".to_string()).or_default());
+        }
+    }
+
+    pub fn hit(&self, method: &str) {
+        self.update(method, |x| x.hits += 1)
+    }
+
+    pub fn miss(&self, method: &str) {
+        self.update(method, |x| x.misses += 1)
+    }
+
+    pub fn bypass(&self, method: &str) {
+        self.update(method, |x| x.bypassed += 1)
+    }
+
+    pub fn prefetched(&self, method: &str) {
+        self.update(method, |x| x.prefetched += 1)
+    }
+
+    pub fn prefetch_hit(&self, method: &str) {
+        self.update(method, |x| x.prefetch_hits += 1)
+    }
+
+    pub fn inserted(&self, method: &str, weight: u32) {
+        self.update(method, |x| {
+            x.entries += 1;
+            x.bytes += weight as u64;
+        })
+    }
+
+    /// Called by the cache's eviction listener. Replaced entries are counted again when they are inserted
+    pub fn removed(&self, method: &str, weight: u32, cause: RemovalCause) {
+        self.update(method, |x| {
+            x.entries = x.entries.saturating_sub(1);
+            x.bytes = x.bytes.saturating_sub(weight as u64);
+
+            if cause.was_evicted() {
+                x.evictions += 1;
+            }
+        })
+    }
+
+    pub fn metrics(&self) -> HashMap<String, ResponseCacheMethodMetrics> {
+        self.methods
+            .lock()
+            .iter()
+            .map(|(method, x)| {
+                let mut x = x.clone();
+
+                let lookups = x.hits + x.misses;
+
+                if lookups > 0 {
+                    x.hit_ratio = x.hits as f64 / lookups as f64;
+                }
+
+                (method.clone(), x)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_cache_stats() {
+        let stats = ResponseCacheStats::default();
+
+        stats.miss("eth_getLogs");
+        stats.inserted("eth_getLogs", 1_000);
+        stats.hit("eth_getLogs");
+        stats.hit("eth_getLogs");
+        stats.hit("eth_getLogs");
+        stats.bypass("eth_getLogs");
+
+        stats.miss("eth_call");
+        stats.inserted("eth_call", 100);
+        stats.removed("eth_call", 100, RemovalCause::Size);
+
+        let metrics = stats.metrics();
+
+        let logs = &metrics["eth_getLogs"];
+        assert_eq!(logs.entries, 1);
+        assert_eq!(logs.bytes, 1_000);
+        assert_eq!(logs.bypassed, 1);
+        assert_eq!(logs.hit_ratio, 0.75);
+
+        let call = &metrics["eth_call"];
+        assert_eq!(call.entries, 0);
+        assert_eq!(call.bytes, 0);
+        assert_eq!(call.evictions, 1);
+        assert_eq!(call.hit_ratio, 0.0);
+    }
+}
@@ -0,0 +1,223 @@
+///! Share one backend request between concurrent identical requests that can't be cached.
+///!
+///! Unlike the response cache, nothing is kept after the request finishes.
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{self, AtomicU64};
+
+/// Identical requests for these methods get identical responses, so they can share a backend request
+pub fn is_deduplicable(method: &str) -> bool {
+    method.starts_with("trace_")
+        || method.starts_with("debug_trace")
+        || matches!(
+            method,
+            "eth_call" | "eth_createAccessList" | "eth_estimateGas"
+        )
+}
+
+/// Read only methods that go through the response cache.
+/// Identical requests for these in one batch can share a response. Methods like eth_newFilter must not
+pub fn is_cacheable_read(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_blockNumber"
+            | "eth_call"
+            | "eth_chainId"
+            | "eth_estimateGas"
+            | "eth_feeHistory"
+            | "eth_gasPrice"
+            | "eth_getBalance"
+            | "eth_getBlockByHash"
+            | "eth_getBlockByNumber"
+            | "eth_getBlockReceipts"
+            | "eth_getBlockTransactionCountByHash"
+            | "eth_getBlockTransactionCountByNumber"
+            | "eth_getCode"
+            | "eth_getLogs"
+            | "eth_getStorageAt"
+            | "eth_getTransactionByBlockHashAndIndex"
+            | "eth_getTransactionByBlockNumberAndIndex"
+            | "eth_getTransactionByHash"
+            | "eth_getTransactionCount"
+            | "eth_getTransactionReceipt"
+            | "eth_getUncleByBlockHashAndIndex"
+            | "eth_getUncleByBlockNumberAndIndex"
+            | "eth_getUncleCountByBlockHash"
+            | "eth_getUncleCountByBlockNumber"
+            | "eth_maxPriorityFeePerGas"
+            | "net_version"
+            | "web3_clientVersion"
+    )
+}
+
+/// The single flight counters in a format that serde_prometheus likes
+#[derive(Serialize)]
+pub struct SingleFlightMetrics {
+    in_flight: usize,
+    /// requests that waited on another request's backend request
+    shared: u64,
+}
+
+pub struct SingleFlight<K, V>
+where
+    V: Clone,
+{
+    in_flight: Mutex<HashMap<K, Shared<BoxFuture<'static, V>>>>,
+    shared: AtomicU64,
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+    V: Clone,
+{
+    fn default() -> Self {
+        Self {
+            in_flight: Default::default(),
+            shared: 0.into(),
+        }
+    }
+}
+
+/// Removes the leader's future when it finishes or is dropped.
+/// If the leader's caller goes away, the next caller starts a new request
+struct LeaderGuard<'a, K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    single_flight: &'a SingleFlight<K, V>,
+    key: K,
+    future: Shared<BoxFuture<'static, V>>,
+}
+
+impl<'a, K, V> Drop for LeaderGuard<'a, K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        let mut in_flight = self.single_flight.in_flight.lock();
+
+        // a new leader might have already replaced this future
+        if in_flight
+            .get(&self.key)
+            .map(|x| x.ptr_eq(&self.future))
+            .unwrap_or(false)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Run `f` unless an identical request is already running, in which case wait for that one instead.
+    /// Returns true if this call ran `f`
+    pub async fn run<F>(&self, key: K, f: F) -> (V, bool)
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let (future, guard) = {
+            let mut in_flight = self.in_flight.lock();
+
+            if let Some(future) = in_flight.get(&key) {
+                self.shared.fetch_add(1, atomic::Ordering::Relaxed);
+
+                (future.clone(), None)
+            } else {
+                let future = f.boxed().shared();
+
+                in_flight.insert(key.clone(), future.clone());
+
+                let guard = LeaderGuard {
+                    single_flight: self,
+                    key,
+                    future: future.clone(),
+                };
+
+                (future, Some(guard))
+            }
+        };
+
+        let x = future.await;
+
+        (x, guard.is_some())
+    }
+
+    pub fn metrics(&self) -> SingleFlightMetrics {
+        SingleFlightMetrics {
+            in_flight: self.in_flight.lock().len(),
+            shared: self.shared.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let single_flight = SingleFlight::<&str, u64>::default();
+
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let f = |calls: Arc<AtomicU64>| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            calls.fetch_add(1, atomic::Ordering::SeqCst) + 1
+        };
+
+        let (a, b, c) = tokio::join!(
+            single_flight.run("trace_transaction", f(calls.clone())),
+            single_flight.run("trace_transaction", f(calls.clone())),
+            single_flight.run("trace_block", f(calls.clone())),
+        );
+
+        // the first two shared a call
+        assert_eq!(a.0, b.0);
+        assert!(a.1);
+        assert!(!b.1);
+        assert!(c.1);
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 2);
+
+        // nothing is kept after the requests finish
+        assert_eq!(single_flight.metrics().in_flight, 0);
+        assert_eq!(single_flight.metrics().shared, 1);
+
+        let (d, _) = single_flight
+            .run("trace_transaction", f(calls.clone()))
+            .await;
+        assert_eq!(d, 3);
+    }
+
+    #[test]
+    fn test_is_deduplicable() {
+        assert!(is_deduplicable("trace_transaction"));
+        assert!(is_deduplicable("debug_traceTransaction"));
+        assert!(is_deduplicable("eth_estimateGas"));
+        assert!(!is_deduplicable("eth_getFilterChanges"));
+        assert!(!is_deduplicable("eth_sendRawTransaction"));
+    }
+
+    #[test]
+    fn test_is_cacheable_read() {
+        assert!(is_cacheable_read("eth_call"));
+        assert!(is_cacheable_read("eth_getLogs"));
+        assert!(!is_cacheable_read("eth_newFilter"));
+        assert!(!is_cacheable_read("eth_newBlockFilter"));
+        assert!(!is_cacheable_read("eth_newPendingTransactionFilter"));
+        assert!(!is_cacheable_read("eth_getFilterChanges"));
+        assert!(!is_cacheable_read("eth_sendRawTransaction"));
+        // idempotent, but never cached
+        assert!(!is_cacheable_read("trace_transaction"));
+    }
+}
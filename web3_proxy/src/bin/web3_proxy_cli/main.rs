@@ -376,7 +376,8 @@ fn main() -> anyhow::Result<()> {
                 let top_config_path =
                     top_config_path.expect("path must be set if top_config exists");
 
-                x.main(top_config, top_config_path, num_workers).await
+                x.main(top_config, top_config_path, num_workers, pagerduty_async)
+                    .await
             }
             SubCommand::DropMigrationLock(x) => {
                 let db_url = cli_config
@@ -189,6 +189,8 @@ impl MigrateStatsToV2 {
                         response_millis: int_response_millis.into(),
                         // We just don't have this data
                         response_from_backup_rpc: false.into(), // I think we did not record this back then // Default::default()
+                        preferred_rpc: Default::default(),
+                        relay_responses: Default::default(),
                     };
 
                     // (3) Send through a channel to a stat emitter
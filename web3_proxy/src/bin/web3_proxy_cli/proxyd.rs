@@ -3,7 +3,9 @@ use argh::FromArgs;
 use futures::StreamExt;
 use log::{error, info, trace, warn};
 use num::Zero;
+use pagerduty_rs::eventsv2async::EventsV2 as PagerdutyAsyncEventsV2;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fs, thread};
 use tokio::sync::broadcast;
@@ -31,6 +33,7 @@ impl ProxydSubCommand {
         top_config: TopConfig,
         top_config_path: PathBuf,
         num_workers: usize,
+        pagerduty_async: Option<PagerdutyAsyncEventsV2>,
     ) -> anyhow::Result<()> {
         let (shutdown_sender, _) = broadcast::channel(1);
         // TODO: i think there is a small race. if config_path changes
@@ -42,6 +45,7 @@ impl ProxydSubCommand {
             self.prometheus_port,
             num_workers,
             shutdown_sender,
+            pagerduty_async.map(Arc::new),
         )
         .await
     }
@@ -54,6 +58,7 @@ async fn run(
     prometheus_port: u16,
     num_workers: usize,
     frontend_shutdown_sender: broadcast::Sender<()>,
+    pagerduty_async: Option<Arc<PagerdutyAsyncEventsV2>>,
 ) -> anyhow::Result<()> {
     // tokio has code for catching ctrl+c so we use that
     // this shutdown sender is currently only used in tests, but we might make a /shutdown endpoint or something
@@ -76,8 +81,13 @@ async fn run(
 
     // start the main app
     // let mut spawned_app = Web3ProxyApp::spawn(top_config, num_workers, app_shutdown_sender.clone()).await?;
-    let mut spawned_app =
-        Web3ProxyApp::spawn(top_config.clone(), num_workers, app_shutdown_sender.clone()).await?;
+    let mut spawned_app = Web3ProxyApp::spawn(
+        top_config.clone(),
+        num_workers,
+        app_shutdown_sender.clone(),
+        pagerduty_async,
+    )
+    .await?;
 
     // start thread for watching config
     if let Some(top_config_path) = top_config_path {
@@ -425,6 +435,7 @@ mod tests {
                     prometheus_port,
                     2,
                     shutdown_sender,
+                    None,
                 )
                 .await
             })
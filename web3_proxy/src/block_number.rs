@@ -6,6 +6,7 @@ use ethers::{
     types::H256,
 };
 use log::{trace, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
 
@@ -106,17 +107,63 @@ pub async fn clean_block_number(
     }
 }
 
+/// Nodes prune different kinds of data at different depths.
+/// A full node might have every receipt but only recent state.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataKind {
+    /// account balances, code, and storage
+    State,
+    /// block headers, bodies, and transactions
+    Blocks,
+    /// receipts and logs
+    Receipts,
+    /// re-executed transactions
+    Traces,
+}
+
+impl DataKind {
+    pub fn from_method(method: &str) -> Self {
+        match method {
+            "eth_getLogs" | "eth_getBlockReceipts" | "eth_getTransactionReceipt" => Self::Receipts,
+            "eth_getBlockByHash"
+            | "eth_getBlockByNumber"
+            | "eth_getBlockTransactionCountByHash"
+            | "eth_getBlockTransactionCountByNumber"
+            | "eth_getTransactionByHash"
+            | "eth_getTransactionByBlockHashAndIndex"
+            | "eth_getTransactionByBlockNumberAndIndex"
+            | "eth_getUncleByBlockHashAndIndex"
+            | "eth_getUncleByBlockNumberAndIndex"
+            | "eth_getUncleCountByBlockHash"
+            | "eth_getUncleCountByBlockNumber" => Self::Blocks,
+            method if method.starts_with("trace_") || method.starts_with("debug_trace") => {
+                Self::Traces
+            }
+            // eth_call, eth_getBalance, etc. anything unknown is assumed to need state
+            _ => Self::State,
+        }
+    }
+
+    /// only state (and traces that re-execute against it) need an archive node when old
+    pub fn needs_archive(&self) -> bool {
+        matches!(self, Self::State | Self::Traces)
+    }
+}
+
 /// TODO: change this to also return the hash needed?
 pub enum BlockNeeded {
     CacheSuccessForever,
     CacheNever,
     Cache {
         block_num: U64,
+        data_kind: DataKind,
         cache_errors: bool,
     },
     CacheRange {
         from_block_num: U64,
         to_block_num: U64,
+        data_kind: DataKind,
         cache_errors: bool,
     },
 }
@@ -128,6 +175,8 @@ pub async fn block_needed(
     head_block_num: U64,
     rpcs: &Web3Rpcs,
 ) -> Web3ProxyResult<BlockNeeded> {
+    let data_kind = DataKind::from_method(method);
+
     // some requests have potentially very large responses
     // TODO: only skip caching if the response actually is large
     if method.starts_with("trace_") || method == "debug_traceTransaction" {
@@ -143,6 +192,7 @@ pub async fn block_needed(
         // caching with the head block /should/ always be okay
         return Ok(BlockNeeded::Cache {
             block_num: head_block_num,
+            data_kind,
             cache_errors: true,
         });
     };
@@ -164,6 +214,7 @@ pub async fn block_needed(
             // TODO: CacheSuccessForever if the block is old enough
             return Ok(BlockNeeded::Cache {
                 block_num: head_block_num,
+                data_kind,
                 cache_errors: true,
             });
         }
@@ -225,6 +276,7 @@ pub async fn block_needed(
                 return Ok(BlockNeeded::CacheRange {
                     from_block_num,
                     to_block_num,
+                    data_kind,
                     cache_errors: true,
                 });
             }
@@ -235,6 +287,7 @@ pub async fn block_needed(
             // try full nodes first. retry will use archive
             return Ok(BlockNeeded::Cache {
                 block_num: head_block_num,
+                data_kind,
                 cache_errors: true,
             });
         }
@@ -250,6 +303,7 @@ pub async fn block_needed(
             // try full nodes first. retry will use archive
             return Ok(BlockNeeded::Cache {
                 block_num: head_block_num,
+                data_kind,
                 cache_errors: true,
             });
         }
@@ -270,6 +324,7 @@ pub async fn block_needed(
             // since we are caching with the head block, it should be safe to cache_errors
             return Ok(BlockNeeded::Cache {
                 block_num: head_block_num,
+                data_kind,
                 cache_errors: true,
             });
         }
@@ -278,12 +333,14 @@ pub async fn block_needed(
     match clean_block_number(authorization, params, block_param_id, head_block_num, rpcs).await {
         Ok(block_num) => Ok(BlockNeeded::Cache {
             block_num,
+            data_kind,
             cache_errors: true,
         }),
         Err(err) => {
             warn!("could not get block from params. err={:?}", err);
             Ok(BlockNeeded::Cache {
                 block_num: head_block_num,
+                data_kind,
                 cache_errors: true,
             })
         }
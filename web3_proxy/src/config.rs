@@ -2,12 +2,12 @@ use crate::app::AnyhowJoinHandle;
 use crate::rpcs::blockchain::{BlocksByHashCache, Web3ProxyBlock};
 use crate::rpcs::one::Web3Rpc;
 use argh::FromArgs;
-use ethers::prelude::TxHash;
+use ethers::prelude::{Address, TxHash};
 use ethers::types::{U256, U64};
 use hashbrown::HashMap;
 use log::warn;
 use migration::sea_orm::DatabaseConnection;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
@@ -58,7 +58,28 @@ pub struct AppConfig {
     #[serde(default = "default_allowed_origin_requests_per_period")]
     pub allowed_origin_requests_per_period: HashMap<String, u64>,
 
+    /// send nonce-sensitive requests from the same key or sender to the same backend rpc
+    #[serde(default)]
+    pub affinity: AffinityConfig,
+
+    /// which users get the highest pending nonce of all the synced rpcs
+    #[serde(default)]
+    pub aggregate_pending_nonce: AggregatePendingNonceConfig,
+
+    /// limits for JSON-RPC batches
+    #[serde(default)]
+    pub batch: BatchConfig,
+
+    /// what to do when the balanced rpcs disagree about the chain
+    #[serde(default)]
+    pub chain_split: ChainSplitConfig,
+
+    /// gzip, brotli, and zstd for http requests and responses. off unless enabled
+    #[serde(default)]
+    pub compression: CompressionConfig,
+
     /// erigon defaults to pruning beyond 90,000 blocks
+    /// only used until the block data limits of the pruned servers are known
     #[serde(default = "default_archive_depth")]
     pub archive_depth: u64,
 
@@ -120,6 +141,20 @@ pub struct AppConfig {
     /// do not serve any requests if the best known block is behind the best known block by more than this many blocks.
     pub max_block_lag: Option<U64>,
 
+    /// How long a request (including retries on other servers) can take. User tiers can set their own.
+    /// 0 = no timeout
+    #[serde(default = "default_request_timeout_seconds")]
+    pub request_timeout_seconds: u64,
+
+    /// The largest request body that is read for anyone. User tiers can set a lower limit.
+    /// 0 = no limit
+    #[serde(default = "default_max_request_bytes")]
+    pub max_request_bytes: u64,
+
+    /// The largest response for user tiers that don't set their own.
+    /// None = no limit
+    pub max_response_bytes: Option<u64>,
+
     /// Rate limit for bearer token authenticated entrypoints.
     /// This is separate from the rpc limits.
     #[serde(default = "default_bearer_token_max_concurrent_requests")]
@@ -151,10 +186,44 @@ pub struct AppConfig {
     /// Salt for hashing recent ips. Not a perfect way to introduce privacy, but better than nothing
     pub public_recent_ips_salt: Option<String>,
 
+    /// simulate private transactions and reject them instead of sending them if they would revert
+    #[serde(default)]
+    pub revert_protection: RevertProtectionConfig,
+
+    /// After an rpc key sends a transaction, adjust its receipt and pending nonce requests for this many seconds.
+    /// 0 disables read-your-writes
+    #[serde(default = "default_read_your_writes_seconds")]
+    pub read_your_writes_seconds: u64,
+
     /// RPC responses are cached locally
     #[serde(default = "default_response_cache_max_bytes")]
     pub response_cache_max_bytes: u64,
 
+    /// Responses bigger than this are returned without being cached.
+    /// 0 uses 1% of response_cache_max_bytes
+    #[serde(default)]
+    pub response_cache_max_item_bytes: u64,
+
+    /// Per-method overrides of response_cache_max_item_bytes
+    #[serde(default)]
+    pub response_cache_max_item_bytes_by_method: HashMap<String, u64>,
+
+    /// Optionally save responses that will never change to disk
+    #[serde(default)]
+    pub disk_cache: DiskCacheConfig,
+
+    /// Refresh the hottest head-block requests when a new consensus head arrives
+    #[serde(default)]
+    pub prefetch: PrefetchConfig,
+
+    /// Reuse eth_call results from older blocks when the called contracts weren't touched
+    #[serde(default)]
+    pub call_cache: CallCacheConfig,
+
+    /// Combine concurrent eth_calls for the same block into one Multicall3 call
+    #[serde(default)]
+    pub multicall: MulticallConfig,
+
     /// the stats page url for an anonymous user.
     pub redirect_public_url: Option<String>,
 
@@ -193,6 +262,10 @@ fn default_archive_depth() -> u64 {
     90_000
 }
 
+fn default_read_your_writes_seconds() -> u64 {
+    30
+}
+
 fn default_allowed_origin_requests_per_period() -> HashMap<String, u64> {
     HashMap::new()
 }
@@ -221,12 +294,36 @@ fn default_kafka_protocol() -> String {
     "ssl".to_string()
 }
 
+fn default_request_timeout_seconds() -> u64 {
+    120
+}
+
+/// 2 MiB. the same as axum's default body limit
+fn default_max_request_bytes() -> u64 {
+    2 * 1024 * 1024
+}
+
 fn default_response_cache_max_bytes() -> u64 {
     // TODO: default to some percentage of the system?
     // 100 megabytes
     10u64.pow(8)
 }
 
+impl AppConfig {
+    /// The largest response for this method that will be put in the response cache
+    pub fn response_cache_max_item_bytes(&self, method: &str) -> u64 {
+        if let Some(x) = self.response_cache_max_item_bytes_by_method.get(method) {
+            return *x;
+        }
+
+        if self.response_cache_max_item_bytes > 0 {
+            self.response_cache_max_item_bytes
+        } else {
+            self.response_cache_max_bytes / 100
+        }
+    }
+}
+
 /// Configuration for a backend web3 RPC server
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
 pub struct Web3RpcConfig {
@@ -243,6 +340,15 @@ pub struct Web3RpcConfig {
     pub http_url: Option<String>,
     /// block data limit. If None, will be queried
     pub block_data_limit: Option<u64>,
+    /// how many blocks of bodies and transactions are kept. If None, will be queried
+    pub blocks_data_limit: Option<u64>,
+    /// how many blocks of receipts and logs are kept. If None, will be queried
+    pub receipts_data_limit: Option<u64>,
+    /// how many blocks can be traced. If None, block_data_limit is used
+    pub traces_data_limit: Option<u64>,
+    /// how often to re-check the data limits that were queried. 0 only checks once at connect
+    #[serde(default = "default_block_data_limit_interval_seconds")]
+    pub block_data_limit_interval_seconds: u64,
     /// the requests per second at which the server starts slowing down
     pub soft_limit: u32,
     /// the requests per second at which the server throws errors (rate limit or otherwise)
@@ -253,10 +359,37 @@ pub struct Web3RpcConfig {
     /// All else equal, a server with a lower tier receives all requests
     #[serde(default = "default_tier")]
     pub tier: u64,
+    /// when polling over http, check eth_blockNumber and only fetch the full block when it changes.
+    /// this is cheaper on chains with very fast blocks
+    #[serde(default)]
+    pub poll_block_number: bool,
     /// Subscribe to the firehose of pending transactions
     /// Don't do this with free rpcs
     #[serde(default)]
     pub subscribe_txs: bool,
+    /// stop sending requests to this rpc if too many of them error or time out. off unless enabled
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// reconnect to this rpc if its head block gets stuck or doesn't match its head over http. off unless enabled
+    #[serde(default)]
+    pub head_watchdog: HeadWatchdogConfig,
+    /// maximum number of requests to have in flight to this server at once. unlimited if not set
+    #[serde(default)]
+    pub max_concurrent_requests: Option<u32>,
+    /// how many requests can wait for a free slot when max_concurrent_requests is reached
+    #[serde(default = "default_max_queued_requests")]
+    pub max_queued_requests: usize,
+    /// how long a queued request waits for a free slot before giving up
+    #[serde(default = "default_max_queue_wait_ms")]
+    pub max_queue_wait_ms: u64,
+    /// methods that this rpc is known to support. these skip capability probing.
+    /// a trailing "*" matches a prefix (like "trace_*")
+    #[serde(default)]
+    pub methods_allowed: Vec<String>,
+    /// methods that should never be sent to this rpc. these win over methods_allowed.
+    /// a trailing "*" matches a prefix (like "debug_*")
+    #[serde(default)]
+    pub methods_denied: Vec<String>,
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, serde_json::Value>,
@@ -266,6 +399,372 @@ fn default_tier() -> u64 {
     0
 }
 
+/// load balanced servers can answer differently over time
+fn default_block_data_limit_interval_seconds() -> u64 {
+    600
+}
+
+fn default_max_queued_requests() -> usize {
+    100
+}
+
+fn default_max_queue_wait_ms() -> u64 {
+    1_000
+}
+
+/// Thresholds for a Web3Rpc's circuit breaker
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// opt-in. routing doesn't change unless this is set
+    pub enabled: bool,
+    /// open the circuit when this percent of requests in the window error or time out.
+    /// 0 disables the circuit breaker
+    pub error_percent: u64,
+    /// the circuit will not open until at least this many requests are seen in the window
+    pub min_requests: u64,
+    /// how long to collect error and timeout counts for before starting over
+    pub window_seconds: u64,
+    /// how long an open circuit waits before letting trial requests through
+    pub open_seconds: u64,
+    /// how many trial requests need to succeed before a half-open circuit closes
+    pub half_open_requests: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            error_percent: 50,
+            min_requests: 20,
+            window_seconds: 30,
+            open_seconds: 30,
+            half_open_requests: 3,
+        }
+    }
+}
+
+/// What to do with transactions while there is a chain split
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainSplitTxPolicy {
+    /// send transactions like normal
+    #[default]
+    Allow,
+    /// reject eth_sendRawTransaction until the split resolves
+    Pause,
+    /// only send to the balanced rpcs on the side of our consensus head.
+    /// private rpcs are skipped because we can't see which side they are on
+    Consensus,
+}
+
+/// Thresholds for detecting chain splits between the balanced rpcs
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChainSplitConfig {
+    /// opt-in. checking for a split fetches blocks while processing new heads
+    pub enabled: bool,
+    /// how many blocks the sides have to disagree for
+    pub min_blocks: u64,
+    /// each side needs this percent of the total soft limit. 0 disables detection
+    pub min_soft_limit_percent: u64,
+    pub tx_policy: ChainSplitTxPolicy,
+}
+
+impl Default for ChainSplitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_blocks: 3,
+            min_soft_limit_percent: 25,
+            tx_policy: ChainSplitTxPolicy::Allow,
+        }
+    }
+}
+
+/// What requests share a backend rpc
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AffinityMode {
+    /// pick the best backend for every request
+    #[default]
+    Off,
+    /// requests with the same rpc key. anonymous requests are not sticky
+    RpcKey,
+    /// requests for the same sender address
+    Address,
+}
+
+/// Sticky backends for pending nonce reads and transaction sends
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AffinityConfig {
+    pub mode: AffinityMode,
+    /// how long to remember the backend after its last nonce-sensitive request
+    pub ttl_seconds: u64,
+    pub max_capacity: u64,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        Self {
+            mode: AffinityMode::Off,
+            ttl_seconds: 60,
+            max_capacity: 10_000,
+        }
+    }
+}
+
+/// Query every synced rpc for `eth_getTransactionCount(address, "pending")` and use the highest nonce.
+/// Mempools differ, so this gives wallets a consistent nonce. Opt-in because it costs a request per synced rpc
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AggregatePendingNonceConfig {
+    /// database ids of the user tiers that get aggregated pending nonces
+    pub user_tier_ids: Vec<u64>,
+    /// database ids of the rpc keys that get aggregated pending nonces
+    pub rpc_key_ids: Vec<u64>,
+    /// how long to cache the nonce for each address
+    pub cache_millis: u64,
+}
+
+impl Default for AggregatePendingNonceConfig {
+    fn default() -> Self {
+        Self {
+            user_tier_ids: vec![],
+            rpc_key_ids: vec![],
+            cache_millis: 1_000,
+        }
+    }
+}
+
+impl AggregatePendingNonceConfig {
+    pub fn enabled_for(&self, user_tier_id: u64, rpc_key_id: u64) -> bool {
+        self.user_tier_ids.contains(&user_tier_id) || self.rpc_key_ids.contains(&rpc_key_id)
+    }
+}
+
+/// Limits for JSON-RPC batches. Every request in a batch counts against the rate limits
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct BatchConfig {
+    /// the most requests allowed in one batch. 0 means no limit
+    pub max_size: usize,
+    /// how many requests from one batch are proxied at the same time
+    pub max_concurrent: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1_000,
+            max_concurrent: 20,
+        }
+    }
+}
+
+/// Compression for the http frontend. Everything is off by default.
+/// Websockets are not compressed yet. permessage-deflate is a separate item in TODO.md
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// compress responses for clients that send Accept-Encoding
+    pub responses: bool,
+    /// smaller responses aren't worth compressing
+    pub min_response_bytes: u16,
+    /// decompress request bodies that have a Content-Encoding.
+    /// the decompressed body is limited by max_request_bytes
+    pub requests: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            responses: false,
+            min_response_bytes: 1_024,
+            requests: false,
+        }
+    }
+}
+
+/// A "no revert" mode for rpc keys with private transactions
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RevertProtectionConfig {
+    /// protect every rpc key that has private_txs enabled
+    pub all_private_keys: bool,
+    /// database ids of the rpc keys to protect. they also need private_txs enabled
+    pub rpc_key_ids: Vec<u64>,
+    /// "pending" or "latest"
+    pub block_tag: String,
+}
+
+impl Default for RevertProtectionConfig {
+    fn default() -> Self {
+        Self {
+            all_private_keys: false,
+            rpc_key_ids: vec![],
+            block_tag: "pending".to_string(),
+        }
+    }
+}
+
+impl RevertProtectionConfig {
+    pub fn enabled_for(&self, private_txs: bool, rpc_key_id: u64) -> bool {
+        private_txs && (self.all_private_keys || self.rpc_key_ids.contains(&rpc_key_id))
+    }
+}
+
+/// An on-disk cache behind the in-memory response cache
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DiskCacheConfig {
+    /// directory for the cache. None disables the disk cache
+    pub path: Option<String>,
+    /// the least recently used responses are removed when the stored responses are bigger than this
+    pub max_bytes: u64,
+    /// larger responses aren't saved. this is separate from response_cache_max_item_bytes so that large eth_getLogs can go to disk
+    pub max_item_bytes: u64,
+    /// only responses for blocks at least this far behind the head are saved
+    pub min_block_depth: u64,
+}
+
+impl Default for DiskCacheConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            // 10 gigabytes
+            max_bytes: 10u64.pow(10),
+            // 1 gigabyte
+            max_item_bytes: 10u64.pow(9),
+            // about 2 epochs on mainnet. blocks this old are finalized
+            min_block_depth: 64,
+        }
+    }
+}
+
+/// Refresh cached responses for the hottest requests on every new head block
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PrefetchConfig {
+    /// how many of the hottest requests to refresh. 0 disables prefetching
+    pub top_n: usize,
+    /// stop prefetching after this long. the next head might be coming soon
+    pub budget_millis: u64,
+    /// how many prefetches to send to the backends at once
+    pub max_concurrent: usize,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            top_n: 0,
+            budget_millis: 500,
+            max_concurrent: 4,
+        }
+    }
+}
+
+/// Reuse eth_call results across blocks. This is a heuristic!
+/// A contract's state can change without a transaction to it or a log from it
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CallCacheConfig {
+    pub enabled: bool,
+    /// never reuse a result from more than this many blocks ago
+    pub max_age_blocks: u64,
+    /// only reuse calls to these 4-byte function selectors. empty allows any call
+    pub selectors: Vec<String>,
+    /// also check that address-like arguments (like the owner in `balanceOf`) weren't touched
+    pub check_calldata_addresses: bool,
+    /// the most memory to use for reusable results
+    pub max_bytes: u64,
+}
+
+impl Default for CallCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_age_blocks: 10,
+            selectors: vec![
+                // name()
+                "0x06fdde03".to_string(),
+                // symbol()
+                "0x95d89b41".to_string(),
+                // decimals()
+                "0x313ce567".to_string(),
+            ],
+            check_calldata_addresses: true,
+            max_bytes: 10_000_000,
+        }
+    }
+}
+
+/// Collect concurrent eth_calls for the same block for a few milliseconds and send them as one Multicall3 `aggregate3`.
+/// Calls that set `from`, `value`, `gas`, or state overrides are sent on their own
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MulticallConfig {
+    /// where Multicall3 is deployed. None disables batching
+    pub address: Option<Address>,
+    /// how long to wait for more calls before sending a batch
+    pub window_millis: u64,
+    /// send a batch as soon as it has this many calls
+    pub max_calls: usize,
+    /// database ids of the user tiers that get batched
+    pub user_tier_ids: Vec<u64>,
+    /// database ids of the rpc keys that get batched
+    pub rpc_key_ids: Vec<u64>,
+}
+
+impl Default for MulticallConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            window_millis: 5,
+            max_calls: 50,
+            user_tier_ids: vec![],
+            rpc_key_ids: vec![],
+        }
+    }
+}
+
+impl MulticallConfig {
+    pub fn enabled_for(&self, user_tier_id: u64, rpc_key_id: u64) -> bool {
+        self.address.is_some()
+            && (self.user_tier_ids.contains(&user_tier_id)
+                || self.rpc_key_ids.contains(&rpc_key_id))
+    }
+}
+
+/// Thresholds for a Web3Rpc's head block watchdog
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HeadWatchdogConfig {
+    /// opt-in. quiet chains and testnets can go minutes without a block
+    pub enabled: bool,
+    /// how often to check the head block. 0 disables the watchdog
+    pub interval_seconds: u64,
+    /// reconnect if the subscription hasn't sent a new head for this long. 0 disables this check
+    pub max_head_age_seconds: u64,
+    /// the subscribed head and the http head can be this many blocks apart
+    pub max_head_lag: u64,
+    /// heads are often a block apart for a moment. only reconnect after this many bad checks in a row
+    pub max_divergent_checks: u32,
+}
+
+impl Default for HeadWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_seconds: 30,
+            max_head_age_seconds: 120,
+            max_head_lag: 5,
+            max_divergent_checks: 2,
+        }
+    }
+}
+
 impl Web3RpcConfig {
     /// Create a Web3Rpc from config
     /// TODO: move this into Web3Rpc? (just need to make things pub(crate))
@@ -277,7 +776,7 @@ impl Web3RpcConfig {
         redis_pool: Option<redis_rate_limiter::RedisPool>,
         chain_id: u64,
         http_client: Option<reqwest::Client>,
-        http_interval_sender: Option<Arc<broadcast::Sender<()>>>,
+        http_interval_sender: Option<Arc<broadcast::Sender<Duration>>>,
         blocks_by_hash_cache: BlocksByHashCache,
         block_sender: Option<flume::Sender<BlockAndRpc>>,
         tx_id_sender: Option<flume::Sender<TxHashAndRpc>>,
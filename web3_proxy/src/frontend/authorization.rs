@@ -1,9 +1,11 @@
 //! Utilities for authorization of logged in and anonymous users.
 
+use super::compression::WireBytes;
 use super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use super::rpc_proxy_ws::ProxyMode;
 use crate::app::{AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
 use crate::rpcs::one::Web3Rpc;
+use crate::rpcs::private_txs::RelayResponse;
 use crate::user_token::UserBearerToken;
 use axum::headers::authorization::Bearer;
 use axum::headers::{Header, Origin, Referer, UserAgent};
@@ -26,7 +28,7 @@ use std::fmt::Display;
 use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::{net::IpAddr, str::FromStr, sync::Arc};
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use ulid::Ulid;
 use uuid::Uuid;
 
@@ -68,6 +70,8 @@ pub struct Authorization {
     pub referer: Option<Referer>,
     pub user_agent: Option<UserAgent>,
     pub authorization_type: AuthorizationType,
+    /// set for http requests. stats wait for the response to be sent so that they know the compressed sizes
+    pub wire_bytes: Option<Arc<WireBytes>>,
 }
 
 #[derive(Debug)]
@@ -84,6 +88,10 @@ pub struct RequestMetadata {
     pub response_bytes: AtomicU64,
     pub response_millis: AtomicU64,
     pub response_from_backup_rpc: AtomicBool,
+    /// name of the backend rpc that recently served this key or sender. see `BackendAffinity`
+    pub preferred_rpc: Mutex<Option<String>>,
+    /// every response when a request is sent to multiple rpcs at once
+    pub relay_responses: Mutex<Vec<RelayResponse>>,
 }
 
 impl RequestMetadata {
@@ -101,6 +109,8 @@ impl RequestMetadata {
             response_bytes: 0.into(),
             response_millis: 0.into(),
             response_from_backup_rpc: false.into(),
+            preferred_rpc: Default::default(),
+            relay_responses: Default::default(),
         }
     }
 }
@@ -296,6 +306,7 @@ impl Authorization {
             referer,
             user_agent,
             authorization_type,
+            wire_bytes: None,
         })
     }
 }
@@ -316,11 +327,15 @@ pub async fn login_is_authorized(app: &Web3ProxyApp, ip: IpAddr) -> Web3ProxyRes
 }
 
 /// semaphore won't ever be None, but its easier if key auth and ip auth work the same way
+/// num_requests is more than 1 for batches. every request in a batch counts against the rate limit
+/// request_bytes is checked before the rate limit so that an oversized request doesn't use it up
 pub async fn ip_is_authorized(
     app: &Arc<Web3ProxyApp>,
     ip: IpAddr,
     origin: Option<Origin>,
     proxy_mode: ProxyMode,
+    num_requests: u64,
+    request_bytes: usize,
 ) -> Web3ProxyResult<(Authorization, Option<OwnedSemaphorePermit>)> {
     // TODO: i think we could write an `impl From` for this
     // TODO: move this to an AuthorizedUser extrator
@@ -330,6 +345,8 @@ pub async fn ip_is_authorized(
             ip,
             origin,
             proxy_mode,
+            num_requests,
+            request_bytes,
         )
         .await?
     {
@@ -381,6 +398,7 @@ pub async fn ip_is_authorized(
 }
 
 /// like app.rate_limit_by_rpc_key but converts to a Web3ProxyError;
+#[allow(clippy::too_many_arguments)]
 pub async fn key_is_authorized(
     app: &Arc<Web3ProxyApp>,
     rpc_key: RpcSecretKey,
@@ -389,11 +407,22 @@ pub async fn key_is_authorized(
     proxy_mode: ProxyMode,
     referer: Option<Referer>,
     user_agent: Option<UserAgent>,
+    num_requests: u64,
+    request_bytes: usize,
 ) -> Web3ProxyResult<(Authorization, Option<OwnedSemaphorePermit>)> {
     // check the rate limits. error if over the limit
     // TODO: i think this should be in an "impl From" or "impl Into"
     let (authorization, semaphore) = match app
-        .rate_limit_by_rpc_key(ip, origin, proxy_mode, referer, rpc_key, user_agent)
+        .rate_limit_by_rpc_key(
+            ip,
+            origin,
+            proxy_mode,
+            referer,
+            rpc_key,
+            user_agent,
+            num_requests,
+            request_bytes,
+        )
         .await?
     {
         RateLimitResult::Allowed(authorization, semaphore) => (authorization, semaphore),
@@ -603,10 +632,12 @@ impl Web3ProxyApp {
         ip: IpAddr,
         origin: Option<Origin>,
         proxy_mode: ProxyMode,
+        num_requests: u64,
+        request_bytes: usize,
     ) -> Web3ProxyResult<RateLimitResult> {
         // ip rate limits don't check referer or user agent
         // they do check origin because we can override rate limits for some origins
-        let authorization = Authorization::external(
+        let mut authorization = Authorization::external(
             allowed_origin_requests_per_period,
             self.db_conn.clone(),
             ip,
@@ -616,9 +647,19 @@ impl Web3ProxyApp {
             None,
         )?;
 
+        self.check_batch_size(&authorization.checks, num_requests as usize)?;
+        self.check_request_bytes(&authorization.checks, request_bytes)?;
+
+        // request handles enforce this
+        authorization.checks.max_response_bytes = self.config.max_response_bytes;
+
         if let Some(rate_limiter) = &self.frontend_ip_rate_limiter {
             match rate_limiter
-                .throttle(ip, authorization.checks.max_requests_per_period, 1)
+                .throttle(
+                    ip,
+                    authorization.checks.max_requests_per_period,
+                    num_requests,
+                )
                 .await
             {
                 Ok(DeferredRateLimitResult::Allowed) => {
@@ -771,6 +812,32 @@ impl Web3ProxyApp {
                             max_requests_per_period: user_tier_model.max_requests_per_period,
                             private_txs: rpc_key_model.private_txs,
                             proxy_mode,
+                            aggregate_pending_nonce: self
+                                .config
+                                .aggregate_pending_nonce
+                                .enabled_for(user_tier_model.id, rpc_key_model.id),
+                            revert_protection: self
+                                .config
+                                .revert_protection
+                                .enabled_for(rpc_key_model.private_txs, rpc_key_model.id),
+                            multicall: self
+                                .config
+                                .multicall
+                                .enabled_for(user_tier_model.id, rpc_key_model.id),
+                            max_batch_size: user_tier_model
+                                .max_batch_size
+                                .filter(|x| *x > 0)
+                                .map(|x| x as usize),
+                            request_timeout: user_tier_model
+                                .request_timeout_seconds
+                                .filter(|x| *x > 0)
+                                .map(Duration::from_secs),
+                            max_request_bytes: user_tier_model.max_request_bytes.filter(|x| *x > 0),
+                            // the app's limit is included so that request handles can enforce it
+                            max_response_bytes: user_tier_model
+                                .max_response_bytes
+                                .filter(|x| *x > 0)
+                                .or(self.config.max_response_bytes),
                         })
                     }
                     None => Ok(AuthorizationChecks::default()),
@@ -782,6 +849,7 @@ impl Web3ProxyApp {
     }
 
     /// Authorized the ip/origin/referer/useragent and rate limit and concurrency
+    #[allow(clippy::too_many_arguments)]
     pub async fn rate_limit_by_rpc_key(
         &self,
         ip: IpAddr,
@@ -790,6 +858,8 @@ impl Web3ProxyApp {
         referer: Option<Referer>,
         rpc_key: RpcSecretKey,
         user_agent: Option<UserAgent>,
+        num_requests: u64,
+        request_bytes: usize,
     ) -> Web3ProxyResult<RateLimitResult> {
         let authorization_checks = self.authorization_checks(proxy_mode, rpc_key).await?;
 
@@ -798,6 +868,9 @@ impl Web3ProxyApp {
             return Ok(RateLimitResult::UnknownKey);
         }
 
+        self.check_batch_size(&authorization_checks, num_requests as usize)?;
+        self.check_request_bytes(&authorization_checks, request_bytes)?;
+
         // TODO: rpc_key should have an option to rate limit by ip instead of by key
 
         // only allow this rpc_key to run a limited amount of concurrent requests
@@ -829,7 +902,7 @@ impl Web3ProxyApp {
                 .throttle(
                     authorization.checks.user_id,
                     Some(user_max_requests_per_period),
-                    1,
+                    num_requests,
                 )
                 .await
             {
@@ -870,6 +943,8 @@ impl Authorization {
     pub async fn check_again(
         &self,
         app: &Arc<Web3ProxyApp>,
+        num_requests: u64,
+        request_bytes: usize,
     ) -> Web3ProxyResult<(Arc<Self>, Option<OwnedSemaphorePermit>)> {
         // TODO: we could probably do this without clones. but this is easy
         let (a, s) = if let Some(rpc_secret_key) = self.checks.rpc_secret_key {
@@ -881,10 +956,20 @@ impl Authorization {
                 self.checks.proxy_mode,
                 self.referer.clone(),
                 self.user_agent.clone(),
+                num_requests,
+                request_bytes,
             )
             .await?
         } else {
-            ip_is_authorized(app, self.ip, self.origin.clone(), self.checks.proxy_mode).await?
+            ip_is_authorized(
+                app,
+                self.ip,
+                self.origin.clone(),
+                self.checks.proxy_mode,
+                num_requests,
+                request_bytes,
+            )
+            .await?
         };
 
         let a = Arc::new(a);
@@ -0,0 +1,305 @@
+//! Compressed http requests and responses.
+//!
+//! The stats count logical (uncompressed) bytes. `WireBytes` counts what was actually sent and received.
+//! Websockets aren't handled here. permessage-deflate is a separate item in TODO.md
+use crate::config::CompressionConfig;
+use crate::stats::{AppStat, RpcQueryStats};
+use axum::body::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use http::{HeaderMap, Request, Response};
+use http_body::{Body as HttpBody, SizeHint};
+use log::warn;
+use parking_lot::Mutex;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tower_http::compression::predicate::{And, DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+
+/// gzip, brotli, or zstd responses for clients that accept them
+pub fn compression_layer(
+    config: &CompressionConfig,
+) -> CompressionLayer<And<DefaultPredicate, SizeAbove>> {
+    let layer = CompressionLayer::new()
+        .compress_when(DefaultPredicate::new().and(SizeAbove::new(config.min_response_bytes)));
+
+    if config.responses {
+        layer
+    } else {
+        layer.no_br().no_gzip().no_zstd()
+    }
+}
+
+/// gzip, brotli, or zstd request bodies.
+/// Bodies are limited by max_request_bytes after they are decompressed. Without that limit, a tiny zip bomb could use all our memory
+pub fn decompression_layer(
+    config: &CompressionConfig,
+    max_request_bytes: u64,
+) -> RequestDecompressionLayer {
+    let layer = RequestDecompressionLayer::new();
+
+    if !config.requests {
+        layer.no_br().no_gzip().no_zstd()
+    } else if max_request_bytes == 0 {
+        warn!("compressed requests are disabled because max_request_bytes is 0");
+        layer.no_br().no_gzip().no_zstd()
+    } else {
+        layer
+    }
+}
+
+/// Bytes sent and received for one http request.
+/// Stats for the request are held until the response is sent so that they can include these
+pub struct WireBytes {
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    finished: AtomicBool,
+    pending: Mutex<Vec<RpcQueryStats>>,
+    stat_sender: Option<flume::Sender<AppStat>>,
+}
+
+impl fmt::Debug for WireBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireBytes")
+            .field("request_bytes", &self.request_bytes)
+            .field("response_bytes", &self.response_bytes)
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A share of `total` that is proportional to `part / sum`
+fn apportion(total: u64, part: u64, sum: u64) -> u64 {
+    if sum == 0 {
+        0
+    } else {
+        (total as u128 * part as u128 / sum as u128) as u64
+    }
+}
+
+impl WireBytes {
+    pub fn new(stat_sender: Option<flume::Sender<AppStat>>) -> Self {
+        Self {
+            request_bytes: 0.into(),
+            response_bytes: 0.into(),
+            finished: false.into(),
+            pending: Default::default(),
+            stat_sender,
+        }
+    }
+
+    /// Hold a stat until the response is sent
+    pub fn defer(&self, stat: RpcQueryStats) {
+        let mut pending = self.pending.lock();
+
+        if self.finished.load(Ordering::Acquire) {
+            drop(pending);
+
+            self.send(vec![stat]);
+        } else {
+            pending.push(stat);
+        }
+    }
+
+    /// The response is done. Split the wire bytes between the stats for this http request.
+    /// A batch has one stat for every request in it
+    fn finish(&self) {
+        let stats = {
+            let mut pending = self.pending.lock();
+
+            self.finished.store(true, Ordering::Release);
+
+            std::mem::take(&mut *pending)
+        };
+
+        self.send(stats);
+    }
+
+    fn send(&self, mut stats: Vec<RpcQueryStats>) {
+        let request_bytes = self.request_bytes.load(Ordering::Acquire);
+        let response_bytes = self.response_bytes.load(Ordering::Acquire);
+
+        let sum_request_bytes = stats.iter().map(|x| x.request_bytes).sum();
+        let sum_response_bytes = stats.iter().map(|x| x.response_bytes).sum();
+
+        for stat in stats.iter_mut() {
+            stat.request_wire_bytes =
+                apportion(request_bytes, stat.request_bytes, sum_request_bytes);
+            stat.response_wire_bytes =
+                apportion(response_bytes, stat.response_bytes, sum_response_bytes);
+        }
+
+        if let Some(stat_sender) = self.stat_sender.as_ref() {
+            for stat in stats {
+                if let Err(err) = stat_sender.send(stat.into()) {
+                    warn!("stat_sender failed for wire bytes: {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Request,
+    Response,
+}
+
+/// Sends the deferred stats when dropped.
+/// This is held by the response body. If the client goes away before there is a response, it is dropped with the request
+struct FinishGuard(Arc<WireBytes>);
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// Counts the bytes that pass through a body
+pub struct CountingBody<B> {
+    inner: Pin<Box<B>>,
+    wire_bytes: Arc<WireBytes>,
+    direction: Direction,
+    _finish: Option<FinishGuard>,
+}
+
+impl<B> HttpBody for CountingBody<B>
+where
+    B: HttpBody<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let x = self.inner.as_mut().poll_data(cx);
+
+        if let Poll::Ready(Some(Ok(data))) = &x {
+            let counter = match self.direction {
+                Direction::Request => &self.wire_bytes.request_bytes,
+                Direction::Response => &self.wire_bytes.response_bytes,
+            };
+
+            counter.fetch_add(data.len() as u64, Ordering::AcqRel);
+        }
+
+        x
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.inner.as_mut().poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Count the bytes of every http request and response. Must be outside of the compression layers
+#[derive(Clone)]
+pub struct WireBytesLayer {
+    stat_sender: Option<flume::Sender<AppStat>>,
+}
+
+impl WireBytesLayer {
+    pub fn new(stat_sender: Option<flume::Sender<AppStat>>) -> Self {
+        Self { stat_sender }
+    }
+}
+
+impl<S> Layer<S> for WireBytesLayer {
+    type Service = WireBytesService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        WireBytesService {
+            inner,
+            stat_sender: self.stat_sender.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WireBytesService<S> {
+    inner: S,
+    stat_sender: Option<flume::Sender<AppStat>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for WireBytesService<S>
+where
+    S: Service<Request<CountingBody<ReqBody>>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<CountingBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let wire_bytes = Arc::new(WireBytes::new(self.stat_sender.clone()));
+
+        let (mut parts, body) = req.into_parts();
+
+        // handlers attach this to the request's Authorization
+        parts.extensions.insert(wire_bytes.clone());
+
+        let body = CountingBody {
+            inner: Box::pin(body),
+            wire_bytes: wire_bytes.clone(),
+            direction: Direction::Request,
+            _finish: None,
+        };
+
+        let f = self.inner.call(Request::from_parts(parts, body));
+
+        let finish = FinishGuard(wire_bytes.clone());
+
+        async move {
+            let response = f.await?;
+
+            Ok(response.map(|body| CountingBody {
+                inner: Box::pin(body),
+                wire_bytes,
+                direction: Direction::Response,
+                _finish: Some(finish),
+            }))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apportion() {
+        // a batch of 3 requests that compressed to 1/4 of their size
+        let logical = [100, 300, 600];
+        let sum = logical.iter().sum();
+
+        let wire: Vec<_> = logical.iter().map(|x| apportion(250, *x, sum)).collect();
+
+        assert_eq!(wire, vec![25, 75, 150]);
+        assert_eq!(wire.iter().sum::<u64>(), 250);
+
+        assert_eq!(apportion(250, 0, 0), 0);
+        assert_eq!(apportion(u64::MAX, u64::MAX, u64::MAX), u64::MAX);
+    }
+}
@@ -1,7 +1,7 @@
 //! Utlities for logging errors for admins and displaying errors to users.
 
 use super::authorization::Authorization;
-use crate::jsonrpc::JsonRpcForwardedResponse;
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse};
 
 use std::net::IpAddr;
 use std::sync::Arc;
@@ -25,6 +25,11 @@ pub type Web3ProxyResult<T> = Result<T, Web3ProxyError>;
 // TODO: take "IntoResponse" instead of Response?
 pub type Web3ProxyResponse = Web3ProxyResult<Response>;
 
+/// JSON-RPC codes for the per-tier limits. -32000 to -32099 are reserved for server errors
+pub const REQUEST_TIMEOUT_CODE: i64 = -32090;
+pub const REQUEST_TOO_LARGE_CODE: i64 = -32091;
+pub const RESPONSE_TOO_LARGE_CODE: i64 = -32092;
+
 // TODO:
 #[derive(Debug, Display, Error, From)]
 pub enum Web3ProxyError {
@@ -36,6 +41,13 @@ pub enum Web3ProxyError {
     #[from(ignore)]
     BadRequest(String),
     BadRouting,
+    #[display(fmt = "{} > {}", size, max)]
+    #[from(ignore)]
+    BatchTooLarge {
+        size: usize,
+        max: usize,
+    },
+    ChainSplit,
     Database(DbErr),
     #[display(fmt = "{:#?}, {:#?}", _0, _1)]
     EipVerificationFailed(Box<Web3ProxyError>, Box<Web3ProxyError>),
@@ -101,6 +113,23 @@ pub enum Web3ProxyError {
     #[error(ignore)]
     #[from(ignore)]
     RefererNotAllowed(headers::Referer),
+    /// the user tier's request timeout
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    RequestTimeout(std::time::Duration),
+    #[display(fmt = "{} > {}", size, max)]
+    #[from(ignore)]
+    RequestTooLarge {
+        size: u64,
+        max: u64,
+    },
+    #[display(fmt = "{} > {}", size, max)]
+    #[from(ignore)]
+    ResponseTooLarge {
+        size: u64,
+        max: u64,
+    },
     SemaphoreAcquireError(AcquireError),
     SendAppStatError(flume::SendError<crate::stats::AppStat>),
     SerdeJson(serde_json::Error),
@@ -111,6 +140,11 @@ pub enum Web3ProxyError {
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     Timeout(Option<tokio::time::error::Elapsed>),
+    /// revert protection simulated the transaction and it reverted
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    TransactionWouldRevert(JsonRpcErrorData),
     UlidDecode(ulid::DecodeError),
     UnknownBlockNumber,
     UnknownKey,
@@ -177,6 +211,31 @@ impl Web3ProxyError {
                     ),
                 )
             }
+            Self::BatchTooLarge { size, max } => {
+                debug!("BatchTooLarge {} > {}", size, max);
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcForwardedResponse::from_string(
+                        format!(
+                            "batch of {} requests is larger than the max of {}",
+                            size, max
+                        ),
+                        Some(StatusCode::PAYLOAD_TOO_LARGE.as_u16().into()),
+                        None,
+                    ),
+                )
+            }
+            Self::ChainSplit => {
+                warn!("transaction paused by a chain split");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcForwardedResponse::from_str(
+                        "chain split detected. transactions are paused",
+                        Some(StatusCode::SERVICE_UNAVAILABLE.as_u16().into()),
+                        None,
+                    ),
+                )
+            }
             Self::Database(err) => {
                 error!("database err={:?}", err);
                 (
@@ -639,6 +698,45 @@ impl Web3ProxyError {
                     ),
                 )
             }
+            Self::RequestTimeout(max) => {
+                debug!("RequestTimeout {:?}", max);
+                (
+                    StatusCode::REQUEST_TIMEOUT,
+                    JsonRpcForwardedResponse::from_string(
+                        format!("request took longer than the max of {}s", max.as_secs_f32()),
+                        Some(REQUEST_TIMEOUT_CODE),
+                        None,
+                    ),
+                )
+            }
+            Self::RequestTooLarge { size, max } => {
+                debug!("RequestTooLarge {} > {}", size, max);
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcForwardedResponse::from_string(
+                        format!(
+                            "request of {} bytes is larger than the max of {} bytes",
+                            size, max
+                        ),
+                        Some(REQUEST_TOO_LARGE_CODE),
+                        None,
+                    ),
+                )
+            }
+            Self::ResponseTooLarge { size, max } => {
+                debug!("ResponseTooLarge {} > {}", size, max);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcForwardedResponse::from_string(
+                        format!(
+                            "response of {} bytes is larger than the max of {} bytes. try a smaller request",
+                            size, max
+                        ),
+                        Some(RESPONSE_TOO_LARGE_CODE),
+                        None,
+                    ),
+                )
+            }
             Self::Arc(err) => match Arc::try_unwrap(err) {
                 Ok(err) => err,
                 Err(err) => Self::Anyhow(anyhow::anyhow!("{}", err)),
@@ -701,6 +799,23 @@ impl Web3ProxyError {
                     None,
                 ),
             ),
+            Self::TransactionWouldRevert(err) => {
+                trace!("TransactionWouldRevert: {:?}", err);
+
+                let message = match err.revert_reason() {
+                    Some(reason) => format!("transaction would revert: {}", reason),
+                    None => "transaction would revert".to_string(),
+                };
+
+                // 3 is the code geth uses for reverts. keep the data so wallets can decode custom errors
+                let mut response = JsonRpcForwardedResponse::from_string(message, Some(3), None);
+
+                if let Some(error) = response.error.as_mut() {
+                    error.data = err.data;
+                }
+
+                (StatusCode::BAD_REQUEST, response)
+            }
             Self::HeaderToString(err) => {
                 // trace!(?err, "HeaderToString");
                 (
@@ -4,6 +4,7 @@
 
 pub mod admin;
 pub mod authorization;
+pub mod compression;
 pub mod errors;
 // TODO: these are only public so docs are generated. What's a better way to do this?
 pub mod rpc_proxy_http;
@@ -13,6 +14,7 @@ pub mod users;
 
 use crate::app::Web3ProxyApp;
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{get, post, put},
     Extension, Router,
 };
@@ -54,6 +56,12 @@ pub async fn serve(
         .time_to_live(Duration::from_millis(100))
         .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+    // the largest request body anyone can send. user tiers can have lower limits
+    let body_limit = match proxy_app.config.max_request_bytes {
+        0 => DefaultBodyLimit::disable(),
+        x => DefaultBodyLimit::max(x as usize),
+    };
+
     // TODO: read config for if fastest/versus should be available publicly. default off
 
     // build our axum Router
@@ -107,6 +115,17 @@ pub async fn serve(
             post(rpc_proxy_http::versus_proxy_web3_rpc_with_key),
         )
         //
+        // HTTP long-poll for transaction receipts (GET)
+        //
+        .route(
+            "/tx/:tx_hash/receipt",
+            get(rpc_proxy_http::wait_for_receipt),
+        )
+        .route(
+            "/rpc/:rpc_key/tx/:tx_hash/receipt",
+            get(rpc_proxy_http::wait_for_receipt_with_key),
+        )
+        //
         // Websocket RPC (GET)
         // If not an RPC, this will redirect to configurable urls
         //
@@ -182,6 +201,7 @@ pub async fn serve(
         .route("/user/keys", post(users::rpc_keys_management))
         .route("/user/keys", put(users::rpc_keys_management))
         .route("/user/revert_logs", get(users::user_revert_logs_get))
+        .route("/user/tx/:tx_hash", get(users::user_private_tx_get))
         .route(
             "/user/stats/aggregate",
             get(users::user_stats_aggregated_get),
@@ -212,6 +232,20 @@ pub async fn serve(
         .layer(SetSensitiveRequestHeadersLayer::new(once(AUTHORIZATION)))
         // handle cors
         .layer(CorsLayer::very_permissive())
+        // limit request bodies. compressed bodies are limited after they are decompressed
+        .layer(body_limit)
+        // gzip, brotli, and zstd
+        .layer(compression::compression_layer(
+            &proxy_app.config.compression,
+        ))
+        .layer(compression::decompression_layer(
+            &proxy_app.config.compression,
+            proxy_app.config.max_request_bytes,
+        ))
+        // count the bytes that are actually sent and received. this must be outside of the compression layers
+        .layer(compression::WireBytesLayer::new(
+            proxy_app.stat_sender.clone(),
+        ))
         // application state
         .layer(Extension(proxy_app.clone()))
         // frontend caches
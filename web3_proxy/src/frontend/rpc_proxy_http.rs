@@ -1,16 +1,25 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
 use super::authorization::{ip_is_authorized, key_is_authorized};
-use super::errors::Web3ProxyResponse;
+use super::compression::WireBytes;
+use super::errors::{Web3ProxyResponse, Web3ProxyResult};
 use super::rpc_proxy_ws::ProxyMode;
-use crate::{app::Web3ProxyApp, jsonrpc::JsonRpcRequestEnum};
-use axum::extract::Path;
+use crate::app::Web3ProxyApp;
+use crate::jsonrpc::{
+    CacheControl, JsonRpcForwardedResponseEnum, JsonRpcId, JsonRpcRequest, JsonRpcRequestEnum,
+    JsonRpcResponseMetadata,
+};
+use axum::extract::{Path, Query};
 use axum::headers::{Origin, Referer, UserAgent};
+use axum::http::header::{CACHE_CONTROL, CONTENT_LENGTH};
+use axum::http::{HeaderMap, HeaderValue};
 use axum::TypedHeader;
 use axum::{response::IntoResponse, Extension, Json};
 use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
 
 /// POST /rpc -- Public entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
@@ -21,9 +30,20 @@ pub async fn proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Best).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        headers,
+        wire_bytes,
+        payload,
+        ProxyMode::Best,
+    )
+    .await
 }
 
 #[debug_handler]
@@ -31,11 +51,22 @@ pub async fn fastest_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
     // TODO: read the fastest number from params
     // TODO: check that the app allows this without authentication
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Fastest(0)).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        headers,
+        wire_bytes,
+        payload,
+        ProxyMode::Fastest(0),
+    )
+    .await
 }
 
 #[debug_handler]
@@ -43,35 +74,202 @@ pub async fn versus_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Versus).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        headers,
+        wire_bytes,
+        payload,
+        ProxyMode::Versus,
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaitForReceiptQuery {
+    /// seconds
+    timeout: Option<u64>,
+}
+
+/// The long-poll routes are a GET wrapper around web3proxy_waitForTransactionReceipt
+fn wait_for_receipt_request(
+    tx_hash: String,
+    query: WaitForReceiptQuery,
+) -> Web3ProxyResult<JsonRpcRequestEnum> {
+    let params = match query.timeout {
+        Some(timeout) => json!([tx_hash, timeout]),
+        None => json!([tx_hash]),
+    };
+
+    let request = JsonRpcRequest::new(
+        JsonRpcId::Number(1),
+        "web3proxy_waitForTransactionReceipt".to_string(),
+        Some(params),
+    )?;
+
+    Ok(request.into())
+}
+
+/// GET /tx/:tx_hash/receipt -- Public long-poll for a transaction receipt.
+/// Responds as soon as the transaction is mined. The result is null if the timeout is reached first.
+#[debug_handler]
+pub async fn wait_for_receipt(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
+    Path(tx_hash): Path<String>,
+    Query(query): Query<WaitForReceiptQuery>,
+) -> Web3ProxyResponse {
+    let payload = wait_for_receipt_request(tx_hash, query)?;
+
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        headers,
+        wire_bytes,
+        payload,
+        ProxyMode::Best,
+    )
+    .await
+}
+
+/// `Cache-Control: no-cache` or `web3proxy-cache: bypass` skip the response cache for every request in the payload.
+/// Single requests always ask for metadata so that it can be sent as headers.
+/// Returns true if the request asked for the metadata in the body, too
+fn apply_request_headers(headers: &HeaderMap, payload: &mut JsonRpcRequestEnum) -> bool {
+    let no_cache = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case("no-cache"));
+
+    let bypass = headers
+        .get("web3proxy-cache")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.trim().eq_ignore_ascii_case("bypass"))
+        .unwrap_or(false);
+
+    let requests = match payload {
+        JsonRpcRequestEnum::Single(x) => std::slice::from_mut(x),
+        JsonRpcRequestEnum::Batch(x) => x.as_mut_slice(),
+    };
+
+    if no_cache || bypass {
+        for request in requests.iter_mut() {
+            request.web3proxy.cache = CacheControl::Bypass;
+        }
+    }
+
+    match payload {
+        JsonRpcRequestEnum::Single(x) => {
+            let metadata_in_body = x.web3proxy.metadata;
+
+            x.web3proxy.metadata = true;
+
+            metadata_in_body
+        }
+        // batches only have in-band metadata
+        JsonRpcRequestEnum::Batch(_) => true,
+    }
+}
+
+/// A single response's metadata. It stays in the body only if the request asked for it there
+fn take_response_metadata(
+    response: &mut JsonRpcForwardedResponseEnum,
+    metadata_in_body: bool,
+) -> Option<JsonRpcResponseMetadata> {
+    match response {
+        JsonRpcForwardedResponseEnum::Single(x) if metadata_in_body => x.web3proxy.clone(),
+        JsonRpcForwardedResponseEnum::Single(x) => x.web3proxy.take(),
+        JsonRpcForwardedResponseEnum::Batch(_) => None,
+    }
+}
+
+fn insert_metadata_headers(headers: &mut HeaderMap, metadata: Option<JsonRpcResponseMetadata>) {
+    let metadata = match metadata {
+        Some(x) => x,
+        None => return,
+    };
+
+    headers.insert(
+        "X-W3P-Cache",
+        HeaderValue::from_static(metadata.cache.as_str()),
+    );
+
+    if let Some(block) = metadata.block {
+        headers.insert(
+            "X-W3P-Block",
+            block
+                .as_u64()
+                .to_string()
+                .parse()
+                .expect("X-W3P-Block should always parse"),
+        );
+    }
+
+    // the backends are already in X-W3P-BACKEND-RPCS
+}
+
+/// Content-Length if the client sent it. Otherwise, the size of the parsed payload
+fn request_bytes(headers: &HeaderMap, payload: &JsonRpcRequestEnum) -> usize {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| payload.num_bytes())
 }
 
 async fn _proxy_web3_rpc(
     app: Arc<Web3ProxyApp>,
     InsecureClientIp(ip): InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
-    payload: JsonRpcRequestEnum,
+    headers: HeaderMap,
+    wire_bytes: Arc<WireBytes>,
+    mut payload: JsonRpcRequestEnum,
     proxy_mode: ProxyMode,
 ) -> Web3ProxyResponse {
     // TODO: benchmark spawning this
     // TODO: do we care about keeping the TypedHeader wrapper?
     let origin = origin.map(|x| x.0);
 
-    let (authorization, semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
+    let (mut authorization, semaphore) = ip_is_authorized(
+        &app,
+        ip,
+        origin,
+        proxy_mode,
+        payload.num_requests(),
+        request_bytes(&headers, &payload),
+    )
+    .await?;
+
+    authorization.wire_bytes = Some(wire_bytes);
 
     let authorization = Arc::new(authorization);
 
-    let (response, rpcs, _semaphore) = app
+    let metadata_in_body = apply_request_headers(&headers, &mut payload);
+
+    let (mut response, rpcs, _semaphore) = app
         .proxy_web3_rpc(authorization, payload)
         .await
         .map(|(x, y)| (x, y, semaphore))?;
 
+    let metadata = take_response_metadata(&mut response, metadata_in_body);
+
     let mut response = Json(&response).into_response();
 
     let headers = response.headers_mut();
 
+    insert_metadata_headers(headers, metadata);
+
     // TODO: this might be slow. think about this more
     // TODO: special string if no rpcs were used (cache hit)?
     let mut backup_used = false;
@@ -113,6 +311,8 @@ pub async fn proxy_web3_rpc_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
@@ -122,6 +322,8 @@ pub async fn proxy_web3_rpc_with_key(
         origin,
         referer,
         user_agent,
+        headers,
+        wire_bytes,
         rpc_key,
         payload,
         ProxyMode::Best,
@@ -136,6 +338,8 @@ pub async fn debug_proxy_web3_rpc_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
@@ -145,6 +349,8 @@ pub async fn debug_proxy_web3_rpc_with_key(
         origin,
         referer,
         user_agent,
+        headers,
+        wire_bytes,
         rpc_key,
         payload,
         ProxyMode::Debug,
@@ -159,6 +365,8 @@ pub async fn fastest_proxy_web3_rpc_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
@@ -168,6 +376,8 @@ pub async fn fastest_proxy_web3_rpc_with_key(
         origin,
         referer,
         user_agent,
+        headers,
+        wire_bytes,
         rpc_key,
         payload,
         ProxyMode::Fastest(0),
@@ -182,6 +392,8 @@ pub async fn versus_proxy_web3_rpc_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Web3ProxyResponse {
@@ -191,6 +403,8 @@ pub async fn versus_proxy_web3_rpc_with_key(
         origin,
         referer,
         user_agent,
+        headers,
+        wire_bytes,
         rpc_key,
         payload,
         ProxyMode::Versus,
@@ -198,6 +412,36 @@ pub async fn versus_proxy_web3_rpc_with_key(
     .await
 }
 
+/// GET /rpc/:rpc_key/tx/:tx_hash/receipt -- Authenticated long-poll for a transaction receipt.
+#[debug_handler]
+pub async fn wait_for_receipt_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Extension(wire_bytes): Extension<Arc<WireBytes>>,
+    Path((rpc_key, tx_hash)): Path<(String, String)>,
+    Query(query): Query<WaitForReceiptQuery>,
+) -> Web3ProxyResponse {
+    let payload = wait_for_receipt_request(tx_hash, query)?;
+
+    _proxy_web3_rpc_with_key(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        headers,
+        wire_bytes,
+        rpc_key,
+        payload,
+        ProxyMode::Best,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn _proxy_web3_rpc_with_key(
     app: Arc<Web3ProxyApp>,
@@ -205,15 +449,17 @@ async fn _proxy_web3_rpc_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    wire_bytes: Arc<WireBytes>,
     rpc_key: String,
-    payload: JsonRpcRequestEnum,
+    mut payload: JsonRpcRequestEnum,
     proxy_mode: ProxyMode,
 ) -> Web3ProxyResponse {
     // TODO: DRY w/ proxy_web3_rpc
     // the request can take a while, so we spawn so that we can start serving another request
     let rpc_key = rpc_key.parse()?;
 
-    let (authorization, semaphore) = key_is_authorized(
+    let (mut authorization, semaphore) = key_is_authorized(
         &app,
         rpc_key,
         ip,
@@ -221,22 +467,32 @@ async fn _proxy_web3_rpc_with_key(
         proxy_mode,
         referer.map(|x| x.0),
         user_agent.map(|x| x.0),
+        payload.num_requests(),
+        request_bytes(&headers, &payload),
     )
     .await?;
 
+    authorization.wire_bytes = Some(wire_bytes);
+
     let authorization = Arc::new(authorization);
 
     let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
 
-    let (response, rpcs, _semaphore) = app
+    let metadata_in_body = apply_request_headers(&headers, &mut payload);
+
+    let (mut response, rpcs, _semaphore) = app
         .proxy_web3_rpc(authorization, payload)
         .await
         .map(|(x, y)| (x, y, semaphore))?;
 
+    let metadata = take_response_metadata(&mut response, metadata_in_body);
+
     let mut response = Json(&response).into_response();
 
     let headers = response.headers_mut();
 
+    insert_metadata_headers(headers, metadata);
+
     let mut backup_used = false;
 
     // TODO: special string if no rpcs were used (cache hit)? or is an empty string fine? maybe the rpc name + "cached"
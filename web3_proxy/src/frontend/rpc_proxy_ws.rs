@@ -9,7 +9,9 @@ use crate::stats::RpcQueryStats;
 use crate::{
     app::Web3ProxyApp,
     frontend::errors::Web3ProxyResult,
-    jsonrpc::{JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest},
+    jsonrpc::{
+        JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest, JsonRpcRequestEnum,
+    },
 };
 use axum::headers::{Origin, Referer, UserAgent};
 use axum::{
@@ -100,12 +102,12 @@ async fn _websocket_handler(
 ) -> Web3ProxyResponse {
     let origin = origin.map(|x| x.0);
 
-    let (authorization, _semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
+    let (authorization, _semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode, 1, 0).await?;
 
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
-        Some(ws) => Ok(ws
+        Some(ws) => Ok(limit_message_size(&app, ws)
             .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket))
             .into_response()),
         None => {
@@ -236,6 +238,8 @@ async fn _websocket_handler_with_key(
         proxy_mode,
         referer.map(|x| x.0),
         user_agent.map(|x| x.0),
+        1,
+        0,
     )
     .await?;
 
@@ -244,9 +248,8 @@ async fn _websocket_handler_with_key(
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
-        Some(ws_upgrade) => {
-            Ok(ws_upgrade.on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket)))
-        }
+        Some(ws_upgrade) => Ok(limit_message_size(&app, ws_upgrade)
+            .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket))),
         None => {
             // if no websocket upgrade, this is probably a user loading the url with their browser
 
@@ -298,6 +301,16 @@ async fn _websocket_handler_with_key(
     }
 }
 
+/// the largest message anyone can send. user tiers can have lower limits
+/// TODO: permessage-deflate (see TODO.md). tungstenite can't do it, so the extension is never accepted
+/// and websocket stats have the same wire and logical bytes
+fn limit_message_size(app: &Web3ProxyApp, ws: WebSocketUpgrade) -> WebSocketUpgrade {
+    match app.config.max_request_bytes {
+        0 => ws,
+        x => ws.max_message_size(x as usize),
+    }
+}
+
 async fn proxy_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
@@ -322,7 +335,22 @@ async fn handle_socket_payload(
     subscription_count: &AtomicUsize,
     subscriptions: Arc<RwLock<HashMap<String, AbortHandle>>>,
 ) -> (Message, Option<OwnedSemaphorePermit>) {
-    let (authorization, semaphore) = match authorization.check_again(&app).await {
+    let request_bytes = payload.len();
+
+    // batches are arrays. everything else is parsed as a single request
+    let payload = if payload.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<JsonRpcRequest>>(payload).map(JsonRpcRequestEnum::Batch)
+    } else {
+        serde_json::from_str::<JsonRpcRequest>(payload).map(JsonRpcRequestEnum::Single)
+    };
+
+    // every request in a batch counts against the rate limits
+    let num_requests = payload.as_ref().map(|x| x.num_requests()).unwrap_or(1);
+
+    let (authorization, semaphore) = match authorization
+        .check_again(&app, num_requests, request_bytes)
+        .await
+    {
         Ok((a, s)) => (a, s),
         Err(err) => {
             let (_, err) = err.into_response_parts();
@@ -333,9 +361,8 @@ async fn handle_socket_payload(
         }
     };
 
-    // TODO: do any clients send batches over websockets?
-    let (id, response) = match serde_json::from_str::<JsonRpcRequest>(payload) {
-        Ok(json_request) => {
+    let (id, response) = match payload {
+        Ok(JsonRpcRequestEnum::Single(json_request)) => {
             let id = json_request.id.clone();
 
             let response: Web3ProxyResult<JsonRpcForwardedResponseEnum> = match &json_request.method
@@ -419,6 +446,17 @@ async fn handle_socket_payload(
 
             (id, response)
         }
+        Ok(JsonRpcRequestEnum::Batch(json_requests)) => {
+            // subscriptions only work as single requests
+            let id = JsonRpcId::None.to_raw_value();
+
+            let response = app
+                .proxy_web3_rpc(authorization.clone(), json_requests.into())
+                .await
+                .map(|(response, _)| response);
+
+            (id, response)
+        }
         Err(err) => {
             let id = JsonRpcId::None.to_raw_value();
             (id, Err(err.into()))
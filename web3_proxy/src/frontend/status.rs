@@ -66,6 +66,7 @@ pub async fn status(
             let body = json!({
                 "version": APP_USER_AGENT,
                 "chain_id": app.config.chain_id,
+                "archive_depth": app.archive_depth(),
                 "balanced_rpcs": app.balanced_rpcs,
                 "private_rpcs": app.private_rpcs,
                 "hostname": app.hostname,
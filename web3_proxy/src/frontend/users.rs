@@ -21,7 +21,10 @@ use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
 use entities::sea_orm_active_enums::TrackingLevel;
 use entities::{login, pending_login, revert_log, rpc_key, user};
-use ethers::{prelude::Address, types::Bytes};
+use ethers::{
+    prelude::{Address, TxHash},
+    types::Bytes,
+};
 use hashbrown::HashMap;
 use http::{HeaderValue, StatusCode};
 use ipnet::IpNet;
@@ -805,6 +808,22 @@ pub async fn user_revert_logs_get(
     Ok(Json(response).into_response())
 }
 
+/// `GET /user/tx/:tx_hash` -- Use a bearer token to get the status of a transaction sent to the private rpcs.
+#[debug_handler]
+pub async fn user_private_tx_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(tx_hash): Path<TxHash>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let status = app
+        .private_tx_status(user.id, &tx_hash)
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    Ok(Json(status).into_response())
+}
+
 /// `GET /user/stats/aggregate` -- Public endpoint for aggregate stats such as bandwidth used and methods requested.
 #[debug_handler]
 pub async fn user_stats_aggregated_get(
@@ -1,6 +1,8 @@
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::rpcs::request::TierLimit;
 use derive_more::From;
-use ethers::prelude::ProviderError;
+use ethers::abi::{self, ParamType};
+use ethers::prelude::{Bytes, ProviderError, U64};
 use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -20,6 +22,60 @@ pub struct JsonRpcRequest {
     pub id: Box<RawValue>,
     pub method: String,
     pub params: Option<serde_json::Value>,
+    /// options for the proxy. never sent to the backends
+    #[serde(default, skip_serializing)]
+    pub web3proxy: JsonRpcRequestOptions,
+}
+
+/// Per-request options for the proxy.
+/// Websockets and batches can't have per-request headers, so these go in the request object
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq)]
+#[serde(default)]
+pub struct JsonRpcRequestOptions {
+    pub cache: CacheControl,
+    /// include `JsonRpcResponseMetadata` in the response
+    pub metadata: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheControl {
+    #[default]
+    Default,
+    /// skip the response cache and get a fresh response from a backend
+    Bypass,
+}
+
+/// How a response was served
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CacheStatus {
+    /// served from the response cache or the call cache
+    Hit,
+    Miss,
+    /// the request asked to skip the cache
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Bypass => "BYPASS",
+        }
+    }
+}
+
+/// Where a response came from. HTTP responses send this as `X-W3P-*` headers
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct JsonRpcResponseMetadata {
+    pub cache: CacheStatus,
+    /// the block that the response cache used for this request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block: Option<U64>,
+    /// names of the backend rpcs that served this response
+    pub backends: Vec<String>,
 }
 
 #[derive(From)]
@@ -55,6 +111,7 @@ impl JsonRpcRequest {
             id: id.to_raw_value(),
             method,
             params,
+            web3proxy: Default::default(),
         };
 
         Ok(x)
@@ -80,6 +137,24 @@ pub enum JsonRpcRequestEnum {
     Single(JsonRpcRequest),
 }
 
+impl JsonRpcRequestEnum {
+    /// every request in a batch counts against the rate limits
+    pub fn num_requests(&self) -> u64 {
+        match self {
+            Self::Batch(x) => x.len() as u64,
+            Self::Single(_) => 1,
+        }
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        match self {
+            Self::Single(x) => x.num_bytes(),
+            // the brackets and commas
+            Self::Batch(x) => 1 + x.iter().map(|x| x.num_bytes() + 1).sum::<usize>().max(1),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -92,6 +167,7 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
             Id,
             Method,
             Params,
+            Web3Proxy,
             // TODO: jsonrpc here, too?
         }
 
@@ -128,6 +204,7 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                 let mut id = None;
                 let mut method = None;
                 let mut params = None;
+                let mut web3proxy = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -155,6 +232,12 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                             }
                             params = Some(map.next_value()?);
                         }
+                        Field::Web3Proxy => {
+                            if web3proxy.is_some() {
+                                return Err(de::Error::duplicate_field("web3proxy"));
+                            }
+                            web3proxy = Some(map.next_value()?);
+                        }
                     }
                 }
 
@@ -175,6 +258,7 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                     id,
                     method,
                     params,
+                    web3proxy: web3proxy.unwrap_or_default(),
                 };
 
                 Ok(JsonRpcRequestEnum::Single(single))
@@ -200,6 +284,30 @@ pub struct JsonRpcErrorData {
     pub data: Option<serde_json::Value>,
 }
 
+impl JsonRpcErrorData {
+    /// geth and erigon both start their revert messages like this
+    pub fn is_revert(&self) -> bool {
+        self.message.starts_with("execution reverted")
+    }
+
+    /// The reason from `require(..., "reason")` or `revert("reason")` (if any)
+    pub fn revert_reason(&self) -> Option<String> {
+        if let Some(reason) = self.message.strip_prefix("execution reverted: ") {
+            return Some(reason.to_string());
+        }
+
+        // the message doesn't always include it. decode it from `Error(string)`
+        let data: Bytes = self.data.as_ref()?.as_str()?.parse().ok()?;
+
+        let encoded = data.strip_prefix(&[0x08, 0xc3, 0x79, 0xa0])?;
+
+        abi::decode(&[ParamType::String], encoded)
+            .ok()?
+            .pop()?
+            .into_string()
+    }
+}
+
 /// A complete response
 /// TODO: better Debug response
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -212,6 +320,9 @@ pub struct JsonRpcForwardedResponse {
     pub result: Option<Box<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<JsonRpcErrorData>,
+    /// only included if the request asked for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web3proxy: Option<JsonRpcResponseMetadata>,
 }
 
 impl JsonRpcRequest {
@@ -224,6 +335,35 @@ impl JsonRpcRequest {
 }
 
 impl JsonRpcForwardedResponse {
+    /// The length of the serialized response.
+    /// The result is already serialized, so this avoids serializing large responses again
+    pub fn num_bytes(&self) -> usize {
+        // {"jsonrpc":"","id":}
+        let mut x = 20 + self.jsonrpc.len() + self.id.get().len();
+
+        if let Some(result) = self.result.as_ref() {
+            // ,"result":
+            x += 10 + result.get().len();
+        }
+
+        if let Some(error) = self.error.as_ref() {
+            // ,"error":
+            x += 9 + serde_json::to_string(error)
+                .expect("this should always be valid json")
+                .len();
+        }
+
+        if let Some(metadata) = self.web3proxy.as_ref() {
+            // ,"web3proxy":
+            x += 13
+                + serde_json::to_string(metadata)
+                    .expect("this should always be valid json")
+                    .len();
+        }
+
+        x
+    }
+
     pub fn from_anyhow_error(
         err: anyhow::Error,
         code: Option<i64>,
@@ -251,6 +391,7 @@ impl JsonRpcForwardedResponse {
                 // TODO: accept data as an argument
                 data: None,
             }),
+            web3proxy: None,
         }
     }
 
@@ -261,6 +402,7 @@ impl JsonRpcForwardedResponse {
             // TODO: since we only use the result here, should that be all we return from try_send_request?
             result: Some(partial_response),
             error: None,
+            web3proxy: None,
         }
     }
 
@@ -273,6 +415,7 @@ impl JsonRpcForwardedResponse {
             id,
             result: Some(partial_response),
             error: None,
+            web3proxy: None,
         }
     }
 
@@ -284,6 +427,11 @@ impl JsonRpcForwardedResponse {
 
         match e {
             ProviderError::JsonRpcClientError(err) => {
+                if let Some(limit) = TierLimit::from_rpc_error(err.as_ref()) {
+                    // not from the backend. don't send it like it was
+                    return Err(limit.into());
+                }
+
                 if let Some(err) = err.as_error_response() {
                     code = err.code;
                     message = err.message.clone();
@@ -307,6 +455,7 @@ impl JsonRpcForwardedResponse {
                 message,
                 data,
             }),
+            web3proxy: None,
         })
     }
 
@@ -319,13 +468,6 @@ impl JsonRpcForwardedResponse {
             Err(e) => Self::from_ethers_error(e, id),
         }
     }
-
-    pub fn num_bytes(&self) -> usize {
-        // TODO: not sure how to do this without wasting a ton of allocations
-        serde_json::to_string(self)
-            .expect("this should always be valid json")
-            .len()
-    }
 }
 
 /// JSONRPC Responses can include one or many response objects.
@@ -336,6 +478,16 @@ pub enum JsonRpcForwardedResponseEnum {
     Batch(Vec<JsonRpcForwardedResponse>),
 }
 
+impl JsonRpcForwardedResponseEnum {
+    pub fn num_bytes(&self) -> usize {
+        match self {
+            Self::Single(x) => x.num_bytes(),
+            // the brackets and commas
+            Self::Batch(x) => 1 + x.iter().map(|x| x.num_bytes() + 1).sum::<usize>().max(1),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -357,6 +509,141 @@ mod tests {
         assert!(matches!(output, JsonRpcRequestEnum::Single(_)));
     }
 
+    #[test]
+    fn this_revert_reason() {
+        let with_message = JsonRpcErrorData {
+            code: 3,
+            message: "execution reverted: not enough balance".to_string(),
+            data: None,
+        };
+
+        assert!(with_message.is_revert());
+        assert_eq!(
+            with_message.revert_reason().as_deref(),
+            Some("not enough balance")
+        );
+
+        // Error("nope")
+        let with_data = JsonRpcErrorData {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some(json!("0x08c379a0000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000000046e6f706500000000000000000000000000000000000000000000000000000000")),
+        };
+
+        assert!(with_data.is_revert());
+        assert_eq!(with_data.revert_reason().as_deref(), Some("nope"));
+
+        let not_a_revert = JsonRpcErrorData {
+            code: -32000,
+            message: "nonce too low".to_string(),
+            data: None,
+        };
+
+        assert!(!not_a_revert.is_revert());
+        assert_eq!(not_a_revert.revert_reason(), None);
+    }
+
+    #[test]
+    fn this_response_num_bytes() {
+        let result =
+            JsonRpcForwardedResponse::from_value(json!(["0x1", "0x2"]), Default::default());
+
+        assert_eq!(
+            result.num_bytes(),
+            serde_json::to_string(&result).unwrap().len()
+        );
+
+        let error = JsonRpcForwardedResponse::from_str("oops", Some(-32000), None);
+
+        assert_eq!(
+            error.num_bytes(),
+            serde_json::to_string(&error).unwrap().len()
+        );
+
+        let mut with_metadata = result;
+        with_metadata.web3proxy = Some(JsonRpcResponseMetadata {
+            cache: CacheStatus::Hit,
+            block: Some(16.into()),
+            backends: vec!["a".to_string(), "b".to_string()],
+        });
+
+        assert_eq!(
+            with_metadata.num_bytes(),
+            serde_json::to_string(&with_metadata).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn this_tier_limits_are_not_backend_errors() {
+        use crate::frontend::errors::REQUEST_TIMEOUT_CODE;
+        use ethers::providers::{JsonRpcError, RpcError};
+        use std::time::Duration;
+
+        /// a backend that happens to use the same code as our timeouts
+        #[derive(Debug)]
+        struct BackendError(JsonRpcError);
+
+        impl std::fmt::Display for BackendError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl std::error::Error for BackendError {}
+
+        impl RpcError for BackendError {
+            fn as_error_response(&self) -> Option<&JsonRpcError> {
+                Some(&self.0)
+            }
+
+            fn as_serde_error(&self) -> Option<&serde_json::Error> {
+                None
+            }
+        }
+
+        let backend_err = ProviderError::JsonRpcClientError(Box::new(BackendError(JsonRpcError {
+            code: REQUEST_TIMEOUT_CODE,
+            message: "request timed out".to_string(),
+            data: Some(json!({ "timeout_seconds": 1.0 })),
+        })));
+
+        let response =
+            JsonRpcForwardedResponse::from_ethers_error(backend_err, Default::default()).unwrap();
+
+        assert_eq!(response.error.unwrap().code, REQUEST_TIMEOUT_CODE);
+
+        let tier_err = TierLimit::Timeout(Duration::from_secs(1)).into();
+
+        assert!(matches!(
+            JsonRpcForwardedResponse::from_ethers_error(tier_err, Default::default()),
+            Err(Web3ProxyError::RequestTimeout(x)) if x == Duration::from_secs(1)
+        ));
+    }
+
+    #[test]
+    fn this_request_options() {
+        let input = r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":1,"web3proxy":{"cache":"bypass","metadata":true}}"#;
+
+        let expected = JsonRpcRequestOptions {
+            cache: CacheControl::Bypass,
+            metadata: true,
+        };
+
+        let output: JsonRpcRequest = serde_json::from_str(input).unwrap();
+        assert_eq!(output.web3proxy, expected);
+
+        // the options are never sent to the backends
+        assert!(!serde_json::to_string(&output)
+            .unwrap()
+            .contains("web3proxy"));
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+        match output {
+            JsonRpcRequestEnum::Single(x) => assert_eq!(x.web3proxy, expected),
+            JsonRpcRequestEnum::Batch(_) => panic!("expected a single request"),
+        }
+    }
+
     #[test]
     fn this_deserialize_batch() {
         let input = r#"[{"jsonrpc":"2.0","method":"eth_getCode","params":["0x5ba1e12693dc8f9c48aad8770482f4739beed696","0xe0e6a4"],"id":27},{"jsonrpc":"2.0","method":"eth_getTransactionCount","params":["0x5ba1e12693dc8f9c48aad8770482f4739beed696","0xe0e6a4"],"id":28},{"jsonrpc":"2.0","method":"eth_getBalance","params":["0x5ba1e12693dc8f9c48aad8770482f4739beed696","0xe0e6a4"],"id":29}]"#;
@@ -381,4 +668,25 @@ mod tests {
 
         assert!(matches!(output, JsonRpcRequestEnum::Batch(_)));
     }
+
+    #[test]
+    fn this_num_bytes() {
+        let input = r#"[{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":1},{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":2}]"#;
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        assert_eq!(output.num_bytes(), input.len());
+
+        let responses = JsonRpcForwardedResponseEnum::Batch(vec![
+            JsonRpcForwardedResponse::from_value(json!("0x1"), JsonRpcId::Number(1).to_raw_value()),
+            JsonRpcForwardedResponse::from_value(json!("0x2"), JsonRpcId::Number(2).to_raw_value()),
+        ]);
+
+        assert_eq!(
+            responses.num_bytes(),
+            serde_json::to_string(&responses).unwrap().len()
+        );
+
+        assert_eq!(JsonRpcForwardedResponseEnum::Batch(vec![]).num_bytes(), 2);
+    }
 }
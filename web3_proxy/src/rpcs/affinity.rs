@@ -0,0 +1,166 @@
+///! Send nonce-sensitive requests from the same key or sender to the same backend rpc.
+///!
+///! Different backends can have different pending nonces for a sender until transactions propagate.
+///! Sticking to one backend for a little while keeps `eth_getTransactionCount(addr, "pending")` consistent with recent sends.
+use super::transactions::decode_raw_transaction;
+use crate::config::{AffinityConfig, AffinityMode};
+use crate::frontend::authorization::Authorization;
+use ethers::prelude::Address;
+use moka::future::Cache;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum AffinityKey {
+    RpcKey(NonZeroU64),
+    Address(Address),
+}
+
+/// Remembers which backend rpc recently served the nonce-sensitive requests for a key
+pub struct BackendAffinity {
+    mode: AffinityMode,
+    /// the value is the name of the rpc
+    cache: Cache<AffinityKey, String, hashbrown::hash_map::DefaultHashBuilder>,
+}
+
+impl Default for BackendAffinity {
+    fn default() -> Self {
+        Self::new(&Default::default())
+    }
+}
+
+impl BackendAffinity {
+    pub fn new(config: &AffinityConfig) -> Self {
+        // all the values are about the same size, so no need for a weigher
+        let cache = Cache::builder()
+            .max_capacity(config.max_capacity)
+            .time_to_live(Duration::from_secs(config.ttl_seconds))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Self {
+            mode: config.mode,
+            cache,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.mode != AffinityMode::Off
+    }
+
+    /// The affinity key for a request. None if the request is not nonce-sensitive or has no key
+    pub fn key(
+        &self,
+        authorization: &Authorization,
+        method: &str,
+        params: Option<&serde_json::Value>,
+    ) -> Option<AffinityKey> {
+        if !is_nonce_sensitive(method, params) {
+            return None;
+        }
+
+        match self.mode {
+            AffinityMode::Off => None,
+            AffinityMode::RpcKey => authorization
+                .checks
+                .rpc_secret_key_id
+                .map(AffinityKey::RpcKey),
+            AffinityMode::Address => sender_address(method, params).map(AffinityKey::Address),
+        }
+    }
+
+    /// The name of the backend rpc that recently served this key
+    pub fn preferred(&self, key: &AffinityKey) -> Option<String> {
+        self.cache.get(key)
+    }
+
+    /// Remember the backend rpc that served this key. This also restarts the ttl
+    pub async fn record(&self, key: AffinityKey, rpc_name: String) {
+        self.cache.insert(key, rpc_name).await;
+    }
+}
+
+/// Requests that can see a different pending nonce depending on the backend
+fn is_nonce_sensitive(method: &str, params: Option<&serde_json::Value>) -> bool {
+    match method {
+        "eth_sendRawTransaction" => true,
+        // TODO: eth_call and eth_estimateGas with "pending" too?
+        "eth_getTransactionCount" => params
+            .and_then(|x| x.get(1))
+            .and_then(|x| x.as_str())
+            .map(|x| x == "pending")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// The sender of a transaction or the address whose nonce is being checked
+fn sender_address(method: &str, params: Option<&serde_json::Value>) -> Option<Address> {
+    match method {
+        "eth_getTransactionCount" => Address::from_str(params?.get(0)?.as_str()?).ok(),
+        "eth_sendRawTransaction" => {
+            // TODO: this is slow. maybe cache it by tx hash since we decode this again after the send
+            decode_raw_transaction(params)?.recover_from().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_nonce_sensitive() {
+        let addr = "0x00000000219ab540356cbb839cbe05303d7705fa";
+
+        assert!(is_nonce_sensitive(
+            "eth_getTransactionCount",
+            Some(&json!([addr, "pending"]))
+        ));
+        assert!(!is_nonce_sensitive(
+            "eth_getTransactionCount",
+            Some(&json!([addr, "latest"]))
+        ));
+        assert!(!is_nonce_sensitive("eth_getTransactionCount", None));
+        assert!(!is_nonce_sensitive(
+            "eth_getBalance",
+            Some(&json!([addr, "pending"]))
+        ));
+
+        assert_eq!(
+            sender_address("eth_getTransactionCount", Some(&json!([addr, "pending"]))),
+            Some(Address::from_str(addr).unwrap())
+        );
+        assert_eq!(
+            sender_address("eth_sendRawTransaction", Some(&json!(["0xdeadbeef"]))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_affinity() {
+        let affinity = BackendAffinity::new(&AffinityConfig {
+            mode: AffinityMode::Address,
+            ttl_seconds: 60,
+            max_capacity: 100,
+        });
+
+        assert!(affinity.enabled());
+
+        let key = AffinityKey::Address(Address::repeat_byte(1));
+
+        assert_eq!(affinity.preferred(&key), None);
+
+        affinity.record(key, "a".to_string()).await;
+        assert_eq!(affinity.preferred(&key), Some("a".to_string()));
+
+        // fallback moves the affinity to the new backend
+        affinity.record(key, "b".to_string()).await;
+        assert_eq!(affinity.preferred(&key), Some("b".to_string()));
+
+        assert!(!BackendAffinity::default().enabled());
+    }
+}
@@ -0,0 +1,106 @@
+///! Estimate a chain's block time from its consensus head blocks.
+use std::collections::VecDeque;
+use tokio::time::Duration;
+
+/// Block timestamps only have second precision. Averaging over many blocks gives sub-second block times
+const SAMPLES: usize = 64;
+
+/// L2s can have blocks faster than this, but polling faster than this isn't worth the requests
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Some chains only make blocks when there are transactions
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct BlockTimeEstimator {
+    /// (block number, timestamp) of recent consensus heads. oldest first
+    samples: VecDeque<(u64, u64)>,
+    estimate: Duration,
+}
+
+impl BlockTimeEstimator {
+    /// `initial` is used until there are enough heads to measure
+    pub fn new(initial: Duration) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(SAMPLES),
+            estimate: initial,
+        }
+    }
+
+    pub fn block_time(&self) -> Duration {
+        self.estimate
+    }
+
+    /// Record a new consensus head and return the new estimate
+    pub fn record(&mut self, number: u64, timestamp: u64) -> Duration {
+        if let Some(&(last_number, _)) = self.samples.back() {
+            if number == last_number {
+                return self.estimate;
+            }
+
+            if number < last_number {
+                // a reorg or a restarted chain. start over
+                self.samples.clear();
+            }
+        }
+
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back((number, timestamp));
+
+        let (first_number, first_timestamp) = self.samples[0];
+
+        let blocks = number - first_number;
+        let seconds = timestamp.saturating_sub(first_timestamp);
+
+        // fast chains have several blocks with the same timestamp. wait for more samples
+        if blocks > 0 && seconds > 0 {
+            self.estimate = Duration::from_secs_f64(seconds as f64 / blocks as f64);
+        }
+
+        self.estimate
+    }
+
+    /// Poll twice per block so new heads are seen quickly
+    pub fn poll_interval(&self) -> Duration {
+        (self.estimate / 2).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_time_estimator() {
+        let mut estimator = BlockTimeEstimator::new(Duration::from_secs(10));
+
+        assert_eq!(estimator.record(100, 1_000), Duration::from_secs(10));
+        assert_eq!(estimator.record(101, 1_012), Duration::from_secs(12));
+        assert_eq!(estimator.record(101, 1_012), Duration::from_secs(12));
+
+        // missed heads are fine
+        assert_eq!(estimator.record(104, 1_048), Duration::from_secs(12));
+        assert_eq!(estimator.poll_interval(), Duration::from_secs(6));
+
+        // a reorg starts over, but keeps the old estimate until there is a new one
+        assert_eq!(estimator.record(90, 900), Duration::from_secs(12));
+        assert_eq!(estimator.record(91, 902), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_sub_second_block_time() {
+        let mut estimator = BlockTimeEstimator::new(Duration::from_secs(10));
+
+        // 8 blocks per second
+        for i in 0..SAMPLES as u64 {
+            estimator.record(1_000 + i, 5_000 + i / 8);
+        }
+
+        let block_time = estimator.block_time().as_secs_f64();
+
+        assert!(block_time > 0.1 && block_time < 0.15, "{}", block_time);
+        assert_eq!(estimator.poll_interval(), MIN_POLL_INTERVAL);
+    }
+}
@@ -275,6 +275,14 @@ impl Web3Rpcs {
         Ok(block)
     }
 
+    /// Get the cannonical block at a given block height without asking any rpcs.
+    /// Blocks from subscriptions might not have their transactions.
+    pub fn cached_block_by_number(&self, num: &U64) -> Option<Web3ProxyBlock> {
+        let block_hash = self.blocks_by_number.get(num)?;
+
+        self.blocks_by_hash.get(&block_hash)
+    }
+
     /// Convenience method to get the cannonical block at a given block height.
     pub async fn block_hash(
         &self,
@@ -423,6 +431,10 @@ impl Web3Rpcs {
             return Ok(());
         }
 
+        // a chain split might be why there is no consensus. check before finding consensus
+        self.check_chain_split(authorization, consensus_finder)
+            .await;
+
         let new_synced_connections = match consensus_finder
             .find_consensus_connections(authorization, self)
             .await
@@ -0,0 +1,252 @@
+///! Track which rpc methods a backend supports.
+use ethers::providers::ProviderError;
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+
+/// Groups of methods that only some clients support
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MethodFamily {
+    /// `trace_*` (erigon, nethermind, openethereum)
+    Trace,
+    /// `debug_trace*` (geth and most forks)
+    DebugTrace,
+    /// `eth_getBlockReceipts`
+    BlockReceipts,
+    /// `erigon_*`
+    Erigon,
+    /// `ots_*` (otterscan)
+    Otterscan,
+}
+
+impl MethodFamily {
+    pub const ALL: [Self; 5] = [
+        Self::Trace,
+        Self::DebugTrace,
+        Self::BlockReceipts,
+        Self::Erigon,
+        Self::Otterscan,
+    ];
+
+    pub fn from_method(method: &str) -> Option<Self> {
+        if method.starts_with("trace_") {
+            Some(Self::Trace)
+        } else if method.starts_with("debug_trace") {
+            Some(Self::DebugTrace)
+        } else if method == "eth_getBlockReceipts" {
+            Some(Self::BlockReceipts)
+        } else if method.starts_with("erigon_") {
+            Some(Self::Erigon)
+        } else if method.starts_with("ots_") {
+            Some(Self::Otterscan)
+        } else {
+            None
+        }
+    }
+
+    /// A cheap request that only errors with "method not found" if the family is unsupported.
+    /// TODO: the genesis block is cheap to trace, but some chains start their nodes at a later block
+    pub fn probe(&self) -> (&'static str, serde_json::Value) {
+        match self {
+            Self::Trace => ("trace_block", json!(["0x0"])),
+            Self::DebugTrace => ("debug_traceBlockByNumber", json!(["0x0", {}])),
+            Self::BlockReceipts => ("eth_getBlockReceipts", json!(["0x0"])),
+            Self::Erigon => ("erigon_blockNumber", json!([])),
+            Self::Otterscan => ("ots_getApiLevel", json!([])),
+        }
+    }
+}
+
+impl fmt::Display for MethodFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Trace => write!(f, "trace_*"),
+            Self::DebugTrace => write!(f, "debug_trace*"),
+            Self::BlockReceipts => write!(f, "eth_getBlockReceipts"),
+            Self::Erigon => write!(f, "erigon_*"),
+            Self::Otterscan => write!(f, "ots_*"),
+        }
+    }
+}
+
+/// Check if an error means that the backend does not have the method at all.
+/// Any other error (bad params, pruned data, etc.) means the method exists.
+pub fn is_method_not_found(err: &ProviderError) -> bool {
+    let err = match err {
+        ProviderError::JsonRpcClientError(err) => err.as_error_response(),
+        _ => None,
+    };
+
+    if let Some(err) = err {
+        if err.code == -32601 {
+            return true;
+        }
+
+        let msg = err.message.to_lowercase();
+
+        // different clients word this differently
+        msg.contains("method")
+            && (msg.contains("not found")
+                || msg.contains("not available")
+                || msg.contains("does not exist")
+                || msg.contains("not supported"))
+    } else {
+        false
+    }
+}
+
+/// `pattern` is either an exact method name or a prefix ending in "*"
+fn method_matches(pattern: &str, method: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        method.starts_with(prefix)
+    } else {
+        pattern == method
+    }
+}
+
+/// What methods a Web3Rpc can serve. Configured lists always win over probed results.
+#[derive(Debug, Default)]
+pub struct MethodCapabilities {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    probed: RwLock<HashMap<MethodFamily, bool>>,
+}
+
+impl MethodCapabilities {
+    pub fn new(allowed: Vec<String>, denied: Vec<String>) -> Self {
+        Self {
+            allowed,
+            denied,
+            probed: Default::default(),
+        }
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        if self.denied.iter().any(|x| method_matches(x, method)) {
+            return false;
+        }
+
+        if self.allowed.iter().any(|x| method_matches(x, method)) {
+            return true;
+        }
+
+        // unknown methods and families that haven't been probed yet are assumed to work
+        // if they don't, the request will be retried on another server
+        MethodFamily::from_method(method)
+            .and_then(|family| self.probed.read().get(&family).copied())
+            .unwrap_or(true)
+    }
+
+    pub fn set_probed(&self, family: MethodFamily, supported: bool) {
+        self.probed.write().insert(family, supported);
+    }
+
+    /// The backend said it doesn't have this method.
+    /// Only the family's probe method marks the whole family. Otherwise one request for a made up method
+    /// like `trace_bogus` would turn off the family until the next reconnect
+    pub fn set_unsupported(&self, method: &str) -> Option<MethodFamily> {
+        let family = MethodFamily::from_method(method)?;
+
+        if family.probe().0 != method {
+            return None;
+        }
+
+        self.set_probed(family, false);
+
+        Some(family)
+    }
+}
+
+impl Serialize for MethodCapabilities {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("MethodCapabilities", 3)?;
+
+        state.serialize_field("allowed", &self.allowed)?;
+        state.serialize_field("denied", &self.denied)?;
+
+        let probed: HashMap<String, bool> = self
+            .probed
+            .read()
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+
+        state.serialize_field("probed", &probed)?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_family() {
+        assert_eq!(
+            MethodFamily::from_method("trace_block"),
+            Some(MethodFamily::Trace)
+        );
+        assert_eq!(
+            MethodFamily::from_method("debug_traceTransaction"),
+            Some(MethodFamily::DebugTrace)
+        );
+        assert_eq!(
+            MethodFamily::from_method("eth_getBlockReceipts"),
+            Some(MethodFamily::BlockReceipts)
+        );
+        assert_eq!(MethodFamily::from_method("eth_call"), None);
+        assert_eq!(MethodFamily::from_method("debug_getRawBlock"), None);
+    }
+
+    #[test]
+    fn test_probed_capabilities() {
+        let capabilities = MethodCapabilities::default();
+
+        // not probed yet
+        assert!(capabilities.supports("trace_block"));
+
+        capabilities.set_probed(MethodFamily::Trace, false);
+        capabilities.set_probed(MethodFamily::DebugTrace, true);
+
+        assert!(!capabilities.supports("trace_block"));
+        assert!(!capabilities.supports("trace_filter"));
+        assert!(capabilities.supports("debug_traceTransaction"));
+        assert!(capabilities.supports("eth_call"));
+
+        // a method that isn't the probe doesn't say anything about the rest of the family
+        assert_eq!(capabilities.set_unsupported("debug_traceBogus"), None);
+        assert!(capabilities.supports("debug_traceTransaction"));
+
+        assert_eq!(
+            capabilities.set_unsupported("debug_traceBlockByNumber"),
+            Some(MethodFamily::DebugTrace)
+        );
+        assert!(!capabilities.supports("debug_traceTransaction"));
+        assert_eq!(capabilities.set_unsupported("eth_call"), None);
+    }
+
+    #[test]
+    fn test_configured_capabilities() {
+        let capabilities = MethodCapabilities::new(
+            vec!["trace_*".to_string()],
+            vec!["eth_getBlockReceipts".to_string(), "ots_*".to_string()],
+        );
+
+        capabilities.set_probed(MethodFamily::Trace, false);
+        capabilities.set_probed(MethodFamily::BlockReceipts, true);
+
+        // config overrides the probes
+        assert!(capabilities.supports("trace_block"));
+        assert!(!capabilities.supports("eth_getBlockReceipts"));
+        assert!(!capabilities.supports("ots_getApiLevel"));
+        assert!(capabilities.supports("eth_blockNumber"));
+    }
+}
@@ -0,0 +1,306 @@
+///! Notice when the backend rpcs disagree about the chain for more than a few blocks.
+use super::blockchain::Web3ProxyBlock;
+use super::consensus::ConsensusFinder;
+use super::many::Web3Rpcs;
+use crate::config::{ChainSplitConfig, ChainSplitTxPolicy};
+use crate::frontend::authorization::Authorization;
+use crate::pagerduty::pagerduty_alert;
+use ethers::prelude::{H256, U64};
+use log::{error, info, warn};
+use pagerduty_rs::eventsv2async::EventsV2 as PagerdutyAsyncEventsV2;
+use pagerduty_rs::types::Event;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+
+/// Rpcs whose heads are all on the same chain
+#[derive(Clone, Debug, Serialize)]
+pub struct ForkSide {
+    pub head_hash: H256,
+    pub head_num: U64,
+    pub rpcs: Vec<String>,
+    pub soft_limit: u32,
+}
+
+impl ForkSide {
+    pub fn new(head: &Web3ProxyBlock) -> Self {
+        Self {
+            head_hash: *head.hash(),
+            head_num: *head.number(),
+            rpcs: vec![],
+            soft_limit: 0,
+        }
+    }
+}
+
+/// Two or more sides with significant soft limits that have disagreed for at least `min_blocks`
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainSplit {
+    /// the highest head when the sides first disagreed
+    pub first_seen_num: U64,
+    /// how many blocks the sides have disagreed for
+    pub blocks: u64,
+    /// heaviest side first
+    pub sides: Vec<ForkSide>,
+}
+
+/// The chain split detector's counters in a format that serde_prometheus likes
+#[derive(Serialize)]
+pub struct ChainSplitMetrics {
+    active: u8,
+    blocks: u64,
+    sides: usize,
+    detected: u64,
+}
+
+pub struct ChainSplitDetector {
+    config: ChainSplitConfig,
+    chain_id: u64,
+    pagerduty_async: Option<Arc<PagerdutyAsyncEventsV2>>,
+    /// the highest head when significant sides were first seen. not a split until it lasts `min_blocks`
+    first_seen_num: Mutex<Option<U64>>,
+    current: RwLock<Option<Arc<ChainSplit>>>,
+    /// TODO: maybe move this to graphana
+    detected: AtomicU64,
+}
+
+impl Default for ChainSplitDetector {
+    fn default() -> Self {
+        Self::new(Default::default(), 0, None)
+    }
+}
+
+impl ChainSplitDetector {
+    pub fn new(
+        config: ChainSplitConfig,
+        chain_id: u64,
+        pagerduty_async: Option<Arc<PagerdutyAsyncEventsV2>>,
+    ) -> Self {
+        Self {
+            config,
+            chain_id,
+            pagerduty_async,
+            first_seen_num: Mutex::new(None),
+            current: RwLock::new(None),
+            detected: 0.into(),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.config.enabled && self.config.min_soft_limit_percent > 0
+    }
+
+    pub fn tx_policy(&self) -> ChainSplitTxPolicy {
+        self.config.tx_policy
+    }
+
+    /// The current chain split (if any)
+    pub fn current(&self) -> Option<Arc<ChainSplit>> {
+        self.current.read().clone()
+    }
+
+    /// Only keep the sides with enough of the total soft limit. Heaviest first
+    fn significant_sides(&self, mut sides: Vec<ForkSide>) -> Vec<ForkSide> {
+        let total_soft_limit: u64 = sides.iter().map(|x| x.soft_limit as u64).sum();
+
+        sides.retain(|x| {
+            x.soft_limit as u64 * 100 >= total_soft_limit * self.config.min_soft_limit_percent
+        });
+
+        sides.sort_by_key(|x| std::cmp::Reverse(x.soft_limit));
+
+        sides
+    }
+
+    /// Update the state with the latest sides. Returns the split if one was just confirmed
+    pub fn update(&self, sides: Vec<ForkSide>) -> Option<Arc<ChainSplit>> {
+        let highest_num = sides.iter().map(|x| x.head_num).max()?;
+
+        let sides = self.significant_sides(sides);
+
+        let mut first_seen_num = self.first_seen_num.lock();
+
+        if sides.len() < 2 {
+            *first_seen_num = None;
+
+            if let Some(old) = self.current.write().take() {
+                info!(
+                    "chain split resolved after {} blocks. {:?}",
+                    highest_num.saturating_sub(old.first_seen_num),
+                    sides
+                );
+            }
+
+            return None;
+        }
+
+        let first_seen = *first_seen_num.get_or_insert(highest_num);
+
+        let blocks = highest_num.saturating_sub(first_seen).as_u64();
+
+        if blocks < self.config.min_blocks {
+            // heads are often a block apart for a moment. wait to see if it resolves
+            return None;
+        }
+
+        let split = Arc::new(ChainSplit {
+            first_seen_num: first_seen,
+            blocks,
+            sides,
+        });
+
+        let old = self.current.write().replace(split.clone());
+
+        if old.is_none() {
+            self.detected.fetch_add(1, atomic::Ordering::Relaxed);
+
+            Some(split)
+        } else {
+            None
+        }
+    }
+
+    /// Log the split and send it to pagerduty (if configured)
+    fn alert(&self, split: &ChainSplit) {
+        error!("chain split detected! {:?}", split);
+
+        if let Some(pagerduty_async) = self.pagerduty_async.clone() {
+            let alert = pagerduty_alert(
+                Some(self.chain_id),
+                Some("chain split".to_string()),
+                None,
+                None,
+                Some("consensus".to_string()),
+                Some(split.clone()),
+                pagerduty_rs::types::Severity::Error,
+                None,
+                format!("chain split with {} sides", split.sides.len()),
+                None,
+            );
+
+            tokio::spawn(async move {
+                if let Err(err) = pagerduty_async.event(Event::AlertTrigger(alert)).await {
+                    error!("Failed sending chain split to pagerduty! {:#?}", err);
+                }
+            });
+        }
+    }
+
+    pub fn metrics(&self) -> ChainSplitMetrics {
+        let current = self.current();
+
+        ChainSplitMetrics {
+            active: current.is_some() as u8,
+            blocks: current.as_ref().map(|x| x.blocks).unwrap_or_default(),
+            sides: current.as_ref().map(|x| x.sides.len()).unwrap_or_default(),
+            detected: self.detected.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl Web3Rpcs {
+    /// Group the rpc heads into sides and check them for a chain split
+    pub(super) async fn check_chain_split(
+        &self,
+        authorization: &Arc<Authorization>,
+        consensus_finder: &ConsensusFinder,
+    ) {
+        if !self.chain_split.enabled() {
+            return;
+        }
+
+        let sides = consensus_finder.fork_sides(authorization, self).await;
+
+        if let Some(split) = self.chain_split.update(sides) {
+            self.chain_split.alert(&split);
+
+            if self.chain_split.tx_policy() != ChainSplitTxPolicy::Allow {
+                warn!(
+                    "using the {:?} transaction policy until the chain split resolves",
+                    self.chain_split.tx_policy()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn side(head_num: u64, rpcs: &[&str], soft_limit: u32) -> ForkSide {
+        ForkSide {
+            head_hash: H256::random(),
+            head_num: head_num.into(),
+            rpcs: rpcs.iter().map(|x| x.to_string()).collect(),
+            soft_limit,
+        }
+    }
+
+    fn test_config() -> ChainSplitConfig {
+        ChainSplitConfig {
+            enabled: true,
+            min_blocks: 3,
+            min_soft_limit_percent: 25,
+            tx_policy: ChainSplitTxPolicy::Pause,
+        }
+    }
+
+    #[test]
+    fn test_chain_split() {
+        let detector = ChainSplitDetector::new(test_config(), 1, None);
+
+        // one side is fine
+        assert!(detector
+            .update(vec![side(100, &["a", "b"], 2_000)])
+            .is_none());
+        assert!(detector.current().is_none());
+
+        // two sides for a moment is fine too
+        assert!(detector
+            .update(vec![side(101, &["a"], 1_000), side(101, &["b"], 1_000)])
+            .is_none());
+        assert!(detector
+            .update(vec![side(103, &["a"], 1_000), side(102, &["b"], 1_000)])
+            .is_none());
+
+        // two sides for 3 blocks is a split
+        let split = detector
+            .update(vec![side(104, &["a"], 1_000), side(103, &["b"], 3_000)])
+            .unwrap();
+        assert_eq!(split.blocks, 3);
+        assert_eq!(split.sides[0].rpcs, vec!["b".to_string()]);
+
+        // only alert once
+        assert!(detector
+            .update(vec![side(105, &["a"], 1_000), side(104, &["b"], 3_000)])
+            .is_none());
+        assert_eq!(detector.current().unwrap().blocks, 4);
+        assert_eq!(detector.metrics().detected, 1);
+
+        // back to one side
+        assert!(detector
+            .update(vec![side(106, &["a", "b"], 4_000)])
+            .is_none());
+        assert!(detector.current().is_none());
+    }
+
+    #[test]
+    fn test_insignificant_side() {
+        let detector = ChainSplitDetector::new(test_config(), 1, None);
+
+        // a single small rpc on its own fork isn't a chain split
+        for i in 0..10 {
+            assert!(detector
+                .update(vec![
+                    side(100 + i, &["a"], 100),
+                    side(100 + i, &["b", "c"], 2_000)
+                ])
+                .is_none());
+        }
+
+        assert!(detector.current().is_none());
+    }
+}
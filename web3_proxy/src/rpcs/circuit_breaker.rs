@@ -0,0 +1,423 @@
+///! Stop sending requests to a backend rpc that keeps erroring or timing out.
+use crate::config::CircuitBreakerConfig;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{self, AtomicU64};
+use tokio::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// requests flow normally
+    Closed,
+    /// too many errors. no requests until `open_until`
+    Open,
+    /// a limited number of trial requests are allowed through
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// a number for graphing in prometheus
+    pub fn as_metric(&self) -> u8 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "closed"),
+            Self::Open => write!(f, "open"),
+            Self::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// What happened when we sent a request to the rpc
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RequestOutcome {
+    Success,
+    Error,
+    Timeout,
+}
+
+struct CircuitBreakerInner {
+    state: CircuitState,
+    /// counts are reset whenever the window is older than `window_seconds`
+    window_start: Instant,
+    successes: u64,
+    errors: u64,
+    timeouts: u64,
+    /// only used when the state is Open
+    open_until: Instant,
+    /// only used when the state is HalfOpen
+    half_open_at: Instant,
+    half_open_started: u64,
+    half_open_successes: u64,
+}
+
+impl CircuitBreakerInner {
+    fn reset_window(&mut self, now: Instant) {
+        self.window_start = now;
+        self.successes = 0;
+        self.errors = 0;
+        self.timeouts = 0;
+    }
+}
+
+/// A closed/open/half-open state machine driven by the recent error and timeout ratio of a Web3Rpc
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<CircuitBreakerInner>,
+    /// TODO: maybe move these to graphana
+    transitions: AtomicU64,
+    total_errors: AtomicU64,
+    total_timeouts: AtomicU64,
+}
+
+/// The circuit breaker's counters in a format that serde_prometheus likes
+#[derive(Serialize)]
+pub struct CircuitBreakerMetrics {
+    state: u8,
+    transitions: u64,
+    errors: u64,
+    timeouts: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        let now = Instant::now();
+
+        let inner = CircuitBreakerInner {
+            state: CircuitState::Closed,
+            window_start: now,
+            successes: 0,
+            errors: 0,
+            timeouts: 0,
+            open_until: now,
+            half_open_at: now,
+            half_open_started: 0,
+            half_open_successes: 0,
+        };
+
+        Self {
+            config,
+            inner: Mutex::new(inner),
+            transitions: 0.into(),
+            total_errors: 0.into(),
+            total_timeouts: 0.into(),
+        }
+    }
+
+    #[inline]
+    fn enabled(&self) -> bool {
+        self.config.enabled && self.config.error_percent > 0
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().state
+    }
+
+    fn transition(
+        &self,
+        inner: &mut CircuitBreakerInner,
+        new_state: CircuitState,
+        now: Instant,
+        rpc: &dyn fmt::Display,
+    ) {
+        let old_state = inner.state;
+
+        inner.state = new_state;
+
+        match new_state {
+            CircuitState::Open => {
+                inner.open_until = now + Duration::from_secs(self.config.open_seconds);
+
+                warn!(
+                    "circuit breaker on {} is {} (was {}). errors={} timeouts={} successes={}",
+                    rpc, new_state, old_state, inner.errors, inner.timeouts, inner.successes
+                );
+            }
+            CircuitState::HalfOpen => {
+                inner.half_open_at = now;
+                inner.half_open_started = 0;
+                inner.half_open_successes = 0;
+
+                info!(
+                    "circuit breaker on {} is {} (was {})",
+                    rpc, new_state, old_state
+                );
+            }
+            CircuitState::Closed => {
+                info!(
+                    "circuit breaker on {} is {} (was {})",
+                    rpc, new_state, old_state
+                );
+            }
+        }
+
+        // start counting fresh in the new state
+        inner.reset_window(now);
+
+        self.transitions.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Check if a request should be sent to this rpc.
+    /// If not, returns the time that the caller should retry at.
+    pub fn check(&self, rpc: &dyn fmt::Display) -> Result<(), Instant> {
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        let mut inner = self.inner.lock();
+
+        if inner.state == CircuitState::Open {
+            if now < inner.open_until {
+                return Err(inner.open_until);
+            }
+
+            self.transition(&mut inner, CircuitState::HalfOpen, now, rpc);
+        }
+
+        if inner.state == CircuitState::HalfOpen {
+            // if the trial requests never reported back (maybe they got rate limited), let some more through
+            if now.duration_since(inner.half_open_at)
+                > Duration::from_secs(self.config.window_seconds)
+            {
+                inner.half_open_at = now;
+                inner.half_open_started = 0;
+            }
+
+            if inner.half_open_started >= self.config.half_open_requests {
+                // TODO: what duration? this is long enough for the trial requests to finish
+                return Err(now + Duration::from_secs(1));
+            }
+
+            inner.half_open_started += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Record the outcome of a request to this rpc and update the state machine
+    pub fn record(&self, rpc: &dyn fmt::Display, outcome: RequestOutcome) {
+        match outcome {
+            RequestOutcome::Success => {}
+            RequestOutcome::Error => {
+                self.total_errors.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            RequestOutcome::Timeout => {
+                self.total_timeouts.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        if !self.enabled() {
+            return;
+        }
+
+        let now = Instant::now();
+
+        let mut inner = self.inner.lock();
+
+        match inner.state {
+            CircuitState::Closed => {
+                if now.duration_since(inner.window_start)
+                    > Duration::from_secs(self.config.window_seconds)
+                {
+                    inner.reset_window(now);
+                }
+
+                match outcome {
+                    RequestOutcome::Success => inner.successes += 1,
+                    RequestOutcome::Error => inner.errors += 1,
+                    RequestOutcome::Timeout => inner.timeouts += 1,
+                }
+
+                let failures = inner.errors + inner.timeouts;
+                let total = inner.successes + failures;
+
+                if total >= self.config.min_requests
+                    && failures * 100 >= self.config.error_percent * total
+                {
+                    self.transition(&mut inner, CircuitState::Open, now, rpc);
+                }
+            }
+            CircuitState::Open => {
+                // requests that were already in flight when the circuit opened. ignore them
+            }
+            CircuitState::HalfOpen => match outcome {
+                RequestOutcome::Success => {
+                    inner.half_open_successes += 1;
+
+                    if inner.half_open_successes >= self.config.half_open_requests {
+                        self.transition(&mut inner, CircuitState::Closed, now, rpc);
+                    }
+                }
+                RequestOutcome::Error | RequestOutcome::Timeout => {
+                    self.transition(&mut inner, CircuitState::Open, now, rpc);
+                }
+            },
+        }
+    }
+
+    pub fn metrics(&self) -> CircuitBreakerMetrics {
+        CircuitBreakerMetrics {
+            state: self.state().as_metric(),
+            transitions: self.transitions.load(atomic::Ordering::Relaxed),
+            errors: self.total_errors.load(atomic::Ordering::Relaxed),
+            timeouts: self.total_timeouts.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl Serialize for CircuitBreaker {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("CircuitBreaker", 7)?;
+
+        {
+            let inner = self.inner.lock();
+
+            state.serialize_field("state", &inner.state)?;
+            state.serialize_field("window_successes", &inner.successes)?;
+            state.serialize_field("window_errors", &inner.errors)?;
+            state.serialize_field("window_timeouts", &inner.timeouts)?;
+        }
+
+        state.serialize_field(
+            "transitions",
+            &self.transitions.load(atomic::Ordering::Relaxed),
+        )?;
+        state.serialize_field("errors", &self.total_errors.load(atomic::Ordering::Relaxed))?;
+        state.serialize_field(
+            "timeouts",
+            &self.total_timeouts.load(atomic::Ordering::Relaxed),
+        )?;
+
+        state.end()
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(open_seconds: u64) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            enabled: true,
+            error_percent: 50,
+            min_requests: 4,
+            window_seconds: 60,
+            open_seconds,
+            half_open_requests: 2,
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens() {
+        let breaker = CircuitBreaker::new(test_config(60));
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.check(&"test").is_ok());
+
+        // not enough requests to open yet
+        breaker.record(&"test", RequestOutcome::Error);
+        breaker.record(&"test", RequestOutcome::Timeout);
+        breaker.record(&"test", RequestOutcome::Success);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // 3 of 4 failed
+        breaker.record(&"test", RequestOutcome::Error);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.check(&"test").is_err());
+
+        let metrics = breaker.metrics();
+        assert_eq!(metrics.transitions, 1);
+        assert_eq!(metrics.errors, 2);
+        assert_eq!(metrics.timeouts, 1);
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        // open_seconds of 0 means the circuit is half-open on the very next check
+        let breaker = CircuitBreaker::new(test_config(0));
+
+        for _ in 0..4 {
+            breaker.record(&"test", RequestOutcome::Error);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // only half_open_requests trial requests are allowed
+        assert!(breaker.check(&"test").is_ok());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.check(&"test").is_ok());
+        assert!(breaker.check(&"test").is_err());
+
+        breaker.record(&"test", RequestOutcome::Success);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(&"test", RequestOutcome::Success);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // a failed trial request opens the circuit again
+        for _ in 0..4 {
+            breaker.record(&"test", RequestOutcome::Timeout);
+        }
+        assert!(breaker.check(&"test").is_ok());
+        breaker.record(&"test", RequestOutcome::Error);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            error_percent: 0,
+            ..test_config(60)
+        });
+
+        for _ in 0..100 {
+            breaker.record(&"test", RequestOutcome::Error);
+        }
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.check(&"test").is_ok());
+        assert_eq!(breaker.metrics().errors, 100);
+    }
+
+    #[test]
+    fn test_circuit_breaker_off_by_default() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig::default());
+
+        for _ in 0..100 {
+            breaker.record(&"test", RequestOutcome::Error);
+        }
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.check(&"test").is_ok());
+        assert_eq!(breaker.metrics().errors, 100);
+    }
+}
@@ -1,4 +1,5 @@
 use super::blockchain::Web3ProxyBlock;
+use super::chain_split::ForkSide;
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
 use crate::frontend::authorization::Authorization;
@@ -173,6 +174,16 @@ impl ConsensusFinder {
                 //      }
                 // }
 
+                // the head watchdog saw the subscription get stuck or disagree with the http head
+                if !rpc.head_watchdog.is_healthy() {
+                    trace!(
+                        "rpc_head_block from {} is unhealthy! {}",
+                        rpc,
+                        rpc_head_block
+                    );
+                    return Ok(self.remove(&rpc).is_some());
+                }
+
                 if let Some(max_age) = self.max_block_age {
                     if rpc_head_block.age() > max_age {
                         trace!("rpc_head_block from {} is too old! {}", rpc, rpc_head_block);
@@ -374,6 +385,80 @@ impl ConsensusFinder {
         None
     }
 
+    /// Group the heads of the primary rpcs by the chain that they are on
+    pub async fn fork_sides(
+        &self,
+        authorization: &Arc<Authorization>,
+        web3_rpcs: &Web3Rpcs,
+    ) -> Vec<ForkSide> {
+        // highest heads first so that lower heads can be checked against the chains above them
+        let mut heads: Vec<_> = self
+            .rpc_heads
+            .iter()
+            .filter(|(rpc, _)| !rpc.backup)
+            .collect();
+        heads.sort_by_cached_key(|(_, head)| Reverse(*head.number()));
+
+        let mut sides: Vec<(Web3ProxyBlock, ForkSide)> = vec![];
+
+        'heads: for (rpc, head) in heads {
+            for (tip, side) in sides.iter_mut() {
+                if self
+                    .is_ancestor(authorization, web3_rpcs, head, tip, rpc)
+                    .await
+                {
+                    side.rpcs.push(rpc.name.clone());
+                    side.soft_limit += rpc.soft_limit;
+                    continue 'heads;
+                }
+            }
+
+            let mut side = ForkSide::new(head);
+
+            side.rpcs.push(rpc.name.clone());
+            side.soft_limit += rpc.soft_limit;
+
+            sides.push((head.clone(), side));
+        }
+
+        sides.into_iter().map(|(_, side)| side).collect()
+    }
+
+    /// Check if `block` is `tip` or one of its parents.
+    /// Errors fetching parents count as the same chain. A missing block shouldn't look like a chain split
+    async fn is_ancestor(
+        &self,
+        authorization: &Arc<Authorization>,
+        web3_rpcs: &Web3Rpcs,
+        block: &Web3ProxyBlock,
+        tip: &Web3ProxyBlock,
+        rpc: &Arc<Web3Rpc>,
+    ) -> bool {
+        // lagging rpcs are already kept out of consensus. don't walk too far back
+        let max_depth = self.max_block_lag.unwrap_or_else(|| U64::from(10));
+
+        if tip.number().saturating_sub(*block.number()) > max_depth {
+            return true;
+        }
+
+        let mut block_to_check = tip.clone();
+
+        while block_to_check.number() > block.number() {
+            match web3_rpcs
+                .block(authorization, block_to_check.parent_hash(), Some(rpc))
+                .await
+            {
+                Ok(parent_block) => block_to_check = parent_block,
+                Err(err) => {
+                    trace!("unable to check for a chain split: {:?}", err);
+                    return true;
+                }
+            }
+        }
+
+        block_to_check.hash() == block.hash()
+    }
+
     pub fn worst_tier(&self) -> Option<u64> {
         self.rpc_heads.iter().map(|(x, _)| x.tier).max()
     }
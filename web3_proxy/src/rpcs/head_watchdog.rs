@@ -0,0 +1,248 @@
+///! Notice when a backend rpc's head block gets stuck or stops matching the head it serves over http.
+use crate::config::HeadWatchdogConfig;
+use ethers::prelude::U64;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::fmt;
+use tokio::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadHealth {
+    /// no checks since the last (re)connect
+    Unknown,
+    Healthy,
+    /// no new heads for longer than `max_head_age_seconds`
+    Stale,
+    /// the subscribed head and the http head are too far apart
+    Diverged,
+}
+
+impl HeadHealth {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Self::Unknown | Self::Healthy)
+    }
+}
+
+impl fmt::Display for HeadHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::Healthy => write!(f, "healthy"),
+            Self::Stale => write!(f, "stale"),
+            Self::Diverged => write!(f, "diverged"),
+        }
+    }
+}
+
+struct HeadWatchdogInner {
+    health: HeadHealth,
+    /// when the subscription last gave us a new head. starts at the (re)connect time
+    last_head_at: Instant,
+    /// how many checks in a row have seen the heads too far apart
+    divergent_checks: u32,
+}
+
+/// Tracks the health of a Web3Rpc's head block subscription
+pub struct HeadWatchdog {
+    config: HeadWatchdogConfig,
+    inner: Mutex<HeadWatchdogInner>,
+}
+
+impl Default for HeadWatchdog {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl HeadWatchdog {
+    pub fn new(config: HeadWatchdogConfig) -> Self {
+        let inner = HeadWatchdogInner {
+            health: HeadHealth::Unknown,
+            last_head_at: Instant::now(),
+            divergent_checks: 0,
+        };
+
+        Self {
+            config,
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled && self.config.interval_seconds > 0
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval_seconds)
+    }
+
+    pub fn health(&self) -> HeadHealth {
+        self.inner.lock().health
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health().is_healthy()
+    }
+
+    /// The subscription sent a new head
+    pub fn on_head(&self) {
+        self.inner.lock().last_head_at = Instant::now();
+    }
+
+    /// Start over after a (re)connect
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+
+        inner.health = HeadHealth::Unknown;
+        inner.last_head_at = Instant::now();
+        inner.divergent_checks = 0;
+    }
+
+    /// Compare the subscribed head with the head from http (if we were able to get one) and update the health
+    pub fn check(&self, subscribed_head: Option<U64>, http_head: Option<U64>) -> HeadHealth {
+        let mut inner = self.inner.lock();
+
+        let max_head_age = Duration::from_secs(self.config.max_head_age_seconds);
+
+        if self.config.max_head_age_seconds > 0 && inner.last_head_at.elapsed() > max_head_age {
+            inner.health = HeadHealth::Stale;
+
+            return inner.health;
+        }
+
+        let lag = match (subscribed_head, http_head) {
+            (Some(subscribed_head), Some(http_head)) => {
+                if subscribed_head > http_head {
+                    subscribed_head - http_head
+                } else {
+                    http_head - subscribed_head
+                }
+            }
+            // TODO: if the http head keeps failing, something is wrong too
+            _ => U64::zero(),
+        };
+
+        if lag > self.config.max_head_lag.into() {
+            inner.divergent_checks += 1;
+        } else {
+            inner.divergent_checks = 0;
+        }
+
+        inner.health = if inner.divergent_checks >= self.config.max_divergent_checks.max(1) {
+            HeadHealth::Diverged
+        } else {
+            HeadHealth::Healthy
+        };
+
+        inner.health
+    }
+}
+
+impl Serialize for HeadWatchdog {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("HeadWatchdog", 3)?;
+
+        {
+            let inner = self.inner.lock();
+
+            state.serialize_field("health", &inner.health)?;
+            state.serialize_field(
+                "last_head_seconds_ago",
+                &inner.last_head_at.elapsed().as_secs(),
+            )?;
+            state.serialize_field("divergent_checks", &inner.divergent_checks)?;
+        }
+
+        state.end()
+    }
+}
+
+impl fmt::Debug for HeadWatchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeadWatchdog")
+            .field("health", &self.health())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(max_head_age_seconds: u64) -> HeadWatchdogConfig {
+        HeadWatchdogConfig {
+            enabled: true,
+            interval_seconds: 30,
+            max_head_age_seconds,
+            max_head_lag: 5,
+            max_divergent_checks: 2,
+        }
+    }
+
+    #[test]
+    fn test_head_watchdog_diverged() {
+        let watchdog = HeadWatchdog::new(test_config(60));
+
+        assert_eq!(watchdog.health(), HeadHealth::Unknown);
+        assert!(watchdog.is_healthy());
+
+        // a little lag is normal
+        assert_eq!(
+            watchdog.check(Some(100.into()), Some(103.into())),
+            HeadHealth::Healthy
+        );
+
+        // a single bad check isn't enough
+        assert_eq!(
+            watchdog.check(Some(100.into()), Some(110.into())),
+            HeadHealth::Healthy
+        );
+        assert_eq!(
+            watchdog.check(Some(100.into()), Some(110.into())),
+            HeadHealth::Diverged
+        );
+        assert!(!watchdog.is_healthy());
+
+        // a missing http head doesn't count either way
+        assert_eq!(watchdog.check(Some(110.into()), None), HeadHealth::Healthy);
+
+        // the subscription being ahead is also wrong
+        watchdog.check(Some(200.into()), Some(110.into()));
+        assert_eq!(
+            watchdog.check(Some(200.into()), Some(110.into())),
+            HeadHealth::Diverged
+        );
+
+        watchdog.reset();
+        assert_eq!(watchdog.health(), HeadHealth::Unknown);
+    }
+
+    #[test]
+    fn test_head_watchdog_stale() {
+        // max_head_age_seconds of 0 disables the stale check
+        let watchdog = HeadWatchdog::new(test_config(0));
+
+        assert_eq!(watchdog.check(None, None), HeadHealth::Healthy);
+
+        let watchdog = HeadWatchdog::new(test_config(60));
+
+        watchdog.inner.lock().last_head_at = Instant::now() - Duration::from_secs(61);
+
+        assert_eq!(
+            watchdog.check(Some(100.into()), Some(100.into())),
+            HeadHealth::Stale
+        );
+
+        watchdog.on_head();
+
+        assert_eq!(
+            watchdog.check(Some(101.into()), Some(101.into())),
+            HeadHealth::Healthy
+        );
+    }
+}
@@ -0,0 +1,189 @@
+///! A json-rpc client for http backends.
+///!
+///! ethers' http client drops the response's headers. We need Retry-After when a backend rate limits us
+use super::rate_limits::retry_after_from_headers;
+use async_trait::async_trait;
+use ethers::providers::{JsonRpcClient, JsonRpcError, ProviderError, RpcError};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use url::Url;
+
+#[derive(Debug)]
+pub enum HttpClientError {
+    Reqwest(reqwest::Error),
+    JsonRpc(JsonRpcError),
+    SerdeJson {
+        err: serde_json::Error,
+        text: String,
+    },
+}
+
+impl fmt::Display for HttpClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reqwest(err) => write!(f, "{}", err),
+            Self::JsonRpc(err) => write!(f, "{}", err),
+            Self::SerdeJson { err, text } => {
+                write!(f, "Deserialization Error: {}. Response: {}", err, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HttpClientError {}
+
+impl RpcError for HttpClientError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            Self::JsonRpc(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            Self::SerdeJson { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for HttpClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Reqwest(err)
+    }
+}
+
+impl From<HttpClientError> for ProviderError {
+    fn from(err: HttpClientError) -> Self {
+        match err {
+            HttpClientError::Reqwest(err) => ProviderError::HTTPError(err),
+            err => ProviderError::JsonRpcClientError(Box::new(err)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpResponse<'a> {
+    #[serde(borrow)]
+    result: Option<&'a RawValue>,
+    error: Option<JsonRpcError>,
+}
+
+/// Like `ethers::providers::Http`, but rate limits include the Retry-After header
+#[derive(Debug)]
+pub struct HttpClient {
+    id: AtomicU64,
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl HttpClient {
+    pub fn new(url: Url, client: reqwest::Client) -> Self {
+        Self {
+            id: AtomicU64::new(1),
+            client,
+            url,
+        }
+    }
+
+    /// a dedicated client that sends the authorization header on every request
+    pub fn new_with_auth(url: Url, auth: ethers::providers::Authorization) -> anyhow::Result<Self> {
+        let mut auth_value = HeaderValue::from_str(&auth.to_string())?;
+        auth_value.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, auth_value);
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+
+        Ok(Self::new(url, client))
+    }
+}
+
+/// The backend rate limited us. Put Retry-After into the error's data so that `check_provider_error` finds it
+fn rate_limit_error(error: Option<JsonRpcError>, headers: &HeaderMap) -> JsonRpcError {
+    let mut error = error.unwrap_or_else(|| JsonRpcError {
+        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+        message: "Too Many Requests".to_string(),
+        data: None,
+    });
+
+    if let Some(retry_after) = retry_after_from_headers(headers) {
+        let data = error.data.get_or_insert_with(|| json!({}));
+
+        // a retry time in the backend's json-rpc error wins
+        if let serde_json::Value::Object(data) = data {
+            data.entry("retry_after")
+                .or_insert_with(|| retry_after.as_secs_f64().into());
+        }
+    }
+
+    error
+}
+
+#[async_trait]
+impl JsonRpcClient for HttpClient {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+
+        let payload = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        let response = self
+            .client
+            .post(self.url.as_ref())
+            .json(&payload)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+
+        let body = response.bytes().await?;
+
+        let parsed = serde_json::from_slice::<HttpResponse>(&body);
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let error = parsed.ok().and_then(|x| x.error);
+
+            return Err(HttpClientError::JsonRpc(rate_limit_error(error, &headers)));
+        }
+
+        let raw = match parsed {
+            Ok(HttpResponse {
+                error: Some(error), ..
+            }) => return Err(HttpClientError::JsonRpc(error)),
+            Ok(HttpResponse { result, .. }) => result.map(|x| x.get()).unwrap_or("null"),
+            Err(err) => {
+                return Err(HttpClientError::SerdeJson {
+                    err,
+                    text: String::from_utf8_lossy(&body).to_string(),
+                })
+            }
+        };
+
+        serde_json::from_str(raw).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: raw.to_string(),
+        })
+    }
+}
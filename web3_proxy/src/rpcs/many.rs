@@ -1,9 +1,14 @@
 ///! Load balanced communication with a group of web3 rpc providers
+use super::block_time::BlockTimeEstimator;
 use super::blockchain::{BlocksByHashCache, Web3ProxyBlock};
+use super::chain_split::ChainSplitDetector;
 use super::consensus::ConsensusWeb3Rpcs;
 use super::one::Web3Rpc;
+use super::private_txs::RelayResponse;
+use super::rate_limits;
 use super::request::{OpenRequestHandle, OpenRequestResult, RequestErrorHandler};
 use crate::app::{flatten_handle, AnyhowJoinHandle, Web3ProxyApp};
+use crate::block_number::DataKind;
 ///! Load balanced communication with a group of web3 providers
 use crate::config::{BlockAndRpc, TxHashAndRpc, Web3RpcConfig};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
@@ -14,7 +19,7 @@ use crate::rpcs::transactions::TxStatus;
 use anyhow::Context;
 use counter::Counter;
 use derive_more::From;
-use ethers::prelude::{ProviderError, TxHash, H256, U64};
+use ethers::prelude::{ProviderError, TxHash, H256, U256, U64};
 use futures::future::try_join_all;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
@@ -37,7 +42,7 @@ use std::{cmp, fmt};
 use thread_fast_rng::rand::seq::SliceRandom;
 use tokio;
 use tokio::sync::{broadcast, watch};
-use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 
 /// A collection of web3 connections. Sends requests either the current best server or all servers.
 #[derive(From)]
@@ -48,7 +53,8 @@ pub struct Web3Rpcs {
     /// TODO: i tried to make this an AsyncRwLock, but then we have trouble serializing it
     /// TODO: maybe an ArcSwap would be better. writes are rare
     pub(crate) by_name: RwLock<HashMap<String, Arc<Web3Rpc>>>,
-    pub(crate) http_interval_sender: Option<Arc<broadcast::Sender<()>>>,
+    /// ticks when http rpcs should poll for a new head. sends the current poll interval
+    pub(crate) http_interval_sender: Option<Arc<broadcast::Sender<Duration>>>,
     /// all providers with the same consensus head block. won't update if there is no `self.watch_consensus_head_sender`
     /// TODO: document that this is a watch sender and not a broadcast! if things get busy, blocks might get missed
     /// TODO: why is watch_consensus_head_sender in an Option, but this one isn't?
@@ -73,6 +79,18 @@ pub struct Web3Rpcs {
     pub(super) max_block_lag: Option<U64>,
     /// how old our consensus head block we can be before we stop serving requests
     pub(super) max_block_age: Option<u64>,
+    /// notice when the rpcs disagree about the chain
+    pub(crate) chain_split: ChainSplitDetector,
+}
+
+/// How to pick the response when a request is sent to multiple rpcs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResponseAggregate {
+    /// the most common response
+    #[default]
+    MostCommon,
+    /// the highest numeric result. mempools differ, so this is best for pending nonces
+    Max,
 }
 
 impl Web3Rpcs {
@@ -89,6 +107,7 @@ impl Web3Rpcs {
         pending_transaction_cache: Cache<TxHash, TxStatus, hashbrown::hash_map::DefaultHashBuilder>,
         pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
         watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
+        chain_split: ChainSplitDetector,
     ) -> anyhow::Result<(
         Arc<Self>,
         AnyhowJoinHandle<()>,
@@ -98,7 +117,7 @@ impl Web3Rpcs {
         let (pending_tx_id_sender, pending_tx_id_receiver) = flume::unbounded();
         let (block_sender, block_receiver) = flume::unbounded::<BlockAndRpc>();
 
-        // TODO: query the rpc to get the actual expected block time, or get from config? maybe have this be part of a health check?
+        // this is only a starting point. the http interval adjusts to the block time measured from consensus heads
         let expected_block_time_ms = match chain_id {
             // ethereum
             1 => 12_000,
@@ -123,9 +142,11 @@ impl Web3Rpcs {
         let http_interval_sender = if http_client.is_some() {
             let (sender, _) = broadcast::channel(1);
 
-            // TODO: what interval? follow a websocket also? maybe by watching synced connections with a timeout. will need debounce
-            let mut interval = interval(Duration::from_millis(expected_block_time_ms / 2));
-            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut block_time =
+                BlockTimeEstimator::new(Duration::from_millis(expected_block_time_ms));
+
+            let mut consensus_head_receiver =
+                watch_consensus_head_sender.as_ref().map(|x| x.subscribe());
 
             let sender = Arc::new(sender);
 
@@ -133,12 +154,57 @@ impl Web3Rpcs {
                 let sender = sender.clone();
 
                 async move {
+                    let mut last_tick = Instant::now();
+                    let mut next_tick = last_tick;
+
                     loop {
-                        interval.tick().await;
+                        // new consensus heads update the block time. otherwise wait for the next tick
+                        let new_head = if let Some(receiver) = consensus_head_receiver.as_mut() {
+                            tokio::select! {
+                                _ = sleep_until(next_tick) => None,
+                                x = receiver.changed() => Some(x.map(|_| {
+                                    receiver.borrow_and_update().as_ref().map(|x| {
+                                        (x.number().as_u64(), x.block.timestamp.as_u64())
+                                    })
+                                })),
+                            }
+                        } else {
+                            sleep_until(next_tick).await;
+                            None
+                        };
+
+                        match new_head {
+                            None => {}
+                            Some(Ok(Some((number, timestamp)))) => {
+                                let old_block_time = block_time.block_time();
+
+                                let new_block_time = block_time.record(number, timestamp);
+
+                                if new_block_time != old_block_time {
+                                    trace!("block time: {:?}", new_block_time);
+                                }
+
+                                // the interval might have gotten a lot shorter
+                                next_tick = next_tick.min(last_tick + block_time.poll_interval());
+
+                                continue;
+                            }
+                            Some(Ok(None)) => continue,
+                            Some(Err(_)) => {
+                                // the sender was dropped. keep ticking with the last estimate
+                                consensus_head_receiver = None;
+                                continue;
+                            }
+                        }
+
+                        let poll_interval = block_time.poll_interval();
+
+                        last_tick = Instant::now();
+                        next_tick = last_tick + poll_interval;
 
                         // trace!("http interval ready");
 
-                        if sender.send(()).is_err() {
+                        if sender.send(poll_interval).is_err() {
                             // errors are okay. they mean that all receivers have been dropped, or the rpcs just haven't started yet
                             // TODO: i'm seeing this error a lot more than expected
                             trace!("no http receivers");
@@ -196,6 +262,7 @@ impl Web3Rpcs {
             min_head_rpcs,
             max_block_age,
             max_block_lag,
+            chain_split,
         });
 
         let authorization = Arc::new(Authorization::internal(db_conn)?);
@@ -345,6 +412,16 @@ impl Web3Rpcs {
         self.min_head_rpcs
     }
 
+    /// The deepest state kept by any pruned server. None if every server is an archive server or hasn't been checked yet
+    pub fn archive_depth(&self) -> Option<u64> {
+        self.by_name
+            .read()
+            .values()
+            .map(|x| x.block_data_limit.load(atomic::Ordering::Relaxed))
+            .filter(|x| *x != 0 && *x != u64::MAX)
+            .max()
+    }
+
     /// subscribe to blocks and transactions from all the backend rpcs.
     /// blocks are processed by all the `Web3Rpc`s and then sent to the `block_receiver`
     /// transaction ids from all the `Web3Rpc`s are deduplicated and forwarded to `pending_tx_sender`
@@ -431,6 +508,8 @@ impl Web3Rpcs {
         method: &str,
         params: Option<&serde_json::Value>,
         error_level: Level,
+        aggregate: ResponseAggregate,
+        request_metadata: Option<&Arc<RequestMetadata>>,
         // TODO: remove this box once i figure out how to do the options
     ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
         // TODO: if only 1 active_request_handles, do self.try_send_request?
@@ -438,15 +517,47 @@ impl Web3Rpcs {
         let responses = active_request_handles
             .into_iter()
             .map(|active_request_handle| async move {
+                let rpc = active_request_handle.clone_connection();
+
                 let result: Result<Box<RawValue>, _> = active_request_handle
                     .request(method, &json!(&params), error_level.into(), None)
                     .await;
-                result
+
+                (rpc, result)
             })
             .collect::<FuturesUnordered<_>>()
-            .collect::<Vec<Result<Box<RawValue>, ProviderError>>>()
+            .collect::<Vec<(Arc<Web3Rpc>, Result<Box<RawValue>, ProviderError>)>>()
             .await;
 
+        // save every response. private transaction status uses these
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .relay_responses
+                .lock()
+                .extend(responses.iter().map(|(rpc, result)| {
+                    RelayResponse::new(
+                        rpc.name.clone(),
+                        result.as_deref().map_err(|err| format!("{}", err)),
+                    )
+                }));
+        }
+
+        let responses: Vec<_> = responses.into_iter().map(|(_, result)| result).collect();
+
+        if aggregate == ResponseAggregate::Max {
+            let max = responses
+                .iter()
+                .filter_map(|x| x.as_ref().ok())
+                .filter_map(|x| Some((serde_json::from_str::<U256>(x.get()).ok()?, x)))
+                .max_by_key(|(x, _)| *x);
+
+            if let Some((_, max)) = max {
+                return JsonRpcForwardedResponse::try_from_response_result(Ok(max.clone()), id);
+            }
+
+            // none of the results were numbers. fall back to the most common response
+        }
+
         // TODO: Strings are not great keys, but we can't use RawValue or ProviderError as keys because they don't implement Hash or Eq
         let mut count_map: HashMap<String, _> = HashMap::new();
         let mut counts: Counter<String> = Counter::new();
@@ -506,11 +617,16 @@ impl Web3Rpcs {
         &self,
         authorization: &Arc<Authorization>,
         request_metadata: Option<&Arc<RequestMetadata>>,
+        // if set, only rpcs that support this method are used
+        method: Option<&str>,
         skip: &[Arc<Web3Rpc>],
         // TODO: if we are checking for the consensus head, i don' think we need min_block_needed/max_block_needed
         min_block_needed: Option<&U64>,
         max_block_needed: Option<&U64>,
     ) -> Web3ProxyResult<OpenRequestResult> {
+        // pruned nodes often keep old receipts and blocks even though they don't have old state
+        let data_kind = method.map(DataKind::from_method).unwrap_or(DataKind::State);
+
         let usable_rpcs_by_tier_and_head_number: BTreeMap<
             (u64, Reverse<Option<U64>>),
             Vec<Arc<Web3Rpc>>,
@@ -594,7 +710,9 @@ impl Web3Rpcs {
                                     // we've already tried this server or have some other reason to skip it
                                     false
                                 } else if max_block_needed
-                                    .map(|max_block_needed| !x.has_block_data(max_block_needed))
+                                    .map(|max_block_needed| {
+                                        !x.has_data(data_kind, max_block_needed)
+                                    })
                                     .unwrap_or(false)
                                 {
                                     // server does not have the max block
@@ -606,7 +724,9 @@ impl Web3Rpcs {
                                     false
                                 } else {
                                     !min_block_needed
-                                        .map(|min_block_needed| !x.has_block_data(min_block_needed))
+                                        .map(|min_block_needed| {
+                                            !x.has_data(data_kind, min_block_needed)
+                                        })
                                         .unwrap_or(false)
                                 }
                             })
@@ -673,9 +793,79 @@ impl Web3Rpcs {
             usable_rpcs_by_tier_and_head_number
         );
 
+        // user requests wait in backend queues for a concurrency permit.
+        // there is one deadline for every candidate. otherwise a saturated tier would make the request wait once per rpc
+        let max_wait = request_metadata.map(|x| {
+            let max_queue_wait = usable_rpcs_by_tier_and_head_number
+                .values()
+                .flatten()
+                .map(|rpc| rpc.max_queue_wait)
+                .max()
+                .unwrap_or_default();
+
+            let deadline = Instant::now() + max_queue_wait;
+
+            // and never past the user's timeout
+            match authorization.checks.request_timeout {
+                Some(timeout) => deadline.min(x.start_instant + timeout),
+                None => deadline,
+            }
+        });
+
+        // nonce-sensitive requests prefer the backend that recently served the same key or sender
+        let preferred_rpc = request_metadata.and_then(|x| x.preferred_rpc.lock().clone());
+
+        if let Some(preferred_rpc) = preferred_rpc {
+            let rpc = usable_rpcs_by_tier_and_head_number
+                .values()
+                .flatten()
+                .find(|x| x.name == preferred_rpc)
+                .cloned();
+
+            if let Some(rpc) = rpc.filter(|x| {
+                method.map(|m| x.supports_method(m)).unwrap_or(true) && x.head_watchdog.is_healthy()
+            }) {
+                // checking the circuit breaker can start a half-open trial. only check it right before using the rpc
+                if let Err(retry_at) = rpc.circuit_breaker_check() {
+                    debug!(
+                        "circuit breaker is open on preferred rpc {}. falling back. {:?}",
+                        rpc, retry_at
+                    );
+                } else {
+                    match rpc.queue_for_request_handle(authorization, max_wait).await {
+                        Ok(OpenRequestResult::Handle(handle)) => {
+                            return Ok(OpenRequestResult::Handle(handle));
+                        }
+                        x => {
+                            debug!(
+                                "preferred rpc {} is unavailable. falling back. {:?}",
+                                rpc, x
+                            );
+                        }
+                    }
+                }
+            } else {
+                // the app remembers whichever backend serves this request instead
+                debug!(
+                    "preferred rpc {} is not usable. falling back",
+                    preferred_rpc
+                );
+            }
+        }
+
         let mut earliest_retry_at = None;
 
         for mut usable_rpcs in usable_rpcs_by_tier_and_head_number.into_values() {
+            if let Some(method) = method {
+                // don't send trace_* requests to servers that don't have them
+                usable_rpcs.retain(|x| x.supports_method(method));
+
+                if usable_rpcs.is_empty() {
+                    trace!("no rpcs in this tier support {}", method);
+                    continue;
+                }
+            }
+
             // sort the tier randomly
             if usable_rpcs.len() == 1 {
                 // TODO: include an rpc from the next tier?
@@ -696,14 +886,27 @@ impl Web3Rpcs {
                 let best_rpc = min_by_key(rpc_a, rpc_b, |x| x.peak_ewma());
                 trace!("winner: {}", best_rpc);
 
+                // skip servers that have been erroring or timing out
+                if let Err(retry_at) = best_rpc.circuit_breaker_check() {
+                    trace!("circuit breaker is open on {}", best_rpc);
+                    earliest_retry_at =
+                        Some(earliest_retry_at.map_or(retry_at, |x: Instant| x.min(retry_at)));
+                    continue;
+                }
+
                 // just because it has lower latency doesn't mean we are sure to get a connection
-                match best_rpc.try_request_handle(authorization, None).await {
+                match best_rpc
+                    .queue_for_request_handle(authorization, max_wait)
+                    .await
+                {
                     Ok(OpenRequestResult::Handle(handle)) => {
                         // trace!("opened handle: {}", best_rpc);
                         return Ok(OpenRequestResult::Handle(handle));
                     }
                     Ok(OpenRequestResult::RetryAt(retry_at)) => {
-                        earliest_retry_at = earliest_retry_at.min(Some(retry_at));
+                        // None is less than Some, so Option::min would never set this
+                        earliest_retry_at =
+                            Some(earliest_retry_at.map_or(retry_at, |x: Instant| x.min(retry_at)));
                     }
                     Ok(OpenRequestResult::NotReady) => {
                         // TODO: log a warning? emit a stat?
@@ -827,12 +1030,20 @@ impl Web3Rpcs {
                 }
             }
 
+            if let Err(retry_at) = rpc.circuit_breaker_check() {
+                warn!("{} circuit breaker is open. skipping", rpc);
+                earliest_retry_at =
+                    Some(earliest_retry_at.map_or(retry_at, |x: Instant| x.min(retry_at)));
+                continue;
+            }
+
             // check rate limits and increment our connection counter
             match rpc.try_request_handle(authorization, None).await {
                 Ok(OpenRequestResult::RetryAt(retry_at)) => {
                     // this rpc is not available. skip it
                     warn!("{} is rate limited. skipping", rpc);
-                    earliest_retry_at = earliest_retry_at.min(Some(retry_at));
+                    earliest_retry_at =
+                        Some(earliest_retry_at.map_or(retry_at, |x: Instant| x.min(retry_at)));
                 }
                 Ok(OpenRequestResult::Handle(handle)) => {
                     trace!("{} is available", rpc);
@@ -881,6 +1092,7 @@ impl Web3Rpcs {
                 .best_available_rpc(
                     authorization,
                     request_metadata,
+                    Some(&request.method),
                     &skip_rpcs,
                     min_block_needed,
                     max_block_needed,
@@ -929,13 +1141,10 @@ impl Web3Rpcs {
                                 let error_msg = error.message.as_str();
 
                                 // different providers do different codes. check all of them
-                                // TODO: there's probably more strings to add here
-                                let rate_limit_substrings = ["limit", "exceeded"];
-                                for rate_limit_substr in rate_limit_substrings {
-                                    if error_msg.contains(rate_limit_substr) {
-                                        warn!("rate limited by {}", skip_rpcs.last().unwrap());
-                                        continue;
-                                    }
+                                if rate_limits::is_rate_limit_error(error.code, error_msg) {
+                                    // the request handle already told this server to back off. try another server
+                                    warn!("rate limited by {}", skip_rpcs.last().unwrap());
+                                    continue;
                                 }
 
                                 match error.code {
@@ -962,6 +1171,15 @@ impl Web3Rpcs {
                                         // but sometimes the method is something that is actually unsupported,
                                         // so we save the response here to return it later
 
+                                        // if this was the family's probe method, stop sending the family to this server until it reconnects
+                                        if let Some(rpc) = skip_rpcs.last() {
+                                            if let Some(family) =
+                                                rpc.capabilities.set_unsupported(&request.method)
+                                            {
+                                                debug!("{} does not support {}", rpc, family);
+                                            }
+                                        }
+
                                         // some providers look like this
                                         if error_msg.starts_with("the method")
                                             && error_msg.ends_with("is not available")
@@ -984,6 +1202,13 @@ impl Web3Rpcs {
 
                             return Ok(response);
                         }
+                        Err(
+                            err @ (Web3ProxyError::RequestTimeout(_)
+                            | Web3ProxyError::ResponseTooLarge { .. }),
+                        ) => {
+                            // the user's limits. another server won't do any better
+                            return Err(err);
+                        }
                         Err(err) => {
                             let rpc = skip_rpcs
                                 .last()
@@ -1109,6 +1334,7 @@ impl Web3Rpcs {
         error_level: Level,
         max_count: Option<usize>,
         always_include_backups: bool,
+        aggregate: ResponseAggregate,
     ) -> Web3ProxyResult<JsonRpcForwardedResponse> {
         let mut watch_consensus_rpcs = self.watch_consensus_rpcs_sender.subscribe();
 
@@ -1128,7 +1354,7 @@ impl Web3Rpcs {
                     // TODO: do something with this handle?
                     // TODO: this is not working right. simplify
 
-                    if let Some(request_metadata) = request_metadata {
+                    if let Some(request_metadata) = request_metadata.as_ref() {
                         let mut backup_used = false;
 
                         request_metadata.backend_requests.lock().extend(
@@ -1156,6 +1382,8 @@ impl Web3Rpcs {
                             request.method.as_ref(),
                             request.params.as_ref(),
                             error_level,
+                            aggregate,
+                            request_metadata.as_ref(),
                         )
                         .await;
                 }
@@ -1237,7 +1465,7 @@ impl Serialize for Web3Rpcs {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpcs", 6)?;
+        let mut state = serializer.serialize_struct("Web3Rpcs", 7)?;
 
         {
             let by_name = self.by_name.read();
@@ -1263,6 +1491,7 @@ impl Serialize for Web3Rpcs {
         state.serialize_field("block_hashes_size", &self.blocks_by_hash.weighted_size())?;
         state.serialize_field("block_numbers_count", &self.blocks_by_number.entry_count())?;
         state.serialize_field("block_numbers_size", &self.blocks_by_number.weighted_size())?;
+        state.serialize_field("chain_split", &self.chain_split.current())?;
         state.end()
     }
 }
@@ -1481,6 +1710,7 @@ mod tests {
             max_block_age: None,
             // TODO: test max_block_lag?
             max_block_lag: None,
+            chain_split: Default::default(),
             min_head_rpcs: 1,
             min_sum_soft_limit: 1,
         };
@@ -1523,7 +1753,7 @@ mod tests {
 
         // best_synced_backend_connection requires servers to be synced with the head block
         let x = rpcs
-            .best_available_rpc(&authorization, None, &[], None, None)
+            .best_available_rpc(&authorization, None, None, &[], None, None)
             .await
             .unwrap();
 
@@ -1571,26 +1801,26 @@ mod tests {
         assert_eq!(rpcs.num_synced_rpcs(), 1);
 
         assert!(matches!(
-            rpcs.best_available_rpc(&authorization, None, &[], None, None)
+            rpcs.best_available_rpc(&authorization, None, None, &[], None, None)
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
 
         assert!(matches!(
-            rpcs.best_available_rpc(&authorization, None, &[], Some(&0.into()), None)
+            rpcs.best_available_rpc(&authorization, None, None, &[], Some(&0.into()), None)
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
 
         assert!(matches!(
-            rpcs.best_available_rpc(&authorization, None, &[], Some(&1.into()), None)
+            rpcs.best_available_rpc(&authorization, None, None, &[], Some(&1.into()), None)
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
 
         // future block should not get a handle
         let future_rpc = rpcs
-            .best_available_rpc(&authorization, None, &[], Some(&2.into()), None)
+            .best_available_rpc(&authorization, None, None, &[], Some(&2.into()), None)
             .await;
         assert!(matches!(future_rpc, Ok(OpenRequestResult::NotReady)));
     }
@@ -1678,8 +1908,12 @@ mod tests {
             min_sum_soft_limit: 4_000,
             max_block_age: None,
             max_block_lag: None,
+            chain_split: Default::default(),
         };
 
+        // the archive server doesn't count
+        assert_eq!(rpcs.archive_depth(), Some(64));
+
         let authorization = Arc::new(Authorization::internal(None).unwrap());
 
         let mut connection_heads = ConsensusFinder::new(None, None);
@@ -1710,7 +1944,14 @@ mod tests {
         // best_synced_backend_connection requires servers to be synced with the head block
         // TODO: test with and without passing the head_block.number?
         let best_available_server = rpcs
-            .best_available_rpc(&authorization, None, &[], Some(head_block.number()), None)
+            .best_available_rpc(
+                &authorization,
+                None,
+                None,
+                &[],
+                Some(head_block.number()),
+                None,
+            )
             .await;
 
         debug!("best_available_server: {:#?}", best_available_server);
@@ -1721,13 +1962,13 @@ mod tests {
         ));
 
         let _best_available_server_from_none = rpcs
-            .best_available_rpc(&authorization, None, &[], None, None)
+            .best_available_rpc(&authorization, None, None, &[], None, None)
             .await;
 
         // assert_eq!(best_available_server, best_available_server_from_none);
 
         let best_archive_server = rpcs
-            .best_available_rpc(&authorization, None, &[], Some(&1.into()), None)
+            .best_available_rpc(&authorization, None, None, &[], Some(&1.into()), None)
             .await;
 
         match best_archive_server {
@@ -1838,6 +2079,7 @@ mod tests {
             min_sum_soft_limit: 1_000,
             max_block_age: None,
             max_block_lag: None,
+            chain_split: Default::default(),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1,8 +1,18 @@
 // TODO: all pub, or export useful things here instead?
+pub mod affinity;
+pub mod block_time;
 pub mod blockchain;
+pub mod capabilities;
+pub mod chain_split;
+pub mod circuit_breaker;
 pub mod consensus;
+pub mod head_watchdog;
+pub mod http;
 pub mod many;
 pub mod one;
+pub mod private_txs;
 pub mod provider;
+pub mod rate_limits;
+pub mod recent_txs;
 pub mod request;
 pub mod transactions;
@@ -1,8 +1,13 @@
 ///! Rate-limited communication with a web3 provider.
 use super::blockchain::{ArcBlock, BlocksByHashCache, Web3ProxyBlock};
+use super::capabilities::{is_method_not_found, MethodCapabilities, MethodFamily};
+use super::circuit_breaker::{CircuitBreaker, RequestOutcome};
+use super::head_watchdog::HeadWatchdog;
 use super::provider::Web3Provider;
+use super::rate_limits::{check_provider_error, AdaptiveRateLimit};
 use super::request::{OpenRequestHandle, OpenRequestResult};
 use crate::app::{flatten_handle, AnyhowJoinHandle};
+use crate::block_number::DataKind;
 use crate::config::{BlockAndRpc, Web3RpcConfig};
 use crate::frontend::authorization::Authorization;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
@@ -12,6 +17,7 @@ use ethers::prelude::{Bytes, Middleware, ProviderError, TxHash, H256, U64};
 use ethers::types::{Address, Transaction, U256};
 use futures::future::try_join_all;
 use futures::StreamExt;
+use hashbrown::HashMap;
 use log::{debug, error, info, trace, warn, Level};
 use migration::sea_orm::DatabaseConnection;
 use ordered_float::OrderedFloat;
@@ -22,14 +28,17 @@ use serde::Serialize;
 use serde_json::json;
 use std::borrow::Cow;
 use std::cmp::min;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
 use thread_fast_rng::rand::Rng;
 use thread_fast_rng::thread_fast_rng;
-use tokio::sync::{broadcast, oneshot, watch, RwLock as AsyncRwLock};
-use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tokio::sync::{
+    broadcast, oneshot, watch, OwnedSemaphorePermit, RwLock as AsyncRwLock, Semaphore,
+};
+use tokio::time::{sleep, sleep_until, timeout, timeout_at, Duration, Instant};
 use url::Url;
 
 pub struct Latency {
@@ -95,6 +104,63 @@ impl Latency {
     }
 }
 
+/// How many blocks back to check for data. The deepest one that works is the data limit
+/// TODO: binary search between 90k and max?
+const DATA_LIMIT_DEPTHS: [u64; 9] = [0, 32, 64, 128, 256, 512, 1024, 90_000, u64::MAX];
+
+/// How many times the deepest depth with data must be seen.
+/// Servers behind a load balancer might be a mix of archive and pruned nodes
+const DATA_LIMIT_SAMPLES: usize = 3;
+
+/// How many times to retry a probe that timed out or had a connection error
+const DATA_LIMIT_RETRIES: usize = 2;
+
+/// How many blocks back a server has each kind of data (besides state)
+#[derive(Debug, Default)]
+pub struct DataLimits {
+    /// set in the config. these are never probed
+    configured: HashMap<DataKind, u64>,
+    probed: RwLock<HashMap<DataKind, u64>>,
+}
+
+impl DataLimits {
+    pub fn new(configured: HashMap<DataKind, u64>) -> Self {
+        Self {
+            configured,
+            probed: Default::default(),
+        }
+    }
+
+    pub fn get(&self, kind: DataKind) -> Option<u64> {
+        self.configured
+            .get(&kind)
+            .copied()
+            .or_else(|| self.probed.read().get(&kind).copied())
+    }
+
+    pub fn needs_probe(&self, kind: DataKind) -> bool {
+        !self.configured.contains_key(&kind)
+    }
+
+    /// returns the previous probed limit
+    pub fn set_probed(&self, kind: DataKind, limit: u64) -> Option<u64> {
+        self.probed.write().insert(kind, limit)
+    }
+}
+
+impl Serialize for DataLimits {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut limits = self.probed.read().clone();
+
+        limits.extend(self.configured.iter());
+
+        limits.serialize(serializer)
+    }
+}
+
 /// An active connection to a Web3 RPC server like geth or erigon.
 #[derive(Default)]
 pub struct Web3Rpc {
@@ -111,8 +177,10 @@ pub struct Web3Rpc {
     /// TODO: watch channel instead of a lock
     /// TODO: is this only used for new heads subscriptions? if so, rename
     pub(super) provider: AsyncRwLock<Option<Arc<Web3Provider>>>,
-    /// keep track of hard limits. Optional because we skip this code for our own servers.
+    /// keep track of hard limits. this is only inside an Option so that the "Default" derive works
     pub(super) hard_limit_until: Option<watch::Sender<Instant>>,
+    /// a requests per second limit learned from the server's rate limit errors
+    pub(crate) rate_limit: AdaptiveRateLimit,
     /// rate limits are stored in a central redis so that multiple proxies can share their rate limits
     /// We do not use the deferred rate limiter because going over limits would cause errors
     pub(super) hard_limit: Option<RedisRateLimiter>,
@@ -122,21 +190,42 @@ pub struct Web3Rpc {
     pub(super) automatic_block_limit: bool,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     pub backup: bool,
+    /// when polling over http, only fetch the head block when eth_blockNumber changes
+    pub(super) poll_block_number: bool,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
+    /// block_data_limit is for state. other kinds of data are often kept longer
+    pub(super) data_limits: DataLimits,
+    /// how often to re-check the automatic data limits. zero disables re-checking
+    pub(super) block_data_limit_interval: Duration,
+    /// the data limit probes got different answers for the same depth. this is probably a load balancer
+    pub(super) load_balanced: AtomicBool,
     /// Lower tiers are higher priority when sending requests
     pub(super) tier: u64,
     /// TODO: change this to a watch channel so that http providers can subscribe and take action on change.
     pub(super) head_block: RwLock<Option<Web3ProxyBlock>>,
     /// Track head block latency
     pub(super) head_latency: RwLock<Latency>,
+    /// notice when the head block subscription is stuck or wrong
+    pub(crate) head_watchdog: HeadWatchdog,
     // /// Track request latency
     // /// TODO: refactor this. this lock kills perf. for now just use head_latency
     // pub(super) request_latency: RwLock<Latency>,
     /// Track total requests served
     /// TODO: maybe move this to graphana
     pub(super) total_requests: AtomicUsize,
-    pub(super) active_requests: AtomicUsize,
+    pub(crate) active_requests: AtomicUsize,
+    /// limit how many requests are in flight to this server at once. None means unlimited
+    pub(super) concurrency_semaphore: Option<Arc<Semaphore>>,
+    pub(super) max_concurrent_requests: Option<u32>,
+    /// requests that are waiting for a permit from concurrency_semaphore
+    pub(crate) queued_requests: AtomicUsize,
+    pub(super) max_queued_requests: usize,
+    pub(super) max_queue_wait: Duration,
+    /// stop sending requests here if too many of them error or time out
+    pub(crate) circuit_breaker: CircuitBreaker,
+    /// which of the methods that not every client has are available here
+    pub(crate) capabilities: MethodCapabilities,
     pub(super) reconnect: AtomicBool,
     /// this is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) disconnect_watch: Option<watch::Sender<bool>>,
@@ -155,7 +244,7 @@ impl Web3Rpc {
         // optional because this is only used for http providers. websocket providers don't use it
         http_client: Option<reqwest::Client>,
         // TODO: rename to http_new_head_interval_sender?
-        http_interval_sender: Option<Arc<broadcast::Sender<()>>>,
+        http_interval_sender: Option<Arc<broadcast::Sender<Duration>>>,
         redis_pool: Option<RedisPool>,
         // TODO: think more about soft limit. watching ewma of requests is probably better. but what should the random sort be on? maybe group on tier is enough
         // soft_limit: u32,
@@ -197,17 +286,25 @@ impl Web3Rpc {
         let backup = config.backup;
 
         let block_data_limit: AtomicU64 = config.block_data_limit.unwrap_or_default().into();
+
+        let data_limits = DataLimits::new(
+            [
+                (DataKind::Blocks, config.blocks_data_limit),
+                (DataKind::Receipts, config.receipts_data_limit),
+                (DataKind::Traces, config.traces_data_limit),
+            ]
+            .into_iter()
+            .filter_map(|(kind, limit)| limit.map(|limit| (kind, limit)))
+            .collect(),
+        );
         let automatic_block_limit =
             (block_data_limit.load(atomic::Ordering::Acquire) == 0) && block_sender.is_some();
 
-        // track hard limit until on backup servers (which might surprise us with rate limit changes)
-        // and track on servers that have a configured hard limit
-        let hard_limit_until = if backup || hard_limit.is_some() {
+        // track hard limit until on all servers. any of them might surprise us with rate limits
+        let hard_limit_until = {
             let (sender, _) = watch::channel(Instant::now());
 
             Some(sender)
-        } else {
-            None
         };
 
         if config.ws_url.is_none() && config.http_url.is_none() {
@@ -229,6 +326,10 @@ impl Web3Rpc {
         let (disconnect_sender, disconnect_receiver) = watch::channel(false);
         let reconnect = reconnect.into();
 
+        let concurrency_semaphore = config
+            .max_concurrent_requests
+            .map(|x| Arc::new(Semaphore::new(x as usize)));
+
         let http_url = if let Some(http_url) = config.http_url {
             Some(http_url.parse()?)
         } else {
@@ -253,7 +354,19 @@ impl Web3Rpc {
             soft_limit: config.soft_limit,
             automatic_block_limit,
             backup,
+            poll_block_number: config.poll_block_number,
             block_data_limit,
+            data_limits,
+            block_data_limit_interval: Duration::from_secs(
+                config.block_data_limit_interval_seconds,
+            ),
+            concurrency_semaphore,
+            max_concurrent_requests: config.max_concurrent_requests,
+            max_queued_requests: config.max_queued_requests,
+            max_queue_wait: Duration::from_millis(config.max_queue_wait_ms),
+            capabilities: MethodCapabilities::new(config.methods_allowed, config.methods_denied),
+            circuit_breaker: CircuitBreaker::new(config.circuit_breaker),
+            head_watchdog: HeadWatchdog::new(config.head_watchdog),
             reconnect,
             tier: config.tier,
             disconnect_watch: Some(disconnect_sender),
@@ -313,86 +426,497 @@ impl Web3Rpc {
 
         // TODO: check eth_syncing. if it is not false, return Ok(None)
 
-        let mut limit = None;
+        let limit = self
+            .probe_data_limit(DataKind::State, authorization, unlocked_provider.clone())
+            .await?;
+
+        if let Some(limit) = limit {
+            if limit == 0 {
+                warn!("{} is unable to serve requests", self);
+            }
+
+            let old_limit = self.block_data_limit.swap(limit, atomic::Ordering::AcqRel);
+
+            // this is checked periodically. only log changes
+            if old_limit != limit {
+                if limit == u64::MAX {
+                    info!("block data limit on {}: archive", self);
+                } else {
+                    info!("block data limit on {}: {}", self, limit);
+                }
+            }
+        }
+
+        // nodes commonly keep more blocks and receipts than state
+        // TODO: probe traces. they are expensive and erigon and geth store them very differently
+        for kind in [DataKind::Blocks, DataKind::Receipts] {
+            if !self.data_limits.needs_probe(kind) {
+                continue;
+            }
+
+            if kind == DataKind::Receipts && !self.supports_method("eth_getBlockReceipts") {
+                continue;
+            }
+
+            match self
+                .probe_data_limit(kind, authorization, unlocked_provider.clone())
+                .await
+            {
+                Ok(Some(kind_limit)) => {
+                    if self.data_limits.set_probed(kind, kind_limit) != Some(kind_limit) {
+                        info!("{:?} data limit on {}: {}", kind, self, kind_limit);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    debug!(
+                        "unable to probe {:?} data limit on {}: {:?}",
+                        kind, self, err
+                    );
+                }
+            }
+        }
+
+        Ok(limit)
+    }
+
+    /// Find how many blocks back this server has the given kind of data
+    async fn probe_data_limit(
+        self: &Arc<Self>,
+        kind: DataKind,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+    ) -> anyhow::Result<Option<u64>> {
+        if kind == DataKind::Traces {
+            return Ok(None);
+        }
+
+        let mut deepest = None;
 
-        // TODO: binary search between 90k and max?
         // TODO: start at 0 or 1?
-        for block_data_limit in [0, 32, 64, 128, 256, 512, 1024, 90_000, u64::MAX] {
-            let handle = self
-                .wait_for_request_handle(authorization, None, unlocked_provider.clone())
-                .await?;
+        for (i, depth) in DATA_LIMIT_DEPTHS.into_iter().enumerate() {
+            if !self
+                .probe_has_data(kind, depth, authorization, unlocked_provider.clone())
+                .await?
+            {
+                break;
+            }
 
-            let head_block_num_future = handle.request::<Option<()>, U256>(
-                "eth_blockNumber",
-                &None,
-                // error here are expected, so keep the level low
-                Level::Debug.into(),
-                unlocked_provider.clone(),
+            deepest = Some(i);
+        }
+
+        let mut deepest = match deepest {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+
+        // a single answer from a load balancer might have come from an archive node that most requests won't reach
+        // check the deepest depth again. if any sample disagrees, step back to a shallower depth
+        let mut consistent = true;
+
+        while deepest > 0 {
+            let depth = DATA_LIMIT_DEPTHS[deepest];
+
+            let mut agreed = true;
+
+            for _ in 1..DATA_LIMIT_SAMPLES {
+                if !self
+                    .probe_has_data(kind, depth, authorization, unlocked_provider.clone())
+                    .await?
+                {
+                    agreed = false;
+                    break;
+                }
+            }
+
+            if agreed {
+                break;
+            }
+
+            consistent = false;
+            deepest -= 1;
+        }
+
+        let limit = DATA_LIMIT_DEPTHS[deepest];
+
+        if !consistent && !self.load_balanced.swap(true, atomic::Ordering::AcqRel) {
+            warn!(
+                "{} gave inconsistent {:?} data limits. it is probably load balanced. using {}",
+                self, kind, limit
             );
+        }
+
+        Ok(Some(limit))
+    }
 
-            let head_block_num = timeout(Duration::from_secs(5), head_block_num_future)
+    /// Check if this server has the given kind of data `depth` blocks behind its head.
+    /// Timeouts and connection errors are retried. Errors from the server mean the data is missing
+    async fn probe_has_data(
+        self: &Arc<Self>,
+        kind: DataKind,
+        depth: u64,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+    ) -> anyhow::Result<bool> {
+        let mut retries = 0;
+
+        loop {
+            match self
+                .try_probe_has_data(kind, depth, authorization, unlocked_provider.clone())
                 .await
-                .context("timeout fetching eth_blockNumber")?
-                .context("provider error")?;
+            {
+                Ok(has_data) => return Ok(has_data),
+                Err(err) if retries < DATA_LIMIT_RETRIES => {
+                    retries += 1;
 
-            let maybe_archive_block = head_block_num.saturating_sub((block_data_limit).into());
+                    debug!(
+                        "retrying {:?} data probe on {} at depth {}. {:?}",
+                        kind, self, depth, err
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-            trace!(
-                "checking maybe_archive_block on {}: {}",
-                self,
-                maybe_archive_block
-            );
+    async fn try_probe_has_data(
+        self: &Arc<Self>,
+        kind: DataKind,
+        depth: u64,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+    ) -> anyhow::Result<bool> {
+        if let DataKind::Traces = kind {
+            // there is no cheap request that proves traces exist. never assume that they do
+            return Ok(false);
+        }
 
-            // TODO: wait for the handle BEFORE we check the current block number. it might be delayed too!
-            // TODO: what should the request be?
-            let handle = self
-                .wait_for_request_handle(authorization, None, unlocked_provider.clone())
-                .await?;
+        let handle = self
+            .wait_for_request_handle(authorization, None, unlocked_provider.clone())
+            .await?;
 
-            let archive_result: Result<Bytes, _> = handle
-                .request(
-                    "eth_getCode",
-                    &json!((
-                        "0xdead00000000000000000000000000000000beef",
-                        maybe_archive_block,
-                    )),
-                    // error here are expected, so keep the level low
-                    Level::Trace.into(),
-                    unlocked_provider.clone(),
-                )
-                .await;
+        let head_block_num_future = handle.request::<Option<()>, U256>(
+            "eth_blockNumber",
+            &None,
+            // error here are expected, so keep the level low
+            Level::Debug.into(),
+            unlocked_provider.clone(),
+        );
+
+        let head_block_num = timeout(Duration::from_secs(5), head_block_num_future)
+            .await
+            .context("timeout fetching eth_blockNumber")?
+            .context("provider error")?;
+
+        let maybe_archive_block = head_block_num.saturating_sub(depth.into());
+
+        trace!(
+            "checking maybe_archive_block on {} for {:?}: {}",
+            self,
+            kind,
+            maybe_archive_block
+        );
+
+        // TODO: wait for the handle BEFORE we check the current block number. it might be delayed too!
+        let handle = self
+            .wait_for_request_handle(authorization, None, unlocked_provider.clone())
+            .await?;
+
+        let (method, params) = match kind {
+            DataKind::State => (
+                "eth_getCode",
+                json!((
+                    "0xdead00000000000000000000000000000000beef",
+                    maybe_archive_block,
+                )),
+            ),
+            DataKind::Blocks => ("eth_getBlockByNumber", json!((maybe_archive_block, false))),
+            DataKind::Receipts => ("eth_getBlockReceipts", json!((maybe_archive_block,))),
+            DataKind::Traces => return Ok(false),
+        };
+
+        // error here are expected, so keep the level low
+        let archive_result = handle.request::<_, serde_json::Value>(
+            method,
+            &params,
+            Level::Trace.into(),
+            unlocked_provider,
+        );
+
+        let archive_result = timeout(Duration::from_secs(5), archive_result)
+            .await
+            .with_context(|| format!("timeout fetching {}", method))?;
+
+        let has_data = match archive_result {
+            // pruned nodes return null for missing blocks and receipts
+            Ok(x) => kind == DataKind::State || !x.is_null(),
+            Err(err) if is_missing_data_error(&err) => false,
+            Err(err) => return Err(err).context("provider error"),
+        };
+
+        trace!(
+            "archive_result on {} for {:?} {} ({}): {}",
+            self,
+            kind,
+            depth,
+            maybe_archive_block,
+            has_data
+        );
+
+        Ok(has_data)
+    }
+
+    /// Compare the subscribed head block with the head block over http.
+    /// Returning an error makes `subscribe` disconnect and reconnect
+    async fn watch_head(
+        self: Arc<Self>,
+        authorization: Arc<Authorization>,
+        block_sender: flume::Sender<BlockAndRpc>,
+    ) -> anyhow::Result<()> {
+        // http only servers already poll for their head. the stale check is all they need
+        let http_provider = match (&self.ws_url, &self.http_url) {
+            (Some(_), Some(http_url)) => Some(Arc::new(
+                Web3Provider::new(Cow::Borrowed(http_url), self.http_client.clone()).await?,
+            )),
+            _ => None,
+        };
+
+        loop {
+            tokio::select! {
+                _ = sleep(self.head_watchdog.interval()) => {}
+                x = self.wait_for_disconnect() => {
+                    x?;
+                    break;
+                }
+            }
+
+            let http_head = if let Some(http_provider) = http_provider.as_ref() {
+                // the http provider is only used for this request. it still goes through this rpc's rate limits
+                let f = async {
+                    self.wait_for_request_handle(
+                        &authorization,
+                        Some(Duration::from_secs(5)),
+                        Some(http_provider.clone()),
+                    )
+                    .await
+                    .map_err(|err| anyhow!("no request handle. {:?}", err))?
+                    .request::<_, Option<ArcBlock>>(
+                        "eth_getBlockByNumber",
+                        &json!(("latest", false)),
+                        Level::Debug.into(),
+                        Some(http_provider.clone()),
+                    )
+                    .await
+                    .map_err(anyhow::Error::from)
+                };
+
+                match timeout(Duration::from_secs(5), f).await {
+                    Ok(Ok(block)) => block.and_then(|x| x.number),
+                    Ok(Err(err)) => {
+                        debug!(
+                            "failed fetching head block over http from {}. {:?}",
+                            self, err
+                        );
+                        None
+                    }
+                    Err(_) => {
+                        debug!("timeout fetching head block over http from {}", self);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let subscribed_head = self.head_block.read().as_ref().map(|x| *x.number());
+
+            let health = self.head_watchdog.check(subscribed_head, http_head);
 
             trace!(
-                "archive_result on {} for {} ({}): {:?}",
+                "head watchdog on {}: {} (subscribed={:?} http={:?})",
                 self,
-                block_data_limit,
-                maybe_archive_block,
-                archive_result
+                health,
+                subscribed_head,
+                http_head
             );
 
-            if archive_result.is_err() {
-                break;
-            }
+            if !health.is_healthy() {
+                warn!(
+                    "head block on {} is {}. subscribed={:?} http={:?}",
+                    self, health, subscribed_head, http_head
+                );
+
+                // take this rpc out of consensus until it reconnects
+                block_sender
+                    .send_async((None, self.clone()))
+                    .await
+                    .context("block_sender during head watchdog")?;
 
-            limit = Some(block_data_limit);
+                return Err(anyhow!("head block on {} is {}", self, health));
+            }
         }
 
-        if let Some(limit) = limit {
-            if limit == 0 {
-                warn!("{} is unable to serve requests", self);
+        debug!("head watchdog for {} exited", self);
+
+        Ok(())
+    }
+
+    /// Wait for the next http poll, plus this server's share of the interval
+    async fn wait_for_http_interval(
+        &self,
+        http_interval_receiver: &mut broadcast::Receiver<Duration>,
+    ) -> anyhow::Result<()> {
+        let poll_interval = loop {
+            match http_interval_receiver.recv().await {
+                Ok(x) => break x,
+                Err(err @ broadcast::error::RecvError::Closed) => {
+                    // channel is closed! that's not good. bubble the error up
+                    return Err(err.into());
+                }
+                Err(broadcast::error::RecvError::Lagged(lagged)) => {
+                    // querying the block was delayed
+                    // this can happen if tokio is very busy or waiting for requests limits took too long
+                    if self.backup {
+                        debug!("http interval on {} lagging by {}!", self, lagged);
+                    } else {
+                        warn!("http interval on {} lagging by {}!", self, lagged);
+                    }
+                }
             }
+        };
+
+        sleep(self.http_poll_delay(poll_interval)).await;
 
-            self.block_data_limit
-                .store(limit, atomic::Ordering::Release);
+        Ok(())
+    }
+
+    /// Spread http polls out so that every server isn't queried at the same moment.
+    /// Each server gets a fixed offset into the interval plus some jitter
+    fn http_poll_delay(&self, poll_interval: Duration) -> Duration {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+
+        // up to 25% of the interval
+        let offset = (hasher.finish() % 1_000) as f64 / 4_000.0;
+
+        // up to 10% of the interval
+        let jitter = thread_fast_rng().gen_range(0.0..0.1);
+
+        poll_interval.mul_f64(offset + jitter)
+    }
+
+    /// Check eth_blockNumber before fetching the whole head block
+    async fn http_head_unchanged(self: &Arc<Self>, authorization: &Arc<Authorization>) -> bool {
+        let known_head = match self.head_block.read().as_ref() {
+            Some(x) => *x.number(),
+            None => return false,
+        };
+
+        let handle = match self
+            .wait_for_request_handle(authorization, None, None)
+            .await
+        {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+
+        let head_block_num: Result<U64, _> = handle
+            .request("eth_blockNumber", &None::<()>, Level::Warn.into(), None)
+            .await;
+
+        matches!(head_block_num, Ok(x) if x == known_head)
+    }
+
+    /// Re-check the automatic data limits. The check at connect time isn't enough for load balanced servers
+    async fn recheck_block_data_limit(
+        self: Arc<Self>,
+        authorization: Arc<Authorization>,
+    ) -> anyhow::Result<()> {
+        loop {
+            tokio::select! {
+                _ = sleep(self.block_data_limit_interval) => {}
+                x = self.wait_for_disconnect() => {
+                    x?;
+                    break;
+                }
+            }
+
+            // the next check after a reconnect will set it again
+            if self.block_data_limit() == U64::zero() {
+                continue;
+            }
+
+            if let Err(err) = self.check_block_data_limit(&authorization, None).await {
+                debug!("failed re-checking block data limit on {}. {:?}", self, err);
+            }
         }
 
-        if limit == Some(u64::MAX) {
-            info!("block data limit on {}: archive", self);
-        } else {
-            info!("block data limit on {}: {:?}", self, limit);
+        debug!("block data limit checks for {} exited", self);
+
+        Ok(())
+    }
+
+    /// Find out which method families this server supports.
+    /// Probing errors are not fatal. Unprobed families are assumed to be supported.
+    async fn check_capabilities(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+    ) -> anyhow::Result<()> {
+        for family in MethodFamily::ALL {
+            let (method, params) = family.probe();
+
+            // leave the family unknown. it is assumed to be supported
+            let handle = match self
+                .wait_for_request_handle(authorization, None, unlocked_provider.clone())
+                .await
+            {
+                Ok(handle) => handle,
+                Err(err) => {
+                    debug!("unable to probe {} on {}. {:?}", family, self, err);
+                    continue;
+                }
+            };
+
+            let probe_future = handle.request::<_, serde_json::Value>(
+                method,
+                &params,
+                // errors here are expected, so keep the level low
+                Level::Trace.into(),
+                unlocked_provider.clone(),
+            );
+
+            let supported = match timeout(Duration::from_secs(5), probe_future).await {
+                Ok(Ok(_)) => true,
+                // an error about the params or missing data still means the method exists
+                Ok(Err(err)) => !is_method_not_found(&err),
+                Err(_) => {
+                    debug!("timeout probing {} on {}", family, self);
+                    continue;
+                }
+            };
+
+            trace!("{} on {}: {}", family, self, supported);
+
+            self.capabilities.set_probed(family, supported);
         }
 
-        Ok(limit)
+        Ok(())
+    }
+
+    /// Check if this server can serve the given method
+    pub fn supports_method(&self, method: &str) -> bool {
+        self.capabilities.supports(method)
+    }
+
+    /// Check the circuit breaker. If it is open, returns the time that the caller should retry at.
+    pub fn circuit_breaker_check(&self) -> Result<(), Instant> {
+        self.circuit_breaker.check(self)
+    }
+
+    /// Record a request's outcome on the circuit breaker.
+    pub fn circuit_breaker_record(&self, outcome: RequestOutcome) {
+        self.circuit_breaker.record(self, outcome)
     }
 
     /// TODO: this might be too simple. different nodes can prune differently. its possible we will have a block range
@@ -400,7 +924,25 @@ impl Web3Rpc {
         self.block_data_limit.load(atomic::Ordering::Acquire).into()
     }
 
+    /// How many blocks back this server has the given kind of data.
+    /// Kinds that are not configured or probed fall back to the state limit.
+    pub fn data_limit(&self, kind: DataKind) -> U64 {
+        match kind {
+            DataKind::State => self.block_data_limit(),
+            kind => self
+                .data_limits
+                .get(kind)
+                .map(U64::from)
+                .unwrap_or_else(|| self.block_data_limit()),
+        }
+    }
+
+    /// check if this server has state for the given block
     pub fn has_block_data(&self, needed_block_num: &U64) -> bool {
+        self.has_data(DataKind::State, needed_block_num)
+    }
+
+    pub fn has_data(&self, kind: DataKind, needed_block_num: &U64) -> bool {
         let head_block_num = match self.head_block.read().as_ref() {
             None => return false,
             Some(x) => *x.number(),
@@ -418,15 +960,16 @@ impl Web3Rpc {
         }
 
         // if this is a pruning node, we might not actually have the block
-        let block_data_limit: U64 = self.block_data_limit();
+        let block_data_limit: U64 = self.data_limit(kind);
 
         let oldest_block_num = head_block_num.saturating_sub(block_data_limit);
 
         if needed_block_num < &oldest_block_num {
             trace!(
-                "{} needs {} but the oldest available is {}",
+                "{} needs {} but the oldest available {:?} is {}",
                 self,
                 needed_block_num,
+                kind,
                 oldest_block_num
             );
             return false;
@@ -599,12 +1142,19 @@ impl Web3Rpc {
                 }
             }
 
+            // capabilities are checked first so we know how to probe for old receipts
+            self.check_capabilities(&authorization, unlocked_provider.clone())
+                .await
+                .context(format!("unable to check_capabilities of {}", self))?;
+
             self.check_block_data_limit(&authorization, unlocked_provider.clone())
                 .await
                 .context(format!("unable to check_block_data_limit of {}", self))?;
 
             drop(unlocked_provider);
 
+            self.head_watchdog.reset();
+
             info!("successfully connected to {}", self);
         } else if self.provider.read().await.is_none() {
             return Err(anyhow!("failed waiting for client {}", self));
@@ -679,6 +1229,8 @@ impl Web3Rpc {
                     let _ = head_block.insert(new_head_block.clone());
                 }
 
+                self.head_watchdog.on_head();
+
                 if self.block_data_limit() == U64::zero() {
                     let authorization = Arc::new(Authorization::internal(self.db_conn.clone())?);
                     if let Err(err) = self.check_block_data_limit(&authorization, None).await {
@@ -728,7 +1280,7 @@ impl Web3Rpc {
         block_sender: Option<flume::Sender<BlockAndRpc>>,
         chain_id: u64,
         disconnect_receiver: watch::Receiver<bool>,
-        http_interval_sender: Option<Arc<broadcast::Sender<()>>>,
+        http_interval_sender: Option<Arc<broadcast::Sender<Duration>>>,
         tx_id_sender: Option<flume::Sender<(TxHash, Arc<Self>)>>,
     ) -> anyhow::Result<()> {
         let error_handler = if self.backup {
@@ -882,6 +1434,22 @@ impl Web3Rpc {
                 ready_rx.await?;
             }
 
+            if let Some(block_sender) = &block_sender {
+                if self.head_watchdog.enabled() {
+                    let f = self
+                        .clone()
+                        .watch_head(authorization.clone(), block_sender.clone());
+
+                    futures.push(flatten_handle(tokio::spawn(f)));
+                }
+            }
+
+            if self.automatic_block_limit && !self.block_data_limit_interval.is_zero() {
+                let f = self.clone().recheck_block_data_limit(authorization.clone());
+
+                futures.push(flatten_handle(tokio::spawn(f)));
+            }
+
             if let Some(block_sender) = &block_sender {
                 // TODO: do we need this to be abortable?
                 let f = self.clone().subscribe_new_heads(
@@ -949,7 +1517,7 @@ impl Web3Rpc {
     async fn subscribe_new_heads(
         self: Arc<Self>,
         authorization: Arc<Authorization>,
-        http_interval_receiver: Option<broadcast::Receiver<()>>,
+        http_interval_receiver: Option<broadcast::Receiver<Duration>>,
         block_sender: flume::Sender<BlockAndRpc>,
         block_map: BlocksByHashCache,
     ) -> anyhow::Result<()> {
@@ -967,6 +1535,13 @@ impl Web3Rpc {
                 let mut last_hash = H256::zero();
 
                 while !self.should_disconnect() {
+                    if self.poll_block_number && self.http_head_unchanged(&authorization).await {
+                        self.wait_for_http_interval(&mut http_interval_receiver)
+                            .await?;
+
+                        continue;
+                    }
+
                     // TODO: what should the max_wait be?
                     // we do not pass unlocked_provider because we want to get a new one each call. otherwise we might re-use an old one
                     match self
@@ -1032,25 +1607,9 @@ impl Web3Rpc {
                         }
                     }
 
-                    // wait for the next interval
                     // TODO: if error or rate limit, increase interval?
-                    while let Err(err) = http_interval_receiver.recv().await {
-                        match err {
-                            broadcast::error::RecvError::Closed => {
-                                // channel is closed! that's not good. bubble the error up
-                                return Err(err.into());
-                            }
-                            broadcast::error::RecvError::Lagged(lagged) => {
-                                // querying the block was delayed
-                                // this can happen if tokio is very busy or waiting for requests limits took too long
-                                if self.backup {
-                                    debug!("http interval on {} lagging by {}!", self, lagged);
-                                } else {
-                                    warn!("http interval on {} lagging by {}!", self, lagged);
-                                }
-                            }
-                        }
-                    }
+                    self.wait_for_http_interval(&mut http_interval_receiver)
+                        .await?;
                 }
             }
             Web3Provider::Both(_, client) | Web3Provider::Ws(client) => {
@@ -1203,9 +1762,16 @@ impl Web3Rpc {
     ) -> Web3ProxyResult<OpenRequestHandle> {
         let max_wait = max_wait.map(|x| Instant::now() + x);
 
+        // wait in line for a free slot. the permit is held while we wait for rate limits
+        let mut permit = self.wait_for_permit(max_wait).await?;
+
         loop {
             match self
-                .try_request_handle(authorization, unlocked_provider.clone())
+                .try_request_handle_with_permit(
+                    authorization,
+                    unlocked_provider.clone(),
+                    &mut permit,
+                )
                 .await
             {
                 Ok(OpenRequestResult::Handle(handle)) => return Ok(handle),
@@ -1249,12 +1815,95 @@ impl Web3Rpc {
         }
     }
 
+    /// Wait in a bounded queue for a slot under max_concurrent_requests.
+    /// Returns None if this server has no concurrency limit.
+    async fn wait_for_permit(
+        &self,
+        max_wait: Option<Instant>,
+    ) -> Web3ProxyResult<Option<OwnedSemaphorePermit>> {
+        let semaphore = match self.concurrency_semaphore.as_ref() {
+            None => return Ok(None),
+            Some(x) => x.clone(),
+        };
+
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        let queued = QueuedRequest::new(&self.queued_requests);
+
+        if queued.position >= self.max_queued_requests {
+            trace!("request queue on {} is full", self);
+            return Err(Web3ProxyError::NoHandleReady);
+        }
+
+        let mut wait_until = Instant::now() + self.max_queue_wait;
+        if let Some(max_wait) = max_wait {
+            wait_until = wait_until.min(max_wait);
+        }
+
+        let permit = timeout_at(wait_until, semaphore.acquire_owned())
+            .await
+            .map_err(|x| Web3ProxyError::Timeout(Some(x)))??;
+
+        Ok(Some(permit))
+    }
+
     pub async fn try_request_handle(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         // TODO: borrow on this instead of needing to clone the Arc?
         unlocked_provider: Option<Arc<Web3Provider>>,
     ) -> Web3ProxyResult<OpenRequestResult> {
+        self.try_request_handle_with_permit(authorization, unlocked_provider, &mut None)
+            .await
+    }
+
+    /// Like `try_request_handle`, but waits in this server's queue for a concurrency permit.
+    /// A full queue or a wait past `max_wait` returns `RetryAt` so that the caller can try another server
+    pub async fn queue_for_request_handle(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        max_wait: Option<Instant>,
+    ) -> Web3ProxyResult<OpenRequestResult> {
+        let mut permit = match self.wait_for_permit(max_wait).await {
+            Ok(x) => x,
+            Err(Web3ProxyError::NoHandleReady) | Err(Web3ProxyError::Timeout(_)) => {
+                trace!("no permit available on {}", self);
+                return Ok(OpenRequestResult::RetryAt(
+                    Instant::now() + Duration::from_millis(10),
+                ));
+            }
+            Err(err) => return Err(err),
+        };
+
+        self.try_request_handle_with_permit(authorization, None, &mut permit)
+            .await
+    }
+
+    /// `permit` is only taken if a handle is returned. if it is None, a permit is acquired without waiting
+    async fn try_request_handle_with_permit(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+        permit: &mut Option<OwnedSemaphorePermit>,
+    ) -> Web3ProxyResult<OpenRequestResult> {
+        // check the concurrency limit before the rate limits so that we don't use up rate limits on requests we won't send
+        if permit.is_none() {
+            if let Some(semaphore) = self.concurrency_semaphore.as_ref() {
+                match semaphore.clone().try_acquire_owned() {
+                    Ok(x) => *permit = Some(x),
+                    Err(_) => {
+                        trace!("{} is at max_concurrent_requests", self);
+                        // TODO: what duration? subscribe to the semaphore instead of polling?
+                        return Ok(OpenRequestResult::RetryAt(
+                            Instant::now() + Duration::from_millis(10),
+                        ));
+                    }
+                }
+            }
+        }
+
         // TODO: think more about this read block
         // TODO: this should *not* be new_head_client. this should be a separate object
         if unlocked_provider.is_some() || self.provider.read().await.is_some() {
@@ -1271,6 +1920,12 @@ impl Web3Rpc {
             }
         }
 
+        // this works even if we don't have a configured hard limit or redis
+        if let Err(retry_at) = self.rate_limit.try_acquire() {
+            trace!("{} is at its learned hard limit", self);
+            return Ok(OpenRequestResult::RetryAt(retry_at));
+        }
+
         // check rate limits
         if let Some(ratelimiter) = self.hard_limit.as_ref() {
             // TODO: how should we know if we should set expire or not?
@@ -1305,7 +1960,8 @@ impl Web3Rpc {
             }
         };
 
-        let handle = OpenRequestHandle::new(authorization.clone(), self.clone()).await;
+        let handle =
+            OpenRequestHandle::new(authorization.clone(), self.clone(), permit.take()).await;
 
         Ok(OpenRequestResult::Handle(handle))
     }
@@ -1412,7 +2068,7 @@ impl Serialize for Web3Rpc {
         S: Serializer,
     {
         // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Web3Rpc", 9)?;
+        let mut state = serializer.serialize_struct("Web3Rpc", 18)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1430,6 +2086,13 @@ impl Serialize for Web3Rpc {
             }
         }
 
+        state.serialize_field("data_limits", &self.data_limits)?;
+
+        state.serialize_field(
+            "load_balanced",
+            &self.load_balanced.load(atomic::Ordering::Relaxed),
+        )?;
+
         state.serialize_field("tier", &self.tier)?;
 
         state.serialize_field("soft_limit", &self.soft_limit)?;
@@ -1439,15 +2102,68 @@ impl Serialize for Web3Rpc {
 
         state.serialize_field("head_latency", &self.head_latency.read().value())?;
 
+        state.serialize_field("head_watchdog", &self.head_watchdog)?;
+
         state.serialize_field(
             "total_requests",
             &self.total_requests.load(atomic::Ordering::Relaxed),
         )?;
 
+        state.serialize_field(
+            "active_requests",
+            &self.active_requests.load(atomic::Ordering::Relaxed),
+        )?;
+
+        state.serialize_field("max_concurrent_requests", &self.max_concurrent_requests)?;
+
+        state.serialize_field(
+            "queued_requests",
+            &self.queued_requests.load(atomic::Ordering::Relaxed),
+        )?;
+
+        state.serialize_field("rate_limit", &self.rate_limit)?;
+
+        state.serialize_field("circuit_breaker", &self.circuit_breaker)?;
+
+        state.serialize_field("capabilities", &self.capabilities)?;
+
         state.end()
     }
 }
 
+/// The server answered, but doesn't have the data. Rate limits don't count because they say nothing about the data
+fn is_missing_data_error(err: &ProviderError) -> bool {
+    if check_provider_error(err).is_some() {
+        return false;
+    }
+
+    match err {
+        ProviderError::JsonRpcClientError(err) => err.as_error_response().is_some(),
+        _ => false,
+    }
+}
+
+/// Counts a request waiting for a concurrency permit. The count is decremented on drop so cancelled requests leave the queue
+struct QueuedRequest<'a> {
+    counter: &'a AtomicUsize,
+    /// how many requests were already in the queue
+    position: usize,
+}
+
+impl<'a> QueuedRequest<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        let position = counter.fetch_add(1, atomic::Ordering::AcqRel);
+
+        Self { counter, position }
+    }
+}
+
+impl Drop for QueuedRequest<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, atomic::Ordering::AcqRel);
+    }
+}
+
 impl fmt::Debug for Web3Rpc {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut f = f.debug_struct("Web3Rpc");
@@ -1547,6 +2263,79 @@ mod tests {
         assert!(!x.has_block_data(&(head_block.number() + 1000)));
     }
 
+    #[test]
+    fn test_pruned_node_has_old_receipts() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block: Web3ProxyBlock = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            timestamp: now,
+            ..Default::default()
+        })
+        .try_into()
+        .unwrap();
+
+        let block_data_limit = 64;
+
+        let x = Web3Rpc {
+            name: "name".to_string(),
+            block_data_limit: block_data_limit.into(),
+            data_limits: DataLimits::new(HashMap::from([(DataKind::Receipts, u64::MAX)])),
+            head_block: RwLock::new(Some(head_block.clone())),
+            ..Default::default()
+        };
+
+        let old_block = head_block.number() - block_data_limit - 1;
+
+        assert!(!x.has_block_data(&old_block));
+        assert!(!x.has_data(DataKind::State, &old_block));
+        assert!(x.has_data(DataKind::Receipts, &old_block));
+        assert!(x.has_data(DataKind::Receipts, &1.into()));
+
+        // not configured or probed. fall back to the state limit
+        assert!(!x.has_data(DataKind::Blocks, &old_block));
+        assert!(x.has_data(DataKind::Blocks, head_block.number()));
+
+        // probing doesn't override the config
+        assert!(!x.data_limits.needs_probe(DataKind::Receipts));
+        assert!(x.data_limits.needs_probe(DataKind::Blocks));
+        x.data_limits.set_probed(DataKind::Blocks, u64::MAX);
+        assert!(x.has_data(DataKind::Blocks, &old_block));
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_requests_queue() {
+        let x = Web3Rpc {
+            name: "name".to_string(),
+            concurrency_semaphore: Some(Arc::new(Semaphore::new(1))),
+            max_concurrent_requests: Some(1),
+            max_queued_requests: 1,
+            max_queue_wait: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let permit = x.wait_for_permit(None).await.unwrap();
+        assert!(permit.is_some());
+
+        // the queue has room, but nothing frees up the permit
+        assert!(matches!(
+            x.wait_for_permit(None).await,
+            Err(Web3ProxyError::Timeout(_))
+        ));
+        assert_eq!(x.queued_requests.load(atomic::Ordering::Relaxed), 0);
+
+        // fill the queue so the next request is rejected immediately
+        let _queued = QueuedRequest::new(&x.queued_requests);
+        assert!(matches!(
+            x.wait_for_permit(None).await,
+            Err(Web3ProxyError::NoHandleReady)
+        ));
+
+        drop(permit);
+        assert!(x.wait_for_permit(None).await.unwrap().is_some());
+    }
+
     /*
     // TODO: think about how to bring the concept of a "lagged" node back
     #[test]
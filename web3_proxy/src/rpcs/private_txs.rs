@@ -0,0 +1,194 @@
+///! Track transactions sent to the private rpcs so that users can see them before they are mined.
+use super::blockchain::Web3ProxyBlock;
+use super::many::Web3Rpcs;
+use ethers::prelude::{Address, Transaction, TxHash, U256, U64};
+use log::trace;
+use moka::future::Cache;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// If no block has included the transaction by then, it was probably dropped
+/// TODO: check the sender's nonce instead. replaced transactions would be noticed sooner
+pub const DROPPED_AFTER_BLOCKS: u64 = 100;
+
+/// Don't scan too many blocks at once after a gap in the consensus heads
+const MAX_SCAN_BLOCKS: u64 = 100;
+
+/// What a relay said when we sent it a transaction
+#[derive(Clone, Debug, Serialize)]
+pub struct RelayResponse {
+    pub rpc: String,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Box<RawValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RelayResponse {
+    pub fn new(rpc: String, result: Result<&RawValue, String>) -> Self {
+        match result {
+            Ok(result) => Self {
+                rpc,
+                accepted: true,
+                result: Some(result.to_owned()),
+                error: None,
+            },
+            Err(error) => Self {
+                rpc,
+                // the relay already had it. that's fine
+                accepted: error.contains("already known"),
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PrivateTxStatus {
+    pub tx_hash: TxHash,
+    pub sender: Address,
+    pub nonce: U256,
+    /// only this user can see the status
+    #[serde(skip)]
+    pub user_id: u64,
+    /// unix timestamp of the first send
+    pub sent_at: i64,
+    /// the consensus head when the transaction was first sent
+    pub sent_at_block: Option<U64>,
+    /// every relay response. rebroadcasts add more
+    pub relays: Vec<RelayResponse>,
+    pub rebroadcasts: u32,
+    /// the first consensus block that included the transaction
+    pub first_seen_block: Option<U64>,
+    pub dropped: bool,
+}
+
+pub struct PrivateTxTracker {
+    by_hash: Cache<TxHash, Arc<RwLock<PrivateTxStatus>>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// the last consensus head that was scanned for tracked transactions
+    last_scanned: Mutex<Option<U64>>,
+}
+
+impl Default for PrivateTxTracker {
+    fn default() -> Self {
+        // TODO: capacity and ttl from config
+        let by_hash = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(3600))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Self {
+            by_hash,
+            last_scanned: Mutex::new(None),
+        }
+    }
+}
+
+impl PrivateTxTracker {
+    /// Save the relay responses for a transaction. Sending the same transaction again counts as a rebroadcast
+    pub async fn record_send(
+        &self,
+        user_id: u64,
+        tx: &Transaction,
+        sender: Address,
+        relays: Vec<RelayResponse>,
+        head_block_num: Option<U64>,
+    ) {
+        if let Some(status) = self.by_hash.get(&tx.hash) {
+            let mut status = status.write();
+
+            if status.user_id == user_id {
+                status.rebroadcasts += 1;
+                status.relays.extend(relays);
+            }
+
+            return;
+        }
+
+        let status = PrivateTxStatus {
+            tx_hash: tx.hash,
+            sender,
+            nonce: tx.nonce,
+            user_id,
+            sent_at: chrono::Utc::now().timestamp(),
+            sent_at_block: head_block_num,
+            relays,
+            rebroadcasts: 0,
+            first_seen_block: None,
+            dropped: false,
+        };
+
+        self.by_hash
+            .insert(tx.hash, Arc::new(RwLock::new(status)))
+            .await;
+    }
+
+    /// The status of a transaction that this user sent
+    pub fn status(
+        &self,
+        user_id: u64,
+        tx_hash: &TxHash,
+        head_block_num: Option<U64>,
+    ) -> Option<PrivateTxStatus> {
+        let mut status = self.by_hash.get(tx_hash)?.read().clone();
+
+        if status.user_id != user_id {
+            return None;
+        }
+
+        if status.first_seen_block.is_none() {
+            if let (Some(sent_at_block), Some(head_block_num)) =
+                (status.sent_at_block, head_block_num)
+            {
+                status.dropped = head_block_num > sent_at_block + DROPPED_AFTER_BLOCKS;
+            }
+        }
+
+        Some(status)
+    }
+
+    fn scan_block(&self, block: &Web3ProxyBlock) {
+        let block_num = block.number();
+
+        for tx_hash in block.block.transactions.iter() {
+            if let Some(status) = self.by_hash.get(tx_hash) {
+                let mut status = status.write();
+
+                if status.first_seen_block.is_none() {
+                    trace!("private tx {:?} seen in block {}", tx_hash, block_num);
+
+                    status.first_seen_block = Some(*block_num);
+                }
+            }
+        }
+    }
+
+    /// Look for tracked transactions in a new consensus head and any blocks skipped since the last head
+    pub fn on_head_block(&self, rpcs: &Web3Rpcs, head_block: &Web3ProxyBlock) {
+        let head_block_num = *head_block.number();
+
+        let last_scanned = self.last_scanned.lock().replace(head_block_num);
+
+        if let Some(last_scanned) = last_scanned {
+            let first_missed =
+                (last_scanned + 1).max(head_block_num.saturating_sub(MAX_SCAN_BLOCKS.into()));
+
+            let mut num = first_missed;
+
+            while num < head_block_num {
+                if let Some(block) = rpcs.cached_block_by_number(&num) {
+                    self.scan_block(&block);
+                }
+
+                num += U64::one();
+            }
+        }
+
+        self.scan_block(head_block);
+    }
+}
@@ -1,3 +1,4 @@
+use super::http::HttpClient;
 use anyhow::anyhow;
 use derive_more::From;
 use ethers::providers::{Authorization, ConnectionDetails};
@@ -5,7 +6,8 @@ use std::{borrow::Cow, time::Duration};
 use url::Url;
 
 // TODO: our own structs for these that handle streaming large responses
-type EthersHttpProvider = ethers::providers::Provider<ethers::providers::Http>;
+// http uses our own client so that we can see the response headers
+type EthersHttpProvider = ethers::providers::Provider<HttpClient>;
 type EthersWsProvider = ethers::providers::Provider<ethers::providers::Ws>;
 
 /// Use HTTP and WS providers.
@@ -64,11 +66,9 @@ impl Web3Provider {
 
         let provider = if url.scheme().starts_with("http") {
             let provider = if let Some(auth) = auth {
-                ethers::providers::Http::new_with_auth(url.into_owned(), auth)?
-            } else if let Some(http_client) = http_client {
-                ethers::providers::Http::new_with_client(url.into_owned(), http_client)
+                HttpClient::new_with_auth(url.into_owned(), auth)?
             } else {
-                ethers::providers::Http::new(url.into_owned())
+                HttpClient::new(url.into_owned(), http_client.unwrap_or_default())
             };
 
             // TODO: i don't think this interval matters for our uses, but we should probably set it to like `block time / 2`
@@ -0,0 +1,491 @@
+///! Detect when a backend rate limits us and learn how many requests it actually allows.
+use chrono::{DateTime, Utc};
+use ethers::providers::ProviderError;
+use http::header::{HeaderMap, RETRY_AFTER};
+use log::warn;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{self, AtomicU32};
+use tokio::time::{Duration, Instant};
+
+/// error codes that providers use for rate limits. 429 is copied from http.
+/// -32005 ("limit exceeded") is not here because infura and geth also use it for eth_getLogs with too many results
+const RATE_LIMIT_CODES: [i64; 2] = [429, -32007];
+
+/// the code for "execution reverted"
+const EXECUTION_REVERTED_CODE: i64 = 3;
+
+/// providers limit requests (or compute units) per second. a longer window would average away bursts that they reject
+const WINDOW: Duration = Duration::from_secs(1);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The backend told us to slow down
+#[derive(Debug, PartialEq)]
+pub struct RateLimited {
+    /// how long the backend asked us to wait (if it told us)
+    pub retry_after: Option<Duration>,
+}
+
+/// Check a json-rpc error for the different ways that providers say "slow down"
+pub fn is_rate_limit_error(code: i64, message: &str) -> bool {
+    let msg = message.to_lowercase();
+
+    // a contract can revert with any message. "Mint limit reached" is not a rate limit
+    if code == EXECUTION_REVERTED_CODE || msg.starts_with("execution reverted") {
+        return false;
+    }
+
+    if RATE_LIMIT_CODES.contains(&code) {
+        return true;
+    }
+
+    // any other code (including -32005) needs a message about rate limits
+    msg.contains("rate limit")
+        || msg.contains("too many requests")
+        || msg.contains("limit reached")
+        || msg.contains("compute units")
+        || (msg.contains("exceeded")
+            && (msg.contains("rate") || msg.contains("request") || msg.contains("capacity")))
+}
+
+/// Some providers (like infura) include how long to wait in the error's data
+pub fn retry_after_from_data(data: Option<&serde_json::Value>) -> Option<Duration> {
+    let data = data?.as_object()?;
+
+    let seconds = ["retry_after", "retryAfter", "backoff_seconds"]
+        .iter()
+        .filter_map(|key| data.get(*key))
+        .find_map(|x| match x {
+            serde_json::Value::Number(x) => x.as_f64(),
+            serde_json::Value::String(x) => x.parse().ok(),
+            _ => None,
+        })?;
+
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds).min(MAX_BACKOFF))
+    } else {
+        None
+    }
+}
+
+/// Parse an http Retry-After header. It is either a number of seconds or an http date
+pub fn retry_after_from_headers(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let retry_after = if let Ok(seconds) = value.parse::<u64>() {
+        Duration::from_secs(seconds)
+    } else {
+        let retry_at = DateTime::parse_from_rfc2822(value).ok()?;
+
+        // a date in the past means we can retry now
+        (retry_at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    };
+
+    Some(retry_after.min(MAX_BACKOFF))
+}
+
+/// Check any error from a backend for a rate limit.
+/// Our http client puts the Retry-After header into the error's data
+pub fn check_provider_error(err: &ProviderError) -> Option<RateLimited> {
+    match err {
+        ProviderError::JsonRpcClientError(err) => {
+            if let Some(err) = err.as_error_response() {
+                if is_rate_limit_error(err.code, &err.message) {
+                    return Some(RateLimited {
+                        retry_after: retry_after_from_data(err.data.as_ref()),
+                    });
+                }
+
+                None
+            } else if err.to_string().to_lowercase().contains("too many requests") {
+                // a plain http 429 page that isn't json
+                Some(RateLimited { retry_after: None })
+            } else {
+                None
+            }
+        }
+        ProviderError::HTTPError(err) => {
+            if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                Some(RateLimited { retry_after: None })
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+struct AdaptiveRateLimitInner {
+    window_start: Instant,
+    window_requests: u64,
+    /// requests in the window before this one. used to estimate the rate early in a window
+    previous_requests: u64,
+    /// true if we were rate limited during the current window
+    window_limited: bool,
+    /// None until the backend rate limits us
+    learned_limit: Option<u64>,
+}
+
+/// Learn a backend's requests per second from when it rate limits us.
+/// The limit shrinks when we get rate limited and grows back during windows without any.
+pub struct AdaptiveRateLimit {
+    inner: Mutex<AdaptiveRateLimitInner>,
+    /// used for exponential backoff. reset on any success
+    consecutive_limits: AtomicU32,
+}
+
+impl Default for AdaptiveRateLimit {
+    fn default() -> Self {
+        let inner = AdaptiveRateLimitInner {
+            window_start: Instant::now(),
+            window_requests: 0,
+            previous_requests: 0,
+            window_limited: false,
+            learned_limit: None,
+        };
+
+        Self {
+            inner: Mutex::new(inner),
+            consecutive_limits: 0.into(),
+        }
+    }
+}
+
+impl AdaptiveRateLimitInner {
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+
+        if elapsed < WINDOW {
+            return;
+        }
+
+        if !self.window_limited {
+            // no rate limits last window. try a few more requests
+            if let Some(learned_limit) = self.learned_limit.as_mut() {
+                *learned_limit += (*learned_limit / 10).max(1);
+            }
+        }
+
+        // an idle gap means the last window tells us nothing about the current rate
+        self.previous_requests = if elapsed < WINDOW * 2 {
+            self.window_requests
+        } else {
+            0
+        };
+
+        self.window_start = now;
+        self.window_requests = 0;
+        self.window_limited = false;
+    }
+
+    /// Requests during the last WINDOW. The previous window's requests are weighted by how much of it overlaps.
+    /// Without this, a rate limit early in a window would learn a tiny limit
+    fn recent_requests(&self, now: Instant) -> u64 {
+        let elapsed = now.duration_since(self.window_start).min(WINDOW);
+
+        let overlap = (WINDOW - elapsed).as_secs_f64() / WINDOW.as_secs_f64();
+
+        self.window_requests + (self.previous_requests as f64 * overlap) as u64
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Instant> {
+        self.roll_window(now);
+
+        if let Some(learned_limit) = self.learned_limit {
+            if self.recent_requests(now) >= learned_limit {
+                return Err(self.window_start + WINDOW);
+            }
+        }
+
+        self.window_requests += 1;
+
+        Ok(())
+    }
+
+    /// Returns the new limit if it changed
+    fn on_rate_limited(&mut self, now: Instant) -> Option<u64> {
+        self.roll_window(now);
+
+        // requests that were already in flight will also be limited. only shrink once per window
+        if self.window_limited {
+            return None;
+        }
+
+        self.window_limited = true;
+
+        // leave some headroom under the point where we got limited
+        let new_limit = (self.recent_requests(now) * 9 / 10).max(1);
+
+        let new_limit = self.learned_limit.map_or(new_limit, |x| x.min(new_limit));
+
+        if self.learned_limit == Some(new_limit) {
+            return None;
+        }
+
+        self.learned_limit = Some(new_limit);
+
+        Some(new_limit)
+    }
+}
+
+impl AdaptiveRateLimit {
+    /// Count a request against the learned limit. If it is over the limit, returns the time to retry at
+    pub fn try_acquire(&self) -> Result<(), Instant> {
+        self.inner.lock().try_acquire(Instant::now())
+    }
+
+    /// Shrink the learned limit and return the time that the backend should be available again
+    pub fn on_rate_limited(
+        &self,
+        rpc: &dyn fmt::Display,
+        retry_after: Option<Duration>,
+    ) -> Instant {
+        let consecutive = self
+            .consecutive_limits
+            .fetch_add(1, atomic::Ordering::AcqRel);
+
+        let backoff = retry_after.unwrap_or_else(|| {
+            // 1s, 2s, 4s, ...
+            MIN_BACKOFF
+                .saturating_mul(2u32.saturating_pow(consecutive))
+                .min(MAX_BACKOFF)
+        });
+
+        let now = Instant::now();
+
+        if let Some(new_limit) = self.inner.lock().on_rate_limited(now) {
+            warn!(
+                "rate limited by {}. learned hard limit: {}/s",
+                rpc, new_limit
+            );
+        }
+
+        now + backoff
+    }
+
+    pub fn on_success(&self) {
+        self.consecutive_limits.store(0, atomic::Ordering::Release);
+    }
+
+    pub fn learned_limit(&self) -> Option<u64> {
+        self.inner.lock().learned_limit
+    }
+}
+
+impl Serialize for AdaptiveRateLimit {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("AdaptiveRateLimit", 2)?;
+
+        state.serialize_field("learned_limit", &self.learned_limit())?;
+        state.serialize_field(
+            "consecutive_limits",
+            &self.consecutive_limits.load(atomic::Ordering::Relaxed),
+        )?;
+
+        state.end()
+    }
+}
+
+impl fmt::Debug for AdaptiveRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdaptiveRateLimit")
+            .field("learned_limit", &self.learned_limit())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_rate_limit_error() {
+        assert!(is_rate_limit_error(429, "whatever"));
+        assert!(is_rate_limit_error(
+            -32005,
+            "project ID request rate exceeded"
+        ));
+        assert!(is_rate_limit_error(
+            -32000,
+            "Your app has exceeded its compute units per second capacity"
+        ));
+        assert!(is_rate_limit_error(-32000, "Too Many Requests"));
+
+        assert!(!is_rate_limit_error(-32000, "execution reverted"));
+        assert!(!is_rate_limit_error(-32000, "exceeds block gas limit"));
+        assert!(!is_rate_limit_error(-32601, "Method not found"));
+    }
+
+    #[test]
+    fn test_limit_exceeded_is_not_always_a_rate_limit() {
+        // infura and geth use -32005 for eth_getLogs that match too many logs
+        assert!(!is_rate_limit_error(
+            -32005,
+            "query returned more than 10000 results"
+        ));
+        assert!(is_rate_limit_error(-32005, "rate limit exceeded"));
+    }
+
+    #[test]
+    fn test_reverts_are_not_rate_limits() {
+        assert!(!is_rate_limit_error(
+            3,
+            "execution reverted: Mint limit reached"
+        ));
+        assert!(!is_rate_limit_error(
+            -32000,
+            "execution reverted: Too many requests for this wallet"
+        ));
+        assert!(!is_rate_limit_error(3, "rate limit exceeded"));
+    }
+
+    #[test]
+    fn test_retry_after_from_data() {
+        let data = json!({"current_rps": 13.3, "allowed_rps": 10.0, "backoff_seconds": 30.0});
+        assert_eq!(
+            retry_after_from_data(Some(&data)),
+            Some(Duration::from_secs(30))
+        );
+
+        let data = json!({"retry_after": "2"});
+        assert_eq!(
+            retry_after_from_data(Some(&data)),
+            Some(Duration::from_secs(2))
+        );
+
+        // never wait longer than MAX_BACKOFF
+        let data = json!({"retryAfter": 86400});
+        assert_eq!(retry_after_from_data(Some(&data)), Some(MAX_BACKOFF));
+
+        assert_eq!(retry_after_from_data(Some(&json!("see infura.io"))), None);
+        assert_eq!(retry_after_from_data(None), None);
+    }
+
+    #[test]
+    fn test_adaptive_rate_limit() {
+        let now = Instant::now();
+
+        let mut inner = AdaptiveRateLimitInner {
+            window_start: now,
+            window_requests: 0,
+            previous_requests: 0,
+            window_limited: false,
+            learned_limit: None,
+        };
+
+        let later = now + WINDOW / 2;
+
+        for _ in 0..100 {
+            assert!(inner.try_acquire(later).is_ok());
+        }
+        assert_eq!(inner.learned_limit, None);
+
+        // only the first rate limit in a window shrinks the limit
+        assert_eq!(inner.on_rate_limited(later), Some(90));
+        assert_eq!(inner.on_rate_limited(later), None);
+        assert_eq!(inner.learned_limit, Some(90));
+
+        // already used 100 requests this window
+        assert_eq!(inner.try_acquire(later), Err(now + WINDOW));
+    }
+
+    #[test]
+    fn test_adaptive_rate_limit_backoff() {
+        let limit = AdaptiveRateLimit::default();
+
+        // exponential backoff
+        let now = Instant::now();
+        let first = limit.on_rate_limited(&"test", None);
+        assert!(first >= now + Duration::from_secs(1));
+        let second = limit.on_rate_limited(&"test", None);
+        assert!(second >= now + Duration::from_secs(2));
+
+        // a retry_after from the backend wins
+        let third = limit.on_rate_limited(&"test", Some(Duration::from_millis(1)));
+        assert!(third < now + Duration::from_secs(1));
+
+        limit.on_success();
+        let fourth = limit.on_rate_limited(&"test", None);
+        assert!(fourth < Instant::now() + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_adaptive_rate_limit_regrowth() {
+        let now = Instant::now();
+
+        let mut inner = AdaptiveRateLimitInner {
+            window_start: now,
+            window_requests: 50,
+            previous_requests: 0,
+            window_limited: true,
+            learned_limit: Some(100),
+        };
+
+        // the window we were limited in doesn't grow the limit
+        inner.roll_window(now + WINDOW);
+        assert_eq!(inner.learned_limit, Some(100));
+        assert_eq!(inner.window_requests, 0);
+
+        // early in a window, most of the last window's requests still count
+        let early = now + WINDOW + WINDOW / 10;
+        let recent = inner.recent_requests(early);
+        assert!((44..=45).contains(&recent), "{}", recent);
+        assert_eq!(inner.recent_requests(now + WINDOW * 2), 0);
+
+        // every window without a rate limit grows it by 10%
+        inner.roll_window(now + WINDOW * 2);
+        assert_eq!(inner.learned_limit, Some(110));
+        inner.roll_window(now + WINDOW * 3);
+        assert_eq!(inner.learned_limit, Some(121));
+
+        // still inside the current window
+        inner.roll_window(now + WINDOW * 3 + WINDOW / 2);
+        assert_eq!(inner.learned_limit, Some(121));
+
+        // after an idle gap, the old requests are forgotten
+        inner.window_requests = 80;
+        inner.roll_window(now + WINDOW * 10);
+        assert_eq!(inner.previous_requests, 0);
+    }
+
+    #[test]
+    fn test_retry_after_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after_from_headers(&headers), None);
+
+        headers.insert(RETRY_AFTER, "5".parse().unwrap());
+        assert_eq!(
+            retry_after_from_headers(&headers),
+            Some(Duration::from_secs(5))
+        );
+
+        headers.insert(RETRY_AFTER, "86400".parse().unwrap());
+        assert_eq!(retry_after_from_headers(&headers), Some(MAX_BACKOFF));
+
+        // a date in the past
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after_from_headers(&headers), Some(Duration::ZERO));
+
+        let soon = (Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        headers.insert(RETRY_AFTER, soon.parse().unwrap());
+        let retry_after = retry_after_from_headers(&headers).unwrap();
+        assert!(retry_after > Duration::from_secs(25));
+        assert!(retry_after <= Duration::from_secs(30));
+
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after_from_headers(&headers), None);
+    }
+}
@@ -0,0 +1,217 @@
+///! Read-your-writes for transactions sent through the proxy.
+///!
+///! Right after a send, the other backends might not know about the transaction yet.
+///! Remember what each rpc key sent so that its receipt and pending nonce requests don't go backwards.
+use super::transactions::decode_raw_transaction;
+use ethers::prelude::{Address, TxHash, U256, U64};
+use moka::future::Cache;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Don't hold a receipt request for longer than this while waiting for the expected block
+pub const MAX_RECEIPT_WAIT: Duration = Duration::from_secs(12);
+
+#[derive(Debug)]
+pub struct SentTx {
+    pub rpc_key_id: NonZeroU64,
+    pub sender: Address,
+    pub nonce: U256,
+    /// names of the rpcs that the transaction was sent to
+    pub rpcs: Vec<String>,
+    /// the consensus head when the transaction was sent
+    pub head_block_num: Option<U64>,
+}
+
+impl SentTx {
+    /// the first block that could include the transaction
+    pub fn expected_block(&self) -> Option<U64> {
+        self.head_block_num.map(|x| x + 1)
+    }
+}
+
+pub struct RecentTxs {
+    ttl: Duration,
+    by_hash: Cache<TxHash, Arc<SentTx>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// the highest nonce sent by each rpc key and sender
+    nonces: Cache<(NonZeroU64, Address), U256, hashbrown::hash_map::DefaultHashBuilder>,
+}
+
+impl Default for RecentTxs {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl RecentTxs {
+    /// a ttl of 0 disables read-your-writes
+    pub fn new(ttl: Duration) -> Self {
+        // TODO: max_capacity from config
+        let by_hash = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(ttl)
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        let nonces = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(ttl)
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Self {
+            ttl,
+            by_hash,
+            nonces,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Remember a successful eth_sendRawTransaction
+    pub async fn record_send(
+        &self,
+        rpc_key_id: NonZeroU64,
+        params: Option<&serde_json::Value>,
+        rpcs: Vec<String>,
+        head_block_num: Option<U64>,
+    ) -> Option<Arc<SentTx>> {
+        let tx = decode_raw_transaction(params)?;
+
+        let sender = tx.recover_from().ok()?;
+
+        let sent_tx = Arc::new(SentTx {
+            rpc_key_id,
+            sender,
+            nonce: tx.nonce,
+            rpcs,
+            head_block_num,
+        });
+
+        self.by_hash.insert(tx.hash(), sent_tx.clone()).await;
+
+        let nonce_key = (rpc_key_id, sender);
+
+        // TODO: this could race with another send from the same sender. the ttl makes that harmless
+        if self
+            .nonces
+            .get(&nonce_key)
+            .map(|x| x < tx.nonce)
+            .unwrap_or(true)
+        {
+            self.nonces.insert(nonce_key, tx.nonce).await;
+        }
+
+        Some(sent_tx)
+    }
+
+    /// A transaction that this rpc key recently sent
+    pub fn sent_tx(
+        &self,
+        rpc_key_id: NonZeroU64,
+        params: Option<&serde_json::Value>,
+    ) -> Option<Arc<SentTx>> {
+        let tx_hash = params?.get(0)?.as_str()?.parse::<TxHash>().ok()?;
+
+        self.by_hash
+            .get(&tx_hash)
+            .filter(|x| x.rpc_key_id == rpc_key_id)
+    }
+
+    /// The lowest pending nonce that eth_getTransactionCount(sender, "pending") should return for this rpc key
+    pub fn min_pending_nonce(
+        &self,
+        rpc_key_id: NonZeroU64,
+        params: Option<&serde_json::Value>,
+    ) -> Option<U256> {
+        let params = params?;
+
+        if params.get(1)?.as_str()? != "pending" {
+            return None;
+        }
+
+        let sender = params.get(0)?.as_str()?.parse::<Address>().ok()?;
+
+        self.nonces.get(&(rpc_key_id, sender)).map(|x| x + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::prelude::{LocalWallet, Signer, TransactionRequest};
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_read_your_writes() {
+        let recent_txs = RecentTxs::new(Duration::from_secs(30));
+
+        assert!(recent_txs.enabled());
+        assert!(!RecentTxs::default().enabled());
+
+        // a well known development key
+        let wallet: LocalWallet =
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        let wallet = wallet.with_chain_id(1u64);
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::zero())
+            .nonce(7)
+            .gas(21_000)
+            .gas_price(1)
+            .chain_id(1u64)
+            .into();
+
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+        let raw_tx = tx.rlp_signed(&signature);
+
+        let rpc_key_id = NonZeroU64::new(1).unwrap();
+
+        let sent_tx = recent_txs
+            .record_send(
+                rpc_key_id,
+                Some(&json!([raw_tx])),
+                vec!["a".to_string()],
+                Some(100.into()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(sent_tx.sender, wallet.address());
+        assert_eq!(sent_tx.expected_block(), Some(101.into()));
+
+        let pending_params = json!([wallet.address(), "pending"]);
+
+        assert_eq!(
+            recent_txs.min_pending_nonce(rpc_key_id, Some(&pending_params)),
+            Some(8.into())
+        );
+
+        // latest isn't adjusted
+        assert_eq!(
+            recent_txs.min_pending_nonce(rpc_key_id, Some(&json!([wallet.address(), "latest"]))),
+            None
+        );
+
+        // other keys don't see this key's sends
+        let other_key_id = NonZeroU64::new(2).unwrap();
+
+        assert_eq!(
+            recent_txs.min_pending_nonce(other_key_id, Some(&pending_params)),
+            None
+        );
+
+        let tx_hash_params = json!([format!("{:?}", tx.hash(&signature))]);
+
+        assert!(recent_txs
+            .sent_tx(rpc_key_id, Some(&tx_hash_params))
+            .is_some());
+        assert!(recent_txs
+            .sent_tx(other_key_id, Some(&tx_hash_params))
+            .is_none());
+    }
+}
@@ -1,20 +1,25 @@
+use super::circuit_breaker::RequestOutcome;
 use super::one::Web3Rpc;
 use super::provider::Web3Provider;
+use super::rate_limits;
 use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyError;
 use anyhow::Context;
 use chrono::Utc;
 use entities::revert_log;
 use entities::sea_orm_active_enums::Method;
-use ethers::providers::ProviderError;
+use ethers::providers::{JsonRpcError, ProviderError, RpcError};
 use ethers::types::{Address, Bytes};
 use log::{debug, error, trace, warn, Level};
 use migration::sea_orm::{self, ActiveEnum, ActiveModelTrait};
 use serde_json::json;
 use std::fmt;
+use std::io;
 use std::sync::atomic;
 use std::sync::Arc;
 use thread_fast_rng::rand::Rng;
-use tokio::time::{sleep, Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::{sleep, timeout, Duration, Instant};
 
 #[derive(Debug)]
 pub enum OpenRequestResult {
@@ -31,6 +36,8 @@ pub enum OpenRequestResult {
 pub struct OpenRequestHandle {
     authorization: Arc<Authorization>,
     rpc: Arc<Web3Rpc>,
+    /// held until the request is done so the rpc's max_concurrent_requests is respected
+    _permit: Option<OwnedSemaphorePermit>,
 }
 
 /// Depending on the context, RPC errors require different handling.
@@ -48,6 +55,94 @@ pub enum RequestErrorHandler {
     Save,
 }
 
+/// The user's tier stopped this request.
+/// This says nothing about the backend, so it skips the circuit breaker and isn't retried on another server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TierLimit {
+    Timeout(Duration),
+    ResponseTooLarge { size: u64, max: u64 },
+}
+
+impl TierLimit {
+    /// Some if this error came from the request handle instead of the backend.
+    /// Backends can send any json-rpc error code, so this checks the error's type and not its code
+    pub fn from_rpc_error(err: &dyn RpcError) -> Option<Self> {
+        err.source()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for TierLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(max_time) => {
+                write!(f, "request timed out after {}s", max_time.as_secs_f32())
+            }
+            Self::ResponseTooLarge { size, max } => {
+                write!(f, "response too large. {} > {} bytes", size, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TierLimit {}
+
+impl From<TierLimit> for Web3ProxyError {
+    fn from(limit: TierLimit) -> Self {
+        match limit {
+            TierLimit::Timeout(max_time) => Self::RequestTimeout(max_time),
+            TierLimit::ResponseTooLarge { size, max } => Self::ResponseTooLarge { size, max },
+        }
+    }
+}
+
+/// Carries a TierLimit through ethers' ProviderError.
+/// ethers' boxed RpcError can't be downcast, but its source can
+#[derive(Debug)]
+struct TierLimitError(TierLimit);
+
+impl fmt::Display for TierLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for TierLimitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl RpcError for TierLimitError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        None
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        None
+    }
+}
+
+impl From<TierLimit> for ProviderError {
+    fn from(limit: TierLimit) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(TierLimitError(limit)))
+    }
+}
+
+/// Counts the bytes that would be written
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 // TODO: second param could be skipped since we don't need it here
 #[derive(serde::Deserialize, serde::Serialize)]
 struct EthCallParams((EthCallFirstParams, Option<serde_json::Value>));
@@ -122,7 +217,11 @@ impl Authorization {
 }
 
 impl OpenRequestHandle {
-    pub async fn new(authorization: Arc<Authorization>, rpc: Arc<Web3Rpc>) -> Self {
+    pub async fn new(
+        authorization: Arc<Authorization>,
+        rpc: Arc<Web3Rpc>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Self {
         // TODO: take request_id as an argument?
         // TODO: attach a unique id to this? customer requests have one, but not internal queries
         // TODO: what ordering?!
@@ -130,7 +229,11 @@ impl OpenRequestHandle {
         // TODO: these should maybe be sent to an influxdb instance?
         rpc.active_requests.fetch_add(1, atomic::Ordering::Relaxed);
 
-        Self { authorization, rpc }
+        Self {
+            authorization,
+            rpc,
+            _permit: permit,
+        }
     }
 
     pub fn connection_name(&self) -> String {
@@ -197,20 +300,43 @@ impl OpenRequestHandle {
         // let latency = Instant::now();
 
         // TODO: replace ethers-rs providers with our own that supports streaming the responses
-        let response = match provider.as_ref() {
-            #[cfg(test)]
-            Web3Provider::Mock => {
-                return Err(ProviderError::CustomError(
-                    "mock provider can't respond".to_string(),
-                ))
-            }
-            Web3Provider::Ws(p) => p.request(method, params).await,
-            Web3Provider::Http(p) | Web3Provider::Both(p, _) => {
-                // TODO: i keep hearing that http is faster. but ws has always been better for me. investigate more with actual benchmarks
-                p.request(method, params).await
+        #[cfg(test)]
+        if let Web3Provider::Mock = provider.as_ref() {
+            return Err(ProviderError::CustomError(
+                "mock provider can't respond".to_string(),
+            ));
+        }
+
+        let response = async {
+            match provider.as_ref() {
+                #[cfg(test)]
+                Web3Provider::Mock => unreachable!("mock providers returned above"),
+                Web3Provider::Ws(p) => p.request(method, params).await,
+                Web3Provider::Http(p) | Web3Provider::Both(p, _) => {
+                    // TODO: i keep hearing that http is faster. but ws has always been better for me. investigate more with actual benchmarks
+                    p.request(method, params).await
+                }
             }
         };
 
+        // the user tier's timeout. the frontend has its own timeout that covers retries on other servers
+        let response = match self.authorization.checks.request_timeout {
+            Some(max_time) => match timeout(max_time, response).await {
+                Ok(x) => x,
+                Err(_) => {
+                    self.rpc
+                        .active_requests
+                        .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+
+                    // a slow request isn't the backend's fault. don't tell the circuit breaker
+                    trace!("{} timed out on {} after {:?}", method, self.rpc, max_time);
+
+                    return Err(TierLimit::Timeout(max_time).into());
+                }
+            },
+            None => response.await,
+        };
+
         // note. we intentionally do not record this latency now. we do NOT want to measure errors
         // let latency = latency.elapsed();
 
@@ -264,13 +390,19 @@ impl OpenRequestHandle {
             #[derive(Debug)]
             enum ResponseTypes {
                 Revert,
-                RateLimit,
+                /// the backend might have told us how long to wait
+                RateLimit(Option<Duration>),
                 Error,
+                /// no json-rpc response at all. the backend is likely down or overloaded
+                Failed,
             }
 
             // check for "execution reverted" here
             // TODO: move this info a function on ResponseErrorType
-            let response_type = if let ProviderError::JsonRpcClientError(err) = err {
+            let response_type = if let Some(rate_limited) = rate_limits::check_provider_error(err) {
+                trace!("rate limit from {}", self.rpc);
+                ResponseTypes::RateLimit(rate_limited.retry_after)
+            } else if let ProviderError::JsonRpcClientError(err) = err {
                 // Http and Ws errors are very similar, but different types
                 let msg = match &*provider {
                     #[cfg(test)]
@@ -282,27 +414,46 @@ impl OpenRequestHandle {
                     if msg.starts_with("execution reverted") {
                         trace!("revert from {}", self.rpc);
                         ResponseTypes::Revert
-                    } else if msg.contains("limit") || msg.contains("request") {
-                        trace!("rate limit from {}", self.rpc);
-                        ResponseTypes::RateLimit
                     } else {
                         ResponseTypes::Error
                     }
                 } else {
-                    ResponseTypes::Error
+                    ResponseTypes::Failed
                 }
             } else {
-                ResponseTypes::Error
+                ResponseTypes::Failed
             };
 
-            if matches!(response_type, ResponseTypes::RateLimit) {
-                if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
-                    // TODO: how long should we actually wait? different providers have different times
-                    let retry_at = Instant::now() + Duration::from_secs(1);
+            // json-rpc errors (like reverts) mean the backend is working. only failures count against the circuit breaker
+            let outcome = if matches!(response_type, ResponseTypes::Failed) {
+                // TODO: check the error type instead of the string
+                if err.to_string().contains("timed out") {
+                    RequestOutcome::Timeout
+                } else {
+                    RequestOutcome::Error
+                }
+            } else {
+                RequestOutcome::Success
+            };
+
+            self.rpc.circuit_breaker_record(outcome);
 
+            if let ResponseTypes::RateLimit(retry_after) = &response_type {
+                // exponential backoff unless the backend told us how long to wait
+                let retry_at = self.rpc.rate_limit.on_rate_limited(&self.rpc, *retry_after);
+
+                if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
                     trace!("retry {} at: {:?}", self.rpc, retry_at);
 
-                    hard_limit_until.send_replace(retry_at);
+                    // don't shorten a wait that is already longer
+                    hard_limit_until.send_if_modified(|x| {
+                        if retry_at > *x {
+                            *x = retry_at;
+                            true
+                        } else {
+                            false
+                        }
+                    });
                 }
             }
 
@@ -352,18 +503,24 @@ impl OpenRequestHandle {
                     // TODO: do not unwrap! (doesn't matter much since we check method as a string above)
                     let method: Method = Method::try_from_value(&method.to_string()).unwrap();
 
-                    // TODO: DO NOT UNWRAP! But also figure out the best way to keep returning ProviderErrors here
-                    let params: EthCallParams = serde_json::from_value(json!(params))
-                        .context("parsing params to EthCallParams")
-                        .unwrap();
-
-                    // spawn saving to the database so we don't slow down the request
-                    let f = self.authorization.clone().save_revert(method, params.0 .0);
-
-                    tokio::spawn(f);
+                    // contract deployments don't have a "to" and can't be saved
+                    match serde_json::from_value::<EthCallParams>(json!(params)) {
+                        Ok(params) => {
+                            // spawn saving to the database so we don't slow down the request
+                            let f = self.authorization.clone().save_revert(method, params.0 .0);
+
+                            tokio::spawn(f);
+                        }
+                        Err(err) => {
+                            trace!("unable to save revert. err={:?}", err);
+                        }
+                    }
                 }
             }
         } else {
+            self.rpc.circuit_breaker_record(RequestOutcome::Success);
+            self.rpc.rate_limit.on_success();
+
             // TODO: record request latency
             // let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
 
@@ -373,6 +530,16 @@ impl OpenRequestHandle {
             // latency_recording.record(latency_ms);
         }
 
+        if let (Ok(response), Some(max)) = (&response, self.authorization.checks.max_response_bytes)
+        {
+            // TODO: stop reading from the backend once this is reached. ethers only gives us the whole response
+            let mut size = ByteCounter::default();
+
+            if serde_json::to_writer(&mut size, response).is_ok() && size.0 > max {
+                return Err(TierLimit::ResponseTooLarge { size: size.0, max }.into());
+            }
+        }
+
         response
     }
 }
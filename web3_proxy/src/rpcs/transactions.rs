@@ -4,8 +4,10 @@ use super::many::Web3Rpcs;
 ///! Load balanced communication with a group of web3 providers
 use super::one::Web3Rpc;
 use super::request::OpenRequestResult;
-use ethers::prelude::{ProviderError, Transaction, TxHash};
+use ethers::prelude::{Bytes, ProviderError, Transaction, TxHash};
+use ethers::utils::rlp::{Decodable, Rlp};
 use log::{debug, trace, Level};
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
@@ -17,6 +19,15 @@ pub enum TxStatus {
     Orphaned(Transaction),
 }
 
+/// Decode the signed transaction from eth_sendRawTransaction's params
+pub fn decode_raw_transaction(params: Option<&serde_json::Value>) -> Option<Transaction> {
+    let raw_tx = params?.get(0)?.as_str()?;
+
+    let raw_tx = Bytes::from_str(raw_tx).ok()?;
+
+    Transaction::decode(&Rlp::new(raw_tx.as_ref())).ok()
+}
+
 impl Web3Rpcs {
     async fn query_transaction_status(
         &self,
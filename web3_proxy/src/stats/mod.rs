@@ -38,11 +38,17 @@ pub struct RpcQueryStats {
     pub method: Option<String>,
     pub archive_request: bool,
     pub error_response: bool,
+    /// uncompressed
     pub request_bytes: u64,
+    /// what was actually received. less than request_bytes if the request was compressed
+    pub request_wire_bytes: u64,
     /// if backend_requests is 0, there was a cache_hit
     // pub frontend_request: u64,
     pub backend_requests: u64,
+    /// uncompressed
     pub response_bytes: u64,
+    /// what was actually sent. less than response_bytes if the response was compressed
+    pub response_wire_bytes: u64,
     pub response_millis: u64,
     pub response_timestamp: i64,
 }
@@ -177,7 +183,9 @@ pub struct BufferedRpcQueryStats {
     pub cache_misses: u64,
     pub cache_hits: u64,
     pub sum_request_bytes: u64,
+    pub sum_request_wire_bytes: u64,
     pub sum_response_bytes: u64,
+    pub sum_response_wire_bytes: u64,
     pub sum_response_millis: u64,
 }
 
@@ -225,7 +233,9 @@ impl BufferedRpcQueryStats {
         }
 
         self.sum_request_bytes += stat.request_bytes;
+        self.sum_request_wire_bytes += stat.request_wire_bytes;
         self.sum_response_bytes += stat.response_bytes;
+        self.sum_response_wire_bytes += stat.response_wire_bytes;
         self.sum_response_millis += stat.response_millis;
     }
 
@@ -342,8 +352,13 @@ impl BufferedRpcQueryStats {
             .field("cache_misses", self.cache_misses as i64)
             .field("cache_hits", self.cache_hits as i64)
             .field("sum_request_bytes", self.sum_request_bytes as i64)
+            .field("sum_request_wire_bytes", self.sum_request_wire_bytes as i64)
             .field("sum_response_millis", self.sum_response_millis as i64)
-            .field("sum_response_bytes", self.sum_response_bytes as i64);
+            .field("sum_response_bytes", self.sum_response_bytes as i64)
+            .field(
+                "sum_response_wire_bytes",
+                self.sum_response_wire_bytes as i64,
+            );
 
         builder = builder.timestamp(key.response_timestamp);
 
@@ -378,8 +393,11 @@ impl RpcQueryStats {
             method,
             backend_requests,
             request_bytes,
+            // the frontend replaces these if the request or response was compressed
+            request_wire_bytes: request_bytes,
             error_response,
             response_bytes,
+            response_wire_bytes: response_bytes,
             response_millis,
             response_timestamp,
         }